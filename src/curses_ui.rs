@@ -4,6 +4,7 @@
 
 use crate::expression::Expression;
 //use crate::ui_helpers::Selection;
+use crate::ui_helpers::{Token, TokenClass, render_tokens};
 use pancurses as pc;
 
 struct WindowFmt<'a>(&'a pc::Window);
@@ -27,12 +28,13 @@ macro_rules! log {
 		}};
 }
 
-pub fn mainloop(lines: &mut Vec<super::Line>)
+pub fn mainloop(lines: &mut Vec<super::Line>, rules: &[crate::rewrite::Rule], units: &crate::units::Units, path: Option<&std::path::Path>, readonly: bool)
 {
 	let window = pc::initscr();
 	pc::noecho();
 	pc::curs_set(0);	// Hide the cursor
   	window.keypad(true);
+	init_colours();
 
 	#[derive(PartialEq,Debug)]
 	enum InputMode
@@ -59,11 +61,43 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 	let mut mode = InputMode::LineSelect;
 	let mut clipboard = Clipboard::Empty;
 	let mut statusline = std::borrow::Cow::from("");
+	// Other end of a line range (the current line is the other end)
+	let mut line_mark: Option<usize> = None;
+	// Two-dimensional layout (stacked fractions, raised exponents) instead of the flat text
+	let mut pretty = false;
+	// Unicode glyphs in the flat view
+	let mut style = crate::ui_helpers::Style::Ascii;
+	// Put the results of sub-expression operations on a new (derived) line, instead of editing in place
+	let mut derive_lines = false;
+	// File to write on `w` (prompted for if not loaded from one)
+	let mut path = path.map(|p| p.to_owned());
 
 	let mut last_line = 0;
 	let mut redraw = Redraw::All;
 	loop
 	{
+		// In the 2-D view lines have differing heights, so any change re-draws everything
+		if pretty && (redraw != Redraw::None || cur_line != last_line) {
+			redraw = Redraw::All;
+		}
+		// Top and baseline rows of each line
+		let mut line_rows = Vec::with_capacity(lines.len());
+		{
+			let mut y = 0;
+			for line in lines.iter()
+			{
+				if let (true, Some(ref e)) = (pretty, &line.expr) {
+					let b = crate::layout::layout(e, None);
+					line_rows.push( (y, y + b.baseline as i32) );
+					y += b.height() as i32;
+				}
+				else {
+					line_rows.push( (y, y) );
+					y += 1;
+				}
+			}
+		}
+
 		// Re-draw the entire set of expressions
 		if redraw == Redraw::All
 		{
@@ -77,8 +111,12 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 			}
 			for (i, line) in lines.iter().enumerate()
 			{
-				window.mv(i as i32, 2);
-				draw_expression_nosel(&window, &line.expr);
+				if line_mark == Some(i) {
+					window.mv(line_rows[i].1, 0);
+					window.addch('*');
+				}
+				draw_line_nosel(&window, line_rows[i].0, line, units, pretty, style);
+				draw_annotation(&window, line_rows[i].1, line);
 			}
 		}
 
@@ -87,8 +125,8 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 		{
 			if redraw != Redraw::All
 			{
-				window.mv(last_line as i32, 2);
-				draw_expression_nosel(&window, &lines[last_line].expr);
+				draw_line_nosel(&window, line_rows[last_line].0, &lines[last_line], units, pretty, style);
+				draw_annotation(&window, line_rows[last_line].1, &lines[last_line]);
 			}
 
 			//cur_sel = Selection::new();
@@ -99,18 +137,29 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 		if redraw != Redraw::None
 		{
 			let line = &lines[cur_line];
-			window.mv(cur_line as i32, 2);
+			window.mv(line_rows[cur_line].0, 2);
 			window.clrtoeol();
 			if mode == InputMode::LineSelect { 
 				window.attron(pc::Attribute::Bold);
-				draw_expression_nosel(&window, &line.expr);
+				draw_line_nosel(&window, line_rows[cur_line].0, line, units, pretty, style);
 				window.attroff(pc::Attribute::Bold);
 			}
 			else {
-				draw_expression(&window, &line);
+				draw_expression(&window, line_rows[cur_line].0, &line, pretty, style);
 			}
+			draw_annotation(&window, line_rows[cur_line].1, line);
 			
 			{
+				if statusline == "" {
+					if let Some(ref raw) = line.raw {
+						if let Err(e) = Expression::opt_parse_from_str_with_comment(raw) {
+							statusline = format!("Parse error: {}", e).into();
+						}
+					}
+					else if let Some(Err(e)) = line.expr.as_ref().map(|e| units.check(e)) {
+						statusline = format!("Units: {}", e.message).into();
+					}
+				}
 				window.mv( window.get_max_y() - 1, 0 );
 				window.addstr(&statusline);
 				statusline = "".into();
@@ -133,30 +182,216 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 		match window.getch()
 		{
 		Some(pc::Input::Character('q')) => break,
+		Some(pc::Input::Character('w')) if readonly => {
+			statusline = "Read-only, not saving".into();
+			redraw = Redraw::Current;
+			},
+		Some(pc::Input::Character('w')) => {
+			if path.is_none() {
+				let p = show_input_modal(&window, "");
+				if p.trim() != "" {
+					path = Some(p.trim().into());
+				}
+			}
+			if let Some(ref p) = path {
+				statusline = match crate::save_lines(p, lines, units)
+					{
+					Ok(_) => format!("Saved to {}", p.display()).into(),
+					Err(e) => format!("Unable to save to {}: {}", p.display(), e).into(),
+					};
+			}
+			redraw = Redraw::All;
+			},
+		Some(pc::Input::Character('n')) => {
+			derive_lines = !derive_lines;
+			statusline = if derive_lines { "Results on new lines" } else { "Results replace the selection" }.into();
+			redraw = Redraw::Current;
+			},
 		Some(pc::Input::Character('.')) =>
 			match mode
 			{
 			InputMode::LineSelect => {
-				if let Some(opid) = show_menu_modal(&window, &["Simplify", "Compare with clipboard"])
+				if let Some(opid) = show_menu_modal(&window, &["Simplify", "Compare with clipboard", "Apply to both sides", "Evaluate", "Solve Linear System", "Find Roots", "Export LaTeX"])
 				{
 					match opid
 					{
 					// Simplify the expression (remove unneeded parens, merge exponents)
 					0 => {
-						crate::manip::simplify(&mut lines[cur_line].expr);
+						if let Some(ref mut e) = lines[cur_line].expr {
+							crate::manip::simplify(e);
+						}
 						},
 					// Compare with clipboard
 					1 =>
-						match clipboard
+						match (&lines[cur_line].expr, &clipboard)
 						{
-						Clipboard::Line(crate::Line { expr: ref clp_e, .. }) | Clipboard::Expr(ref clp_e) => {
-							let cur_e = crate::manip::normalise(lines[cur_line].expr.clone());
+						(Some(cur_e), Clipboard::Line(crate::Line { expr: Some(clp_e), .. })) | (Some(cur_e), Clipboard::Expr(clp_e)) => {
+							let cur_e = crate::manip::normalise(cur_e.clone());
 							let clp_e = crate::manip::normalise(clp_e.clone());
-							log!(window, "TODO: Compare with clipboard (after normalisation) - {} == {}", cur_e, clp_e);
+							log!(window, "Compare with clipboard (after normalisation) - {} == {}", cur_e, clp_e);
+							statusline = if cur_e == clp_e {
+									"Equal to the clipboard".into()
+								}
+								else {
+									format!("Not equal: {} vs {}", cur_e, clp_e).into()
+								};
 							},
 						_ => {
 							},
 						},
+					// Apply an operation to both sides (creating a new line)
+					2 => {
+						let v = show_input_modal(&window, "");
+						match crate::manip::parse_side_op(&v)
+						{
+						Some( (op, inverse, val) ) =>
+							match lines[cur_line].expr.as_ref().and_then(|e| crate::manip::apply_to_both_sides(e, op, inverse, &val))
+							{
+							Some(e) => {
+								let mut new_line = crate::Line::from_expr(e);
+								let opname = format!("apply {}", v.trim());
+								new_line.derivation = Some(crate::derivation::Derivation::new(vec![cur_line], &opname, &crate::ui_helpers::Selection::new()));
+								crate::insert_line(lines, cur_line + 1, new_line);
+								cur_line += 1;
+								},
+							None => {
+								statusline = "Can only apply to both sides of an equation".into();
+								},
+							},
+						None => {
+							statusline = format!("Bad operation `{}` (expected e.g. `*2`)", v).into();
+							},
+						}
+						},
+					// Numerically evaluate the line (or the right-hand side of an `x = ...` line)
+					3 => {
+						let e = {
+							use crate::expression::{ExprNode,Op};
+							match lines[cur_line].expr
+							{
+							Some(Expression::SubNode(ExprNode { operation: Op::Equality, ref values })) if values.len() == 2 =>
+								match values[0].val
+								{
+								Expression::Variable(_) => Some(values[1].val.clone()),
+								_ => lines[cur_line].expr.clone(),
+								},
+							ref e => e.clone(),
+							}
+							};
+						let mut bindings = crate::eval::Bindings::from_lines(&lines[..cur_line]);
+						// (Comment-only lines have nothing to evaluate)
+						while let Some(ref e) = e
+						{
+							match crate::eval::evaluate(e, &bindings)
+							{
+							Ok(v) => {
+								statusline = format!("{} = {}", e, v).into();
+								break;
+								},
+							// Prompt for the value of any unknown variables
+							Err(crate::eval::EvalError::Unbound(name)) => {
+								let v = show_input_modal(&window, &format!("{} = ", name));
+								let b = v.parse().ok().and_then(|e| crate::eval::get_binding(&e, &bindings));
+								match b
+								{
+								Some( (n, v) ) => bindings.set(&n, v),
+								None => {
+									statusline = format!("No value for `{}`", name).into();
+									break;
+									},
+								}
+								},
+							Err(err) => {
+								statusline = format!("Unable to evaluate: {}", err).into();
+								break;
+								},
+							}
+						}
+						},
+					// Solve the equations between the mark and the current line for a list of unknowns
+					4 => {
+						let (first, last) = match line_mark
+							{
+							Some(m) if m < lines.len() => (std::cmp::min(m, cur_line), std::cmp::max(m, cur_line)),
+							_ => (cur_line, cur_line),
+							};
+						let v = show_input_modal(&window, "");
+						let unknowns: Vec<String> = v.split(|c: char| c == ',' || c.is_whitespace()).filter(|v| v != &"").map(|v| v.to_owned()).collect();
+						// Comment-only lines in the range are skipped
+						let (rows, equations): (Vec<usize>, Vec<&Expression>) = (first ..= last).filter_map(|i| lines[i].expr.as_ref().map(|e| (i, e))).unzip();
+						match crate::linear::solve(&equations, &unknowns)
+						{
+						_ if unknowns.len() == 0 => {
+							statusline = "No unknowns given".into();
+							},
+						Ok(results) => {
+							for (i,(u,e)) in unknowns.iter().zip(results.into_iter()).enumerate()
+							{
+								let mut new_line = crate::Line::from_expr(e);
+								let opname = format!("solve linear {} of {}", u, unknowns.join(","));
+								new_line.derivation = Some(crate::derivation::Derivation::new(rows.clone(), &opname, &crate::ui_helpers::Selection::new()));
+								crate::insert_line(lines, last + 1 + i, new_line);
+							}
+							cur_line = last + unknowns.len();
+							line_mark = None;
+							},
+						Err(crate::linear::SolveError::NotEquation(i)) | Err(crate::linear::SolveError::NotLinear(i)) => {
+							statusline = format!("L{} isn't a linear equation in {}", rows[i] + 1, unknowns.join(", ")).into();
+							},
+						Err(e) => {
+							statusline = format!("Unable to solve: {}", e).into();
+							},
+						}
+						},
+					// Numerically find roots within an interval (using values from earlier binding lines)
+					5 => {
+						let var = show_input_modal(&window, "");
+						let var = var.trim();
+						let range = show_input_modal(&window, "-10 10");
+						let range: Vec<Option<f64>> = range.split(|c: char| c == ',' || c.is_whitespace()).filter(|v| v != &"").map(|v| v.parse().ok()).collect();
+						match (&range[..], &lines[cur_line].expr)
+						{
+						(_, None) => {
+							statusline = "Line has no expression".into();
+							},
+						(&[Some(lo), Some(hi)], Some(e)) if var != "" && lo < hi => {
+							let bindings = crate::eval::Bindings::from_lines(&lines[..cur_line]);
+							match crate::eval::find_roots(e, var, &bindings, lo, hi)
+							{
+							Ok(ref roots) if roots.len() == 0 => {
+								statusline = format!("No roots for {} in [{}, {}]", var, lo, hi).into();
+								},
+							Ok(roots) => {
+								let desc: Vec<String> = roots.iter().map(|(x, res)| format!("{} (residual {:e})", x, res)).collect();
+								statusline = format!("Roots: {}", desc.join(", ")).into();
+								for (i,(x, res)) in roots.into_iter().enumerate()
+								{
+									let mut new_line = crate::Line::from_expr(crate::eval::root_line(var, x));
+									let opname = format!("find root {} {} {} {}", var, lo, hi, i + 1);
+									new_line.derivation = Some(crate::derivation::Derivation::new(vec![cur_line], &opname, &crate::ui_helpers::Selection::new()));
+									new_line.comment = format!(" residual {:e}", res);
+									crate::insert_line(lines, cur_line + 1 + i, new_line);
+								}
+								},
+							Err(e) => {
+								statusline = format!("Unable to find roots: {}", e).into();
+								},
+							}
+							},
+						_ => {
+							statusline = format!("Expected a variable and an interval `low high`").into();
+							},
+						}
+						},
+					// Write all lines to a LaTeX file
+					6 => {
+						let path = show_input_modal(&window, "equations.tex");
+						statusline = match std::fs::write(path.trim(), crate::latex::export(lines))
+							{
+							Ok(_) => format!("Exported {} lines to {}", lines.len(), path.trim()),
+							Err(e) => format!("Unable to write {}: {}", path.trim(), e),
+							}.into();
+						},
 					// TODO: Substitutions
 					_ => {},
 					}
@@ -168,9 +403,19 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 				redraw = Redraw::All;
 				},
 			InputMode::ExprPick | InputMode::ExprSelect => {
-				if let Some(opid) = show_menu_modal(&window, &["Factorise All", "Factorise Leading", "Factorise Trailing", "Distribute Leading", "Substitute", "Extract"])
+				const BUILTIN_OPS: &[&str] = &["Factorise All", "Factorise Leading", "Factorise Trailing", "Distribute Leading", "Substitute", "Extract", "Reverse Substitute", "Differentiate", "Integrate", "Series Expansion", "Polynomial Order", "Polynomial Info", "Polynomial Divide by Clipboard", "Factor Quadratic", "Complete the Square", "Partial Fractions"];
+				let options: Vec<&str> = Iterator::chain( BUILTIN_OPS.iter().cloned(), rules.iter().map(|r| &r.name[..]) ).collect();
+				if let Some(opid) = show_menu_modal(&window, &options)
 				{
 					let e = lines[cur_line].extract_selection();
+					// Extra information for the derivation record
+					let mut op_arg = String::new();
+					let mut sources = vec![cur_line];
+					let mut insert_at = cur_line + 1;
+					// Set if the result is a whole new line (instead of a replacement for the selection)
+					let mut is_new_line = false;
+					// Set if the operation has already completed (and doesn't produce a line)
+					let mut is_complete = false;
 					let (res, opname) = match opid
 						{
 						0 => {
@@ -180,33 +425,42 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 							(crate::manip::factorise_leading(e), "factorise leading",)
 							},
 						2 => {
-							(crate::manip::factorise_trailing(e), "factorise leading",)
+							(crate::manip::factorise_trailing(e), "factorise trailing",)
 							},
 						3 => {
 							//(crate::manip::distribute_leading(e), "distribute leading",)
 							(None, "",)
 							},
-						// Automatic substitution
+						// Substitution (pick the defining line)
 						4 => {
 							if let Expression::Variable(v) = e {
-								let mut rv = None;
-								// Search up from the current line for an assignment
-								for line in lines[0 .. cur_line].iter().rev() {
-									use crate::expression::{ExprNode,Op};
-									if let Expression::SubNode(ExprNode { operation: Op::Equality, ref values }) = line.expr
-									{
-										if let Expression::Variable(ref v2) = values[0].val
+								// Collect all lines that define this variable (either directly, or after solving)
+								let mut options = vec![];
+								for (i,line) in lines.iter().enumerate() {
+									let expr = match line.expr
 										{
-											// Take the first one that is of the form `<v> = ...`
-											if *v2 == v
-											{
-												rv = Some(values[1].val.clone());
-												break ;
-											}
-										}
+										Some(ref e) if i != cur_line => e,
+										_ => continue,
+										};
+									if let Some(val) = crate::manip::find_definition(expr, &v) {
+										options.push( (format!("L{}: {} = {}", i+1, v, val), val, i) );
 									}
+									else if let Some(val) = crate::manip::solve_for(expr, &v) {
+										options.push( (format!("L{}: {} = {} (solved)", i+1, v, val), val, i) );
+									}
+								}
+								let names: Vec<&str> = options.iter().map(|v| &v.0[..]).collect();
+								if options.len() == 0 {
+									(None, "substitute",)
+								}
+								else if let Some(idx) = show_menu_modal(&window, &names) {
+									let (_, val, line_idx) = options.swap_remove(idx);
+									sources.push(line_idx);
+									(Some(val), "substitute",)
+								}
+								else {
+									(None, "substitute",)
 								}
-								(rv, "substitute",)
 							}
 							else {
 								(None, "substitute",)
@@ -218,15 +472,24 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 							if name != ""
 							{
 								// Add a new line below with the contents of this sub-expression assigned
-								lines.insert(cur_line+1, crate::Line::from_expr(Expression::SubNode(crate::expression::ExprNode {
-										operation: crate::expression::Op::Equality,
-										values: vec![
-											crate::expression::SubExpression { inverse: false, val: Expression::Variable(name.clone()), },
-											crate::expression::SubExpression { inverse: false, val: e, },
-											]
-										}) ));
+								let def = Expression::SubNode(crate::expression::ExprNode {
+									operation: crate::expression::Op::Equality,
+									values: vec![
+										crate::expression::SubExpression { inverse: false, val: Expression::Variable(name.clone()), },
+										crate::expression::SubExpression { inverse: false, val: e, },
+										]
+									});
+								let mut def_line = crate::Line::from_expr(def);
+								// - Only recorded when the source line is kept, an in-place edit would leave nothing to replay against
+								if derive_lines {
+									def_line.derivation = Some(crate::derivation::Derivation::new(vec![cur_line], &format!("define {}", name), &lines[cur_line].sel));
+								}
+								crate::insert_line(lines, insert_at, def_line);
+								sources.push(insert_at);
+								insert_at += 1;
+								op_arg = name.clone();
 								// And replace the selection with a variable reference
-								( Some(Expression::Variable(name)), "" )
+								( Some(Expression::Variable(name)), "extract" )
 							}
 							else
 							{
@@ -234,6 +497,157 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 								(None, "extract expression",)
 							}
 							},
+						// Reverse substitution
+						6 => {
+							// Find lines of the form `<var> = <selection>`
+							let mut options = vec![];
+							for (i,line) in lines.iter().enumerate() {
+								let expr = match line.expr
+									{
+									Some(ref e) if i != cur_line => e,
+									_ => continue,
+									};
+								if let Some(name) = crate::manip::find_definition_of(expr, &e) {
+									options.push( (format!("L{}: {} = {}", i+1, name, e), name, i) );
+								}
+							}
+							let names: Vec<&str> = options.iter().map(|v| &v.0[..]).collect();
+							if options.len() == 0 {
+								(None, "reverse substitute",)
+							}
+							else if let Some(idx) = show_menu_modal(&window, &names) {
+								let (_, name, line_idx) = options.swap_remove(idx);
+								sources.push(line_idx);
+								(Some(Expression::Variable(name)), "reverse substitute",)
+							}
+							else {
+								(None, "reverse substitute",)
+							}
+							},
+						// Differentiate the selection, adding `d/dx*(...) = ...` as a new line
+						7 => {
+							let var = show_input_modal(&window, "");
+							let var = var.trim();
+							if var != "" {
+								op_arg = var.to_owned();
+								is_new_line = true;
+								(crate::manip::derivative_equation(&e, var), "differentiate",)
+							}
+							else {
+								(None, "differentiate",)
+							}
+							},
+						// Integrate the selection, adding the result (with a new constant of integration) as a new line
+						8 => {
+							let var = show_input_modal(&window, "");
+							let var = var.trim();
+							if var != "" {
+								// Optional name for the result (e.g. `v` when integrating acceleration)
+								let name = show_input_modal(&window, "");
+								let name = name.trim();
+								let constant = crate::manip::fresh_variable(lines.iter().filter_map(|l| l.expr.as_ref()), "C");
+								op_arg = format!("{} {} {}", var, constant, name).trim_end().to_owned();
+								is_new_line = true;
+								(crate::manip::integral_equation(&e, var, &constant, name), "integrate",)
+							}
+							else {
+								(None, "integrate",)
+							}
+							},
+						// Expand the selection as a truncated power series (prompting for the variable, point, and order)
+						9 => {
+							let var = show_input_modal(&window, "");
+							let point = show_input_modal(&window, "0");
+							let order = show_input_modal(&window, "2");
+							let var = var.trim();
+							match (point.parse::<Expression>(), order.trim().parse::<usize>())
+							{
+							(_, Ok(order)) if order > crate::manip::MAX_SERIES_ORDER => {
+								statusline = format!("Series order is limited to {}", crate::manip::MAX_SERIES_ORDER).into();
+								is_complete = true;
+								(None, "expand series",)
+								},
+							(Ok(point), Ok(order)) if var != "" => {
+								op_arg = format!("{} {} {}", var, order, point);
+								(crate::manip::taylor_series(&e, var, &point, order), "expand series",)
+								},
+							_ => (None, "expand series",),
+							}
+							},
+						// Reorder the selection as a polynomial in descending powers
+						10 => {
+							let var = show_input_modal(&window, "");
+							let var = var.trim();
+							op_arg = var.to_owned();
+							(crate::poly::Polynomial::from_expr(&e, var).map(|p| p.to_expr()), "order polynomial",)
+							},
+						// Show the degree and coefficients
+						11 => {
+							let var = show_input_modal(&window, "");
+							match crate::poly::Polynomial::from_expr(&e, var.trim())
+							{
+							Some(p) => {
+								let coeffs: Vec<_> = p.coeffs.iter().rev().map(|c| format!("{}", c)).collect();
+								statusline = match p.degree()
+									{
+									Some(d) => format!("Degree {} in {}, coefficients (descending): {}", d, p.var, coeffs.join(", ")),
+									None => format!("Zero polynomial"),
+									}.into();
+								is_complete = true;
+								},
+							None => {},
+							}
+							(None, "get polynomial for",)
+							},
+						// Long division by the clipboard, adding the quotient and remainder as new lines
+						12 => {
+							let divisor = match clipboard
+								{
+								Clipboard::Line(crate::Line { expr: Some(ref clp_e), .. }) | Clipboard::Expr(ref clp_e) => Some(clp_e.clone()),
+								_ => None,
+								};
+							let var = show_input_modal(&window, "");
+							let var = var.trim();
+							let res = divisor.as_ref()
+								.and_then(|d| Some( (crate::poly::Polynomial::from_expr(&e, var)?, crate::poly::Polynomial::from_expr(d, var)?) ))
+								.and_then(|(n, d)| n.div_rem(&d));
+							if let Some( (q, r) ) = res
+							{
+								let divisor = divisor.unwrap();
+								let mut q_line = crate::Line::from_expr(q.to_expr());
+								q_line.comment = format!(" quotient of ({}) / ({})", e, divisor);
+								let mut r_line = crate::Line::from_expr(r.to_expr());
+								r_line.comment = format!(" remainder of ({}) / ({})", e, divisor);
+								crate::insert_line(lines, cur_line + 1, q_line);
+								crate::insert_line(lines, cur_line + 2, r_line);
+								cur_line += 1;
+								is_complete = true;
+							}
+							(None, "divide polynomial",)
+							},
+						// Quadratic transformations (prompting for the variable)
+						13 => {
+							let var = show_input_modal(&window, "");
+							op_arg = var.trim().to_owned();
+							(crate::poly::factor_quadratic(&e, var.trim()), "factor quadratic",)
+							},
+						14 => {
+							let var = show_input_modal(&window, "");
+							op_arg = var.trim().to_owned();
+							(crate::poly::complete_square(&e, var.trim()), "complete square",)
+							},
+						// Decompose a rational function (prompting for the variable)
+						15 => {
+							let var = show_input_modal(&window, "");
+							op_arg = var.trim().to_owned();
+							(crate::poly::partial_fractions(&e, var.trim()), "partial fractions",)
+							},
+						// User-provided rewrite rules
+						_ if opid >= BUILTIN_OPS.len() => {
+							let rule = &rules[opid - BUILTIN_OPS.len()];
+							op_arg = rule.name.clone();
+							(rule.apply(&e), "apply rule",)
+							},
 						_ => {
 							(None, "",)
 							}
@@ -241,9 +655,30 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 					if let Some(e2) = res
 					{
 						log!(window, "{} - {:?} - {} => {}", opname, lines[cur_line].sel, lines[cur_line].extract_selection(), e2);
-						lines[cur_line].replace_selection( e2 );
+						let opname = if op_arg != "" { format!("{} {}", opname, op_arg) } else { opname.to_owned() };
+						let d = crate::derivation::Derivation::new(sources, &opname, &lines[cur_line].sel);
+						if is_new_line {
+							let mut l = crate::Line::from_expr(e2);
+							l.derivation = Some(d);
+							crate::insert_line(lines, insert_at, l);
+							cur_line = insert_at;
+						}
+						else if derive_lines {
+							// Add the result as a new line, recording how it was derived
+							let new_line = lines[cur_line].derive(e2, d);
+							crate::insert_line(lines, insert_at, new_line);
+							cur_line = insert_at;
+						}
+						else {
+							lines[cur_line].replace_selection(e2);
+							// The line no longer matches what its derivation produced
+							lines[cur_line].derivation = None;
+						}
 						//TODO: Run a merge pass on the line after replacement (replacement might have left some mess)
 					}
+					else if is_complete
+					{
+					}
 					else if opname == ""
 					{
 						log!(window, "BUG: Unknown operation {}", opid);
@@ -251,7 +686,7 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 					else
 					{
 						log!(window, "Unable to {} in {}", opname, lines[cur_line].extract_selection());
-						statusline = format!("Unable to {}", opname).into();
+						statusline = format!("Cannot {}", opname).into();
 					}
 				}
 				else
@@ -266,6 +701,10 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 		Some(pc::Input::KeyEnter) | Some(pc::Input::Character('\n')) =>
 			match mode
 			{
+			InputMode::LineSelect if lines[cur_line].expr.is_none() => {
+				statusline = "Line has no expression".into();
+				redraw = Redraw::Current;
+				},
 			InputMode::LineSelect => {
 				mode = InputMode::ExprPick;
 				redraw = Redraw::Current;
@@ -280,47 +719,112 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 			match mode
 			{
 			InputMode::LineSelect => {
-				let s = format!("{}", lines[cur_line].expr);
+				let s = match (&lines[cur_line].expr, &lines[cur_line].raw)
+					{
+					(Some(e), _) => format!("{}", e),
+					// Fixing a line that failed to load
+					(None, Some(raw)) => raw.clone(),
+					(None, None) => String::new(),
+					};
 				let v = show_input_modal(&window, &s);
-				match v.parse::<crate::expression::Expression>()
-				{
-				Ok(expr) => {
-					lines[cur_line].expr = expr;
-					},
-				Err(e) => {
-					statusline = format!("Error parsing: {:?}", e).into();
-					},
+				if lines[cur_line].raw.is_some() {
+					// The raw text includes any `# comment`, so replace the whole line
+					match parse_input_line(&v)
+					{
+					Ok(l) => {
+						lines[cur_line] = l;
+						},
+					Err(e) => {
+						statusline = format!("Error parsing: {}", e).into();
+						},
+					}
+				}
+				else {
+					match parse_input(&v)
+					{
+					Ok(expr) => {
+						lines[cur_line].expr = Some(expr);
+						},
+					Err(e) => {
+						statusline = format!("Error parsing: {}", e).into();
+						},
+					}
 				}
 				redraw = Redraw::All;
 				},
 			InputMode::ExprSelect | InputMode::ExprPick => {
 				let s = lines[cur_line].render_selection();
 				let v = show_input_modal(&window, &s);
-				match v.parse::<crate::expression::Expression>()
+				match parse_input(&v)
 				{
 				Ok(expr) => {
 					lines[cur_line].replace_selection( expr );
 					},
 				Err(e) => {
-					statusline = format!("Error parsing: {:?}", e).into();
+					statusline = format!("Error parsing: {}", e).into();
 					},
 				}
 				redraw = Redraw::All;
 				},
 			_ => {},
 			}
+		// Edit the current line's comment
+		Some(pc::Input::Character('c')) =>
+			match mode
+			{
+			// Raw lines are saved unchanged, so the comment would be lost
+			InputMode::LineSelect if lines[cur_line].raw.is_some() => {
+				statusline = "Line failed to parse, edit it with `e` first".into();
+				redraw = Redraw::Current;
+				},
+			InputMode::LineSelect => {
+				let v = show_input_modal(&window, lines[cur_line].comment.trim());
+				let v = v.trim();
+				lines[cur_line].comment = if v == "" { String::new() } else { format!(" {}", v) };
+				redraw = Redraw::All;
+				},
+			_ => {},
+			},
+		Some(pc::Input::Character('t')) => {
+			pretty = !pretty;
+			statusline = if pretty { "2-D view" } else { "Flat view" }.into();
+			redraw = Redraw::All;
+			},
+		Some(pc::Input::Character('u')) => {
+			style = match style
+				{
+				crate::ui_helpers::Style::Ascii => crate::ui_helpers::Style::Unicode,
+				crate::ui_helpers::Style::Unicode => crate::ui_helpers::Style::Ascii,
+				};
+			statusline = format!("{:?} rendering", style).into();
+			redraw = Redraw::All;
+			},
+		Some(pc::Input::Character('m')) =>
+			match mode
+			{
+			InputMode::LineSelect => {
+				line_mark = if line_mark == Some(cur_line) { None } else { Some(cur_line) };
+				statusline = match line_mark
+					{
+					Some(m) => format!("Mark set at L{}", m + 1),
+					None => format!("Mark cleared"),
+					}.into();
+				redraw = Redraw::All;
+				},
+			_ => {},
+			},
 		Some(pc::Input::Character('o')) =>
 			match mode
 			{
 			InputMode::LineSelect => {
 				let v = show_input_modal(&window, "");
-				match v.parse::<crate::expression::Expression>()
+				match parse_input_line(&v)
 				{
-				Ok(expr) => {
-					lines.insert(cur_line + 1, crate::Line::from_expr(expr));
+				Ok(line) => {
+					crate::insert_line(lines, cur_line + 1, line);
 					},
 				Err(e) => {
-					statusline = format!("Error parsing: {:?}", e).into();
+					statusline = format!("Error parsing: {}", e).into();
 					},
 				}
 				redraw = Redraw::All;
@@ -332,13 +836,13 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 			{
 			InputMode::LineSelect => {
 				let v = show_input_modal(&window, "");
-				match v.parse::<crate::expression::Expression>()
+				match parse_input_line(&v)
 				{
-				Ok(expr) => {
-					lines.insert(cur_line, crate::Line::from_expr(expr));
+				Ok(line) => {
+					crate::insert_line(lines, cur_line, line);
 					},
 				Err(e) => {
-					statusline = format!("Error parsing: {:?}", e).into();
+					statusline = format!("Error parsing: {}", e).into();
 					},
 				}
 				redraw = Redraw::All;
@@ -351,7 +855,7 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 			{
 			InputMode::LineSelect => {
 				if cur_line < lines.len() {
-					clipboard = Clipboard::Line( lines.remove(cur_line) );
+					clipboard = Clipboard::Line( crate::remove_line(lines, cur_line) );
 					// TODO: Avoid this?
 					if cur_line != 0 {
 						cur_line -= 1;
@@ -398,7 +902,7 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 				Clipboard::Empty => {},
 				Clipboard::Expr(_) => {},
 				Clipboard::Line(l) => {
-					lines.insert(cur_line, l);
+					crate::insert_line(lines, cur_line, l);
 					redraw = Redraw::All;
 					},
 				},
@@ -416,7 +920,7 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 					if cur_line < lines.len() {
 						cur_line += 1;
 					}
-					lines.insert(cur_line, l);
+					crate::insert_line(lines, cur_line, l);
 					redraw = Redraw::All;
 					},
 				},
@@ -428,7 +932,12 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 			redraw = Redraw::Current;
 			},
 		Some(pc::Input::Character('v')) => {
-			mode = InputMode::ExprSelect;
+			if lines[cur_line].expr.is_some() {
+				mode = InputMode::ExprSelect;
+			}
+			else {
+				statusline = "Line has no expression".into();
+			}
 			redraw = Redraw::Current;
 			},
 		Some(pc::Input::KeyUp) | Some(pc::Input::Character('k')) =>
@@ -601,6 +1110,28 @@ pub fn mainloop(lines: &mut Vec<super::Line>)
 	pc::endwin();
 }
 
+/// Parse an expression typed by the user, as LaTeX if it looks like LaTeX
+fn parse_input(s: &str) -> Result<crate::expression::Expression, String>
+{
+	if s.contains('\\') || s.contains('{') {
+		crate::latex::parse(s).map_err(|e| format!("{}", e))
+	}
+	else {
+		s.parse::<crate::expression::Expression>().map_err(|e| format!("{}", e))
+	}
+}
+
+/// Parse a new line, which can have a `#` comment (or be only a comment) unless it's LaTeX
+fn parse_input_line(s: &str) -> Result<crate::Line, String>
+{
+	if s.contains('#') && !(s.contains('\\') || s.contains('{')) {
+		crate::Line::from_str(s).map_err(|e| format!("{}", e))
+	}
+	else {
+		parse_input(s).map(crate::Line::from_expr)
+	}
+}
+
 fn show_input_modal(win: &pc::Window, prime_value: &str) -> String
 {
 	let (mut before, mut after) = (prime_value.to_owned(), Vec::<char>::new(),);
@@ -668,7 +1199,8 @@ fn show_input_modal(win: &pc::Window, prime_value: &str) -> String
 		Some(pc::Input::KeyBackspace) => {
 			let _ = before.pop();
 			},
-		Some(pc::Input::Character(v @ ' ' ... 'z')) => {
+		// Includes `{|}~` for LaTeX input
+		Some(pc::Input::Character(v @ ' ' ... '~')) => {
 			before.push(v);
 			},
 		_ => {},
@@ -747,26 +1279,190 @@ fn show_menu_modal(win: &pc::Window, options: &[&str]) -> Option<usize>
 	}
 }
 
-fn draw_expression_nosel(win: &pc::Window, e: &Expression)
+/// Colour pair used for each token class (set up by `init_colours`)
+fn token_pair(class: TokenClass) -> i16
 {
-	use std::fmt::Write;
-	write!(WindowFmt(win), "{}", e);
+	match class
+	{
+	TokenClass::Variable => 1,
+	TokenClass::Literal => 2,
+	TokenClass::Operator => 3,
+	TokenClass::Paren => 4,
+	TokenClass::ParentParen => 5,
+	TokenClass::Comment => 6,
+	}
 }
-fn draw_expression(win: &pc::Window, line: &crate::Line)
+/// Set up the syntax colours (over the terminal's own background), if the terminal supports colour
+fn init_colours()
 {
-	let (before, hilight, after,) = line.render_split();
-	if hilight.len() > 0
+	if !pc::has_colors() {
+		return ;
+	}
+	pc::start_color();
+	pc::use_default_colors();
+	for &(class, fg) in [
+		(TokenClass::Variable, pc::COLOR_CYAN),
+		(TokenClass::Literal, pc::COLOR_YELLOW),
+		(TokenClass::Operator, pc::COLOR_GREEN),
+		(TokenClass::Paren, pc::COLOR_MAGENTA),
+		(TokenClass::ParentParen, pc::COLOR_RED),
+		(TokenClass::Comment, pc::COLOR_BLUE),
+		].iter()
 	{
-		win.addstr(&before);
-		win.attron(pc::Attribute::Underline);
-		win.addstr(&hilight);
-		win.attroff(pc::Attribute::Underline);
-		win.addstr(&after);
+		pc::init_pair(token_pair(class), fg, -1);
 	}
-	else
+}
+/// Draw rendered tokens in their colours, with `hilight` added to the selected ones
+fn draw_tokens(win: &pc::Window, tokens: &[Token], hilight: pc::Attribute)
+{
+	for t in tokens
 	{
-		assert!(after.len() == 0);
-		win.addstr(&before);
+		let mut attr = pc::COLOR_PAIR(token_pair(t.class) as pc::chtype);
+		if t.class == TokenClass::ParentParen {
+			attr |= pc::chtype::from(pc::Attribute::Bold);
+		}
+		if t.part == 1 {
+			attr |= pc::chtype::from(hilight);
+		}
+		win.attron(attr);
+		win.addstr(&t.text);
+		win.attroff(attr);
 	}
 }
 
+/// Attribute for comments and annotations
+fn comment_attr() -> pc::chtype
+{
+	pc::COLOR_PAIR(token_pair(TokenClass::Comment) as pc::chtype) | pc::chtype::from(pc::Attribute::Dim)
+}
+/// Draw the line's comment (dimmed) at the cursor, after the expression
+fn draw_comment(win: &pc::Window, line: &crate::Line)
+{
+	if line.comment.trim() == "" {
+		return ;
+	}
+	win.attron(comment_attr());
+	win.addstr(&format!("{}#{}", if line.expr.is_some() { "  " } else { "" }, line.comment));
+	win.attroff(comment_attr());
+}
+/// Draw a summary of how the line was derived, right-aligned on the current row
+fn draw_annotation(win: &pc::Window, y: i32, line: &crate::Line)
+{
+	if let Some(ref d) = line.derivation
+	{
+		let s = d.describe();
+		let x = win.get_max_x() - s.len() as i32 - 1;
+		// Only draw if it won't overlap the expression
+		if x > win.get_cur_x() + 1
+		{
+			win.mv(y, x);
+			win.attron(comment_attr());
+			win.addstr(&s);
+			win.attroff(comment_attr());
+		}
+	}
+}
+
+/// Draw a line without a selection, flagging (and highlighting) any unit mismatch
+fn draw_line_nosel(win: &pc::Window, y: i32, line: &crate::Line, units: &crate::units::Units, pretty: bool, style: crate::ui_helpers::Style)
+{
+	let e = match (&line.expr, &line.raw)
+		{
+		(Some(e), _) => e,
+		// Text that failed to parse
+		(None, Some(raw)) => {
+			win.mv(y, 1);
+			win.addch('?');
+			win.attron(pc::Attribute::Reverse);
+			win.addstr(raw);
+			win.attroff(pc::Attribute::Reverse);
+			return ;
+			},
+		(None, None) => {
+			win.mv(y, 2);
+			draw_comment(win, line);
+			return ;
+			},
+		};
+	if pretty
+	{
+		let err = units.check(e).err();
+		let b = crate::layout::layout(e, err.as_ref().map(|e| &e.sel));
+		win.mv(y + b.baseline as i32, 1);
+		win.addch(if err.is_some() { '!' } else { ' ' });
+		draw_block(win, y, 2, &b, pc::Attribute::Reverse);
+		draw_comment(win, line);
+		return ;
+	}
+	win.mv(y, 1);
+	match units.check(e)
+	{
+	Ok(_) => {
+		win.addch(' ');
+		draw_expression_nosel(win, e, style);
+		},
+	Err(err) => {
+		win.addch('!');
+		draw_tokens(win, &render_tokens(e, Some(&err.sel), style), pc::Attribute::Reverse);
+		},
+	}
+	draw_comment(win, line);
+}
+fn draw_expression_nosel(win: &pc::Window, e: &Expression, style: crate::ui_helpers::Style)
+{
+	draw_tokens(win, &render_tokens(e, None, style), pc::Attribute::Normal);
+}
+fn draw_expression(win: &pc::Window, y: i32, line: &crate::Line, pretty: bool, style: crate::ui_helpers::Style)
+{
+	// Selections are only made on lines with an expression
+	let e = match line.expr
+		{
+		Some(ref e) => e,
+		None => return,
+		};
+	if pretty {
+		draw_block(win, y, 2, &crate::layout::layout(e, Some(&line.sel)), pc::Attribute::Underline);
+	}
+	else {
+		draw_tokens(win, &render_tokens(e, Some(&line.sel), style), pc::Attribute::Underline);
+	}
+	draw_comment(win, line);
+}
+/// Draw a 2-D layout with its top-left at the given position (leaving the cursor at the end of the baseline)
+fn draw_block(win: &pc::Window, y: i32, x: i32, b: &crate::layout::Block, attr: pc::Attribute)
+{
+	for (r,row) in b.rows.iter().enumerate()
+	{
+		win.mv(y + r as i32, x);
+		for c in row.iter()
+		{
+			if c.hilight {
+				win.attron(attr);
+			}
+			win.addstr(&c.ch.to_string());
+			if c.hilight {
+				win.attroff(attr);
+			}
+		}
+	}
+	win.mv(y + b.baseline as i32, x + b.width() as i32);
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn input_lines() {
+		let l = parse_input_line("a = b # the comment").unwrap();
+		assert_eq!(l.expr.as_ref().map(|e| e.to_string()), Some("a=b".to_owned()));
+		assert_eq!(l.comment, " the comment");
+		let l = parse_input_line("# just a comment").unwrap();
+		assert!(l.expr.is_none());
+		// LaTeX input can't have comments (`#` isn't special there)
+		let l = parse_input_line("\\frac{a}{b}").unwrap();
+		assert_eq!(l.expr.as_ref().map(|e| e.to_string()), Some("a/b".to_owned()));
+		assert!(parse_input_line("a = = b # oops").is_err());
+	}
+}
@@ -0,0 +1,410 @@
+//!
+//! Record of how a line was derived from other lines
+//!
+//! Stored in the text format as a trailing `#@` comment, e.g. `a = b*c #@ from L3 by "factorise leading" at [1] 0-0`
+//!
+
+use crate::ui_helpers::Selection;
+use crate::expression::{Expression, SubExpression, Op};
+
+#[derive(Clone,Debug)]
+pub struct Derivation
+{
+	/// Indexes of the source lines (the first is the line that the operation was applied to)
+	pub sources: Vec<usize>,
+	/// Name of the operation (including any arguments)
+	pub operation: String,
+	/// Selection within the first source line that the operation was applied to
+	pub sel: Selection,
+}
+
+impl Derivation
+{
+	pub fn new(sources: Vec<usize>, operation: &str, sel: &Selection) -> Derivation
+	{
+		Derivation {
+			sources: sources,
+			operation: operation.to_owned(),
+			sel: sel.clone(),
+			}
+	}
+
+	/// Short description for display (omits the selection)
+	pub fn describe(&self) -> String
+	{
+		format!("from {} by {}", SourceList(&self.sources), self.operation)
+	}
+
+	/// Update source indexes after a line is inserted at `idx`
+	pub fn line_inserted(&mut self, idx: usize)
+	{
+		for s in self.sources.iter_mut()
+		{
+			if *s >= idx {
+				*s += 1;
+			}
+		}
+	}
+	/// Update source indexes after the line at `idx` is removed, returns false if this derivation depended on it
+	pub fn line_removed(&mut self, idx: usize) -> bool
+	{
+		if self.sources.iter().any(|&s| s == idx) {
+			return false;
+		}
+		for s in self.sources.iter_mut()
+		{
+			if *s > idx {
+				*s -= 1;
+			}
+		}
+		true
+	}
+}
+
+struct SourceList<'a>(&'a [usize]);
+impl std::fmt::Display for SourceList<'_>
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		for (i,s) in self.0.iter().enumerate()
+		{
+			if i > 0 {
+				f.write_str(",")?;
+			}
+			// Line numbers are 1-based when shown to the user
+			write!(f, "L{}", s + 1)?;
+		}
+		Ok( () )
+	}
+}
+
+impl std::fmt::Display for Derivation
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "from {} by \"", SourceList(&self.sources))?;
+		// Quoted, as rule names (used in the operation) can contain anything
+		for c in self.operation.chars()
+		{
+			match c
+			{
+			'"' | '\\' => write!(f, "\\{}", c)?,
+			'\n' => f.write_str("\\n")?,
+			_ => write!(f, "{}", c)?,
+			}
+		}
+		write!(f, "\" at {:?}", self.sel)
+	}
+}
+impl std::str::FromStr for Derivation
+{
+	type Err = String;
+	fn from_str(s: &str) -> Result<Derivation, String> {
+		let s = s.trim();
+		if !s.starts_with("from ") {
+			return Err(format!("Expected `from`"));
+		}
+		let s = &s["from ".len()..];
+		let by_pos = s.find(" by ").ok_or_else(|| format!("Expected `by`"))?;
+		let rest = &s[by_pos + " by ".len() ..];
+		if !rest.starts_with('"') {
+			return Err(format!("Expected a quoted operation name"));
+		}
+		let mut operation = String::new();
+		let mut it = rest.char_indices().skip(1);
+		let end = loop
+			{
+			match it.next()
+			{
+			Some( (i, '"') ) => break i,
+			Some( (_, '\\') ) =>
+				match it.next()
+				{
+				Some( (_, 'n') ) => operation.push('\n'),
+				Some( (_, c) ) => operation.push(c),
+				None => return Err(format!("Unterminated operation name")),
+				},
+			Some( (_, c) ) => operation.push(c),
+			None => return Err(format!("Unterminated operation name")),
+			}
+			};
+		let sel = rest[end+1..].trim_start();
+		if !sel.starts_with("at ") {
+			return Err(format!("Expected `at` after the operation"));
+		}
+		let sel = &sel["at ".len()..];
+
+		let mut sources = Vec::new();
+		for l in s[..by_pos].split(',')
+		{
+			let l = l.trim();
+			if !l.starts_with("L") {
+				return Err(format!("Bad source line `{}`", l));
+			}
+			match l[1..].parse::<usize>()
+			{
+			Ok(v) if v > 0 => sources.push(v - 1),
+			_ => return Err(format!("Bad source line `{}`", l)),
+			}
+		}
+		Ok(Derivation {
+			sources: sources,
+			operation: operation,
+			sel: sel.parse()?,
+			})
+	}
+}
+
+/// Re-run the operation recorded in a derivation, returning the expected expression for the derived line
+pub fn replay(lines: &[crate::Line], d: &Derivation, rules: &[crate::rewrite::Rule]) -> Result<Expression, String>
+{
+	use crate::manip;
+
+	let get_source = |i: usize| -> Result<&Expression, String> {
+		match d.sources.get(i)
+		{
+		Some(&idx) if idx < lines.len() => lines[idx].expr.as_ref().ok_or(format!("Source line L{} has no expression", idx + 1)),
+		Some(&idx) => Err(format!("Source line L{} doesn't exist", idx + 1)),
+		None => Err(format!("Missing source line {}", i + 1)),
+		}
+		};
+	let src = get_source(0)?;
+	if !d.sel.is_valid(src) {
+		return Err(format!("Selection {:?} is invalid in L{}", d.sel, d.sources[0] + 1));
+	}
+	let mut sel = d.sel.clone();
+	let selected = crate::ui_helpers::extract_subexpression(src, &sel);
+	// Replace the selection in the source line with the result of an operation
+	let mut replace = |e: Option<Expression>| -> Result<Expression, String> {
+		match e
+		{
+		Some(e) => {
+			let mut rv = src.clone();
+			crate::ui_helpers::replace_subexpression(&mut rv, &mut sel, e);
+			Ok(rv)
+			},
+		None => Err(format!("Unable to {} `{}`", d.operation, selected)),
+		}
+		};
+
+	let (opname, arg) = split_operation(&d.operation);
+	match opname
+	{
+	"factorise all" => replace( manip::factorise_all(selected.clone()) ),
+	"factorise leading" => replace( manip::factorise_leading(selected.clone()) ),
+	"factorise trailing" => replace( manip::factorise_trailing(selected.clone()) ),
+	"substitute" => {
+		let def = get_source(1)?;
+		match selected
+		{
+		Expression::Variable(ref v) => replace( manip::find_definition(def, v).or_else(|| manip::solve_for(def, v)) ),
+		_ => Err(format!("Selection `{}` isn't a variable", selected)),
+		}
+		},
+	"reverse substitute" => {
+		let def = get_source(1)?;
+		replace( manip::find_definition_of(def, &selected).map(Expression::Variable) )
+		},
+	"define" => Ok( manip::make_node(Op::Equality, vec![
+		SubExpression { inverse: false, val: Expression::Variable(arg.to_owned()) },
+		SubExpression { inverse: false, val: selected.clone() },
+		]) ),
+	"extract" => {
+		let def = get_source(1)?;
+		if manip::find_definition(def, arg).as_ref() != Some(&selected) {
+			return Err(format!("L{} doesn't define {} as `{}`", d.sources[1] + 1, arg, selected));
+		}
+		replace( Some(Expression::Variable(arg.to_owned())) )
+		},
+	"apply rule" =>
+		match rules.iter().find(|r| r.name == arg)
+		{
+		Some(r) => replace( r.apply(&selected) ),
+		None => Err(format!("Unknown rule `{}`", arg)),
+		},
+	"apply" =>
+		match manip::parse_side_op(arg)
+		{
+		Some( (op, inverse, val) ) => manip::apply_to_both_sides(src, op, inverse, &val).ok_or_else(|| format!("L{} isn't an equation", d.sources[0] + 1)),
+		None => Err(format!("Bad operation `{}`", arg)),
+		},
+	"differentiate" => manip::derivative_equation(&selected, arg).ok_or_else(|| format!("Unable to differentiate `{}`", selected)),
+	"integrate" => {
+		// Arguments are `<var> <constant> [<name>]`
+		let args: Vec<&str> = arg.split_whitespace().collect();
+		if args.len() < 2 || args.len() > 3 {
+			return Err(format!("Bad integration arguments `{}`", arg));
+		}
+		manip::integral_equation(&selected, args[0], args[1], args.get(2).cloned().unwrap_or("")).ok_or_else(|| format!("Cannot integrate `{}`", selected))
+		},
+	"expand series" => {
+		// Arguments are `<var> <order> <point>`
+		let args: Vec<&str> = arg.splitn(3, ' ').collect();
+		if args.len() != 3 {
+			return Err(format!("Bad series arguments `{}`", arg));
+		}
+		let order = args[1].parse().map_err(|_| format!("Bad series order `{}`", args[1]))?;
+		let point = args[2].parse().map_err(|e| format!("Bad series point `{}` - {}", args[2], e))?;
+		replace( manip::taylor_series(&selected, args[0], &point, order) )
+		},
+	"order polynomial" => replace( crate::poly::Polynomial::from_expr(&selected, arg).map(|p| p.to_expr()) ),
+	"factor quadratic" => replace( crate::poly::factor_quadratic(&selected, arg) ),
+	"complete square" => replace( crate::poly::complete_square(&selected, arg) ),
+	"partial fractions" => replace( crate::poly::partial_fractions(&selected, arg) ),
+	"find root" => {
+		// Arguments are `<var> <low> <high> <n>` (the `n`th root found in the interval, using earlier binding lines)
+		let args: Vec<&str> = arg.split_whitespace().collect();
+		if args.len() != 4 {
+			return Err(format!("Bad root arguments `{}`", arg));
+		}
+		let lo = args[1].parse().map_err(|_| format!("Bad interval start `{}`", args[1]))?;
+		let hi = args[2].parse().map_err(|_| format!("Bad interval end `{}`", args[2]))?;
+		let n: usize = args[3].parse().map_err(|_| format!("Bad root number `{}`", args[3]))?;
+		let bindings = crate::eval::Bindings::from_lines(&lines[..d.sources[0]]);
+		let roots = crate::eval::find_roots(src, args[0], &bindings, lo, hi).map_err(|e| format!("{}", e))?;
+		match roots.get(n.wrapping_sub(1))
+		{
+		Some(&(x, _)) => Ok(crate::eval::root_line(args[0], x)),
+		None => Err(format!("Only {} roots of L{} found", roots.len(), d.sources[0] + 1)),
+		}
+		},
+	"solve linear" => {
+		// Arguments are `<var> of <unknown>,<unknown>,...`, with every source line being one of the equations
+		let (var, unknowns) = match arg.find(" of ")
+			{
+			Some(p) => (&arg[..p], &arg[p + " of ".len()..]),
+			None => return Err(format!("Bad linear solve arguments `{}`", arg)),
+			};
+		let unknowns: Vec<String> = unknowns.split(',').map(|v| v.trim().to_owned()).collect();
+		let idx = unknowns.iter().position(|u| u == var).ok_or_else(|| format!("`{}` isn't one of the unknowns", var))?;
+		let equations = (0 .. d.sources.len()).map(|i| get_source(i)).collect::<Result<Vec<_>,_>>()?;
+		crate::linear::solve(&equations, &unknowns)
+			.map(|mut v| v.remove(idx))
+			.map_err(|e| format!("{}", e))
+		},
+	_ => Err(format!("Unknown operation `{}`", d.operation)),
+	}
+}
+
+/// Split an operation string into the operation and its argument
+fn split_operation(s: &str) -> (&str, &str)
+{
+	const OPS: &[&str] = &["factorise all", "factorise leading", "factorise trailing", "reverse substitute", "substitute", "define", "extract", "apply rule", "apply", "differentiate", "integrate", "expand series", "order polynomial", "factor quadratic", "complete square", "partial fractions", "find root", "solve linear"];
+	for op in OPS
+	{
+		if s == *op {
+			return (op, "");
+		}
+		if s.starts_with(op) && s[op.len()..].starts_with(" ") {
+			return (op, s[op.len()+1..].trim());
+		}
+	}
+	(s, "")
+}
+
+/// Check every derived line, returning the list of failures (line index and message)
+pub fn check_all(lines: &[crate::Line], rules: &[crate::rewrite::Rule]) -> Vec<(usize, String)>
+{
+	let mut rv = Vec::new();
+	for (i,l) in lines.iter().enumerate()
+	{
+		if let Some(ref d) = l.derivation
+		{
+			match replay(lines, d, rules)
+			{
+			Ok(mut e) => {
+				let mut found = l.expr.clone().expect("derivation on comment line");
+				crate::manip::simplify(&mut e);
+				crate::manip::simplify(&mut found);
+				if e != found {
+					rv.push( (i, format!("{} - expected `{}`, found `{}`", d.describe(), e, found)) );
+				}
+				},
+			Err(msg) => rv.push( (i, format!("{} - {}", d.describe(), msg)) ),
+			}
+		}
+	}
+	rv
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn roundtrip(d: &Derivation) -> Derivation {
+		let s = d.to_string();
+		match s.parse::<Derivation>()
+		{
+		Ok(v) => v,
+		Err(e) => panic!("`{}` didn't parse - {}", s, e),
+		}
+	}
+
+	#[test]
+	fn format_roundtrip() {
+		let d = Derivation::new(vec![2, 0], "substitute", &Selection::new());
+		let d2 = roundtrip(&d);
+		assert_eq!(d2.sources, vec![2, 0]);
+		assert_eq!(d2.operation, "substitute");
+		assert_eq!(format!("{:?}", d2.sel), format!("{:?}", d.sel));
+	}
+	#[test]
+	fn awkward_operation_names() {
+		for name in &["apply rule Shift by one", "apply rule Look at \"this\" \\ here", "apply rule a\nb", "apply rule ends at "] {
+			let d = Derivation::new(vec![0], name, &Selection::new());
+			assert_eq!(roundtrip(&d).operation, *name);
+		}
+	}
+	#[test]
+	fn malformed() {
+		assert!("from L3 by factorise leading at [] 0-0".parse::<Derivation>().is_err());
+		assert!("from L3 by \"factorise leading at [] 0-0".parse::<Derivation>().is_err());
+		assert!("from L0 by \"x\" at [] 0-0".parse::<Derivation>().is_err());
+		assert!("from L1 \"x\" at [] 0-0".parse::<Derivation>().is_err());
+	}
+	#[test]
+	fn replay_partial_fractions() {
+		let mut lines: Vec<crate::Line> = ["1/(x^2 - 1) = y"].iter().map(|l| crate::Line::from_str(l).unwrap()).collect();
+		let d = Derivation::new(vec![0], "partial fractions x", &Selection::new());
+		let e = replay(&lines, &d, &[]).unwrap();
+		assert_eq!(e.to_string(), "0.5/(x-1)-0.5/(x+1)=y");
+		let mut l = crate::Line::from_expr(e);
+		l.derivation = Some(d);
+		lines.push(l);
+		assert_eq!(check_all(&lines, &[]), vec![]);
+		// Not a rational function of the named variable
+		let d = Derivation::new(vec![0], "partial fractions y", &Selection::new());
+		assert!(replay(&lines, &d, &[]).is_err());
+	}
+	#[test]
+	fn replay_linear_solve() {
+		let lines: Vec<crate::Line> = ["x + y = 3", "x - y = 1"].iter().map(|l| crate::Line::from_str(l).unwrap()).collect();
+		let d = Derivation::new(vec![0, 1], "solve linear y of x,y", &Selection::new());
+		assert_eq!(replay(&lines, &d, &[]).unwrap().to_string(), "y=1");
+		let d = Derivation::new(vec![0, 1], "solve linear z of x,y", &Selection::new());
+		assert!(replay(&lines, &d, &[]).is_err());
+		let d = Derivation::new(vec![0], "solve linear x of x,y", &Selection::new());
+		assert!(replay(&lines, &d, &[]).is_err());
+	}
+	#[test]
+	fn replay_roots() {
+		let mut lines: Vec<crate::Line> = ["k = 2", "x^2 = k"].iter().map(|l| crate::Line::from_str(l).unwrap()).collect();
+		let roots = crate::eval::find_roots(lines[1].expr.as_ref().unwrap(), "x", &crate::eval::Bindings::from_lines(&lines[..1]), 0.0, 5.0).unwrap();
+		let mut l = crate::Line::from_expr(crate::eval::root_line("x", roots[0].0));
+		l.derivation = Some(Derivation::new(vec![1], "find root x 0 5 1", &Selection::new()));
+		lines.push(l);
+		assert_eq!(check_all(&lines, &[]), vec![]);
+		// Sources are renumbered as lines are added
+		crate::insert_line(&mut lines, 0, crate::Line::comment_only());
+		assert_eq!(lines[3].derivation.as_ref().unwrap().sources, vec![2]);
+		assert_eq!(check_all(&lines, &[]), vec![]);
+		// And a different binding is noticed
+		lines[1] = crate::Line::from_str("k = 3").unwrap();
+		assert_eq!(check_all(&lines, &[]).len(), 1);
+	}
+	#[test]
+	fn split_operations() {
+		assert_eq!(split_operation("apply rule Shift by one"), ("apply rule", "Shift by one"));
+		assert_eq!(split_operation("reverse substitute"), ("reverse substitute", ""));
+		assert_eq!(split_operation("apply *2"), ("apply", "*2"));
+	}
+}
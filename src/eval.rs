@@ -0,0 +1,375 @@
+//!
+//! Numeric evaluation of expressions
+//!
+
+use crate::expression::{Expression, ExprNode, Op};
+use std::collections::HashMap;
+
+pub type Value = f64;
+
+#[derive(Debug)]
+pub enum EvalError
+{
+	/// A variable without a value
+	Unbound(String),
+	DivideByZero,
+	/// Result isn't a real number (e.g. root of a negative)
+	Domain(String),
+	/// The sides of an equality evaluated to different values
+	Unequal(Value, Value),
+}
+impl std::fmt::Display for EvalError
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self
+		{
+		EvalError::Unbound(n) => write!(f, "`{}` has no value", n),
+		EvalError::DivideByZero => write!(f, "Division by zero"),
+		EvalError::Domain(e) => write!(f, "`{}` is not a real number", e),
+		EvalError::Unequal(a, b) => write!(f, "Sides are not equal ({} != {})", a, b),
+		}
+	}
+}
+
+/// Set of variable values
+#[derive(Default,Clone)]
+pub struct Bindings
+{
+	values: HashMap<String, Value>,
+}
+impl Bindings
+{
+	pub fn new() -> Bindings {
+		Default::default()
+	}
+	pub fn get(&self, name: &str) -> Option<Value> {
+		self.values.get(name).cloned()
+	}
+	pub fn set(&mut self, name: &str, v: Value) {
+		self.values.insert(name.to_owned(), v);
+	}
+
+	/// Collect bindings from lines of the form `x = <number>` (later lines override earlier ones)
+	pub fn from_lines(lines: &[crate::Line]) -> Bindings
+	{
+		let mut rv = Bindings::new();
+		for l in lines
+		{
+			if let Some( (name, v) ) = l.expr.as_ref().and_then(|e| get_binding(e, &rv)) {
+				rv.set(&name, v);
+			}
+		}
+		rv
+	}
+}
+
+/// If the expression is of the form `x = <constant>`, return the variable name and value
+pub fn get_binding(e: &Expression, b: &Bindings) -> Option<(String, Value)>
+{
+	match e
+	{
+	Expression::SubNode(ExprNode { operation: Op::Equality, ref values }) if values.len() == 2 =>
+		match values[0].val
+		{
+		Expression::Variable(ref name) if !crate::manip::uses_variable(&values[1].val, name) =>
+			match evaluate(&values[1].val, b)
+			{
+			Ok(v) => Some( (name.clone(), v) ),
+			Err(_) => None,
+			},
+		_ => None,
+		},
+	_ => None,
+	}
+}
+
+/// Build the line `var = <value>` for a root found by `find_roots`
+///
+/// Literals are only single precision, so the value is stored as a fraction of exactly representable integers.
+pub fn root_line(var: &str, x: Value) -> Expression
+{
+	use crate::expression::SubExpression;
+	// Largest integer exactly representable as a literal
+	const MAX_EXACT: i128 = 1 << 24;
+	let (negative, v) = match crate::rational::Rational::approximate(x, MAX_EXACT)
+		{
+		Some(r) => {
+			let abs = r.abs();
+			(r.is_negative(), match abs.to_expr()
+				{
+				// Decimals are only used if they're exact
+				Expression::Literal(v) if v as Value != abs.to_f64() => crate::manip::make_node(Op::MulDiv, vec![
+					SubExpression { inverse: false, val: Expression::Literal(abs.num() as f32) },
+					SubExpression { inverse: true, val: Expression::Literal(abs.den() as f32) },
+					]),
+				e => e,
+				})
+			},
+		None => (x < 0.0, Expression::Literal(x.abs() as f32)),
+		};
+	let v = if negative { Expression::Negative(Box::new(v)) } else { v };
+	crate::manip::make_node(Op::Equality, vec![
+		SubExpression { inverse: false, val: Expression::Variable(var.to_owned()) },
+		SubExpression { inverse: false, val: v },
+		])
+}
+
+/// Tolerance used when comparing the sides of an equality
+const EQ_TOLERANCE: Value = 1e-6;
+
+pub fn evaluate(e: &Expression, b: &Bindings) -> Result<Value, EvalError>
+{
+	match e
+	{
+	Expression::Negative(ref v) => Ok( -evaluate(v, b)? ),
+	Expression::SubNode(ref sn) => evaluate_node(sn, b),
+	Expression::Literal(v) => Ok( *v as Value ),
+	Expression::Variable(ref n) => b.get(n).ok_or_else(|| EvalError::Unbound(n.clone())),
+	}
+}
+fn evaluate_node(sn: &ExprNode, b: &Bindings) -> Result<Value, EvalError>
+{
+	match sn.operation
+	{
+	Op::Equality => {
+		let first = evaluate(&sn.values[0].val, b)?;
+		for v in sn.values[1..].iter()
+		{
+			let v = evaluate(&v.val, b)?;
+			if (v - first).abs() > EQ_TOLERANCE * Value::max(1.0, first.abs()) {
+				return Err(EvalError::Unequal(first, v));
+			}
+		}
+		Ok(first)
+		},
+	Op::AddSub => {
+		let mut rv = 0.0;
+		for v in sn.values.iter()
+		{
+			let x = evaluate(&v.val, b)?;
+			if v.inverse { rv -= x; } else { rv += x; }
+		}
+		Ok(rv)
+		},
+	Op::MulDiv => {
+		let mut rv = 1.0;
+		for v in sn.values.iter()
+		{
+			let x = evaluate(&v.val, b)?;
+			if v.inverse {
+				if x == 0.0 {
+					return Err(EvalError::DivideByZero);
+				}
+				rv /= x;
+			}
+			else {
+				rv *= x;
+			}
+		}
+		Ok(rv)
+		},
+	// Exponents are right-associative (`a^b^c` is `a^(b^c)`)
+	Op::ExpRoot => {
+		let mut rv = evaluate(&sn.values.last().unwrap().val, b)?;
+		for v in sn.values.iter().rev().skip(1)
+		{
+			let base = evaluate(&v.val, b)?;
+			if base == 0.0 && rv < 0.0 {
+				return Err(EvalError::DivideByZero);
+			}
+			let r = base.powf(rv);
+			if !r.is_finite() {
+				return Err(EvalError::Domain(format!("{}^{}", base, rv)));
+			}
+			rv = r;
+		}
+		Ok(rv)
+		},
+	}
+}
+
+/// Number of sub-intervals sampled when looking for sign changes
+const ROOT_SAMPLES: usize = 1000;
+
+/// Find the roots of an equation (or expression) in `var` within `[lo, hi]`, returning each root and its residual
+///
+/// The interval is sampled for sign changes, and each bracketed root is refined using Brent's method.
+pub fn find_roots(e: &Expression, var: &str, b: &Bindings, lo: Value, hi: Value) -> Result<Vec<(Value, Value)>, EvalError>
+{
+	use crate::expression::SubExpression;
+	// Roots of `lhs - rhs`
+	let f_expr = match e
+		{
+		Expression::SubNode(ExprNode { operation: Op::Equality, ref values }) if values.len() >= 2 => crate::manip::make_node(Op::AddSub, vec![
+			SubExpression { inverse: false, val: values[0].val.clone() },
+			SubExpression { inverse: true, val: values[1].val.clone() },
+			]),
+		_ => e.clone(),
+		};
+	let mut b = b.clone();
+	let mut f = |x: Value| -> Result<Value, EvalError> {
+		b.set(var, x);
+		evaluate(&f_expr, &b)
+		};
+
+	let mut rv = Vec::new();
+	let mut prev: Option<(Value, Value)> = None;
+	for i in 0 ..= ROOT_SAMPLES
+	{
+		let x = lo + (hi - lo) * i as Value / ROOT_SAMPLES as Value;
+		let y = match f(x)
+			{
+			Ok(y) => y,
+			// Points outside the domain are skipped (but missing variables are an error)
+			Err(EvalError::Unbound(n)) => return Err(EvalError::Unbound(n)),
+			Err(_) => {
+				prev = None;
+				continue ;
+				},
+			};
+		if y == 0.0 {
+			rv.push( (x, 0.0) );
+		}
+		else if let Some( (px, py) ) = prev
+		{
+			if py != 0.0 && (py < 0.0) != (y < 0.0)
+			{
+				if let Some(r) = brent(&mut f, px, py, x, y)
+				{
+					// Discontinuities (e.g. `1/x`) also change sign, but their residual doesn't shrink
+					match f(r)
+					{
+					Ok(res) if res.abs() < py.abs().min(y.abs()) => rv.push( (r, res) ),
+					_ => {},
+					}
+				}
+			}
+		}
+		prev = Some( (x, y) );
+	}
+	Ok(rv)
+}
+
+/// Brent's method, for a root bracketed by `a` and `b` (`fa` and `fb` having opposite signs)
+fn brent(f: &mut impl FnMut(Value) -> Result<Value, EvalError>, mut a: Value, mut fa: Value, mut b: Value, mut fb: Value) -> Option<Value>
+{
+	const TOLERANCE: Value = 1e-12;
+	let (mut c, mut fc) = (a, fa);
+	let mut d = b - a;
+	let mut e = d;
+	for _ in 0 .. 100
+	{
+		if (fb > 0.0) == (fc > 0.0) {
+			c = a;
+			fc = fa;
+			d = b - a;
+			e = d;
+		}
+		// Keep `b` as the best estimate
+		if fc.abs() < fb.abs() {
+			a = b; b = c; c = a;
+			fa = fb; fb = fc; fc = fa;
+		}
+		let tol = 2.0 * Value::EPSILON * b.abs() + 0.5 * TOLERANCE;
+		let mid = 0.5 * (c - b);
+		if mid.abs() <= tol || fb == 0.0 {
+			return Some(b);
+		}
+		if e.abs() >= tol && fa.abs() > fb.abs()
+		{
+			// Try interpolation (secant or inverse quadratic)
+			let s = fb / fa;
+			let (mut p, mut q) = if a == c {
+					(2.0 * mid * s, 1.0 - s)
+				}
+				else {
+					let q = fa / fc;
+					let r = fb / fc;
+					(s * (2.0 * mid * q * (q - r) - (b - a) * (r - 1.0)), (q - 1.0) * (r - 1.0) * (s - 1.0))
+				};
+			if p > 0.0 {
+				q = -q;
+			}
+			p = p.abs();
+			if 2.0 * p < Value::min(3.0 * mid * q - (tol * q).abs(), (e * q).abs()) {
+				e = d;
+				d = p / q;
+			}
+			else {
+				// Interpolation failed, fall back to bisection
+				d = mid;
+				e = d;
+			}
+		}
+		else
+		{
+			d = mid;
+			e = d;
+		}
+		a = b;
+		fa = fb;
+		b += if d.abs() > tol { d } else { tol.copysign(mid) };
+		fb = f(b).ok()?;
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn bindings(lines: &[&str]) -> Bindings {
+		let lines: Vec<crate::Line> = lines.iter().map(|l| crate::Line::from_str(l).unwrap()).collect();
+		Bindings::from_lines(&lines)
+	}
+
+	#[test]
+	fn evaluation() {
+		let b = bindings(&["a = 2", "b = a*3", "a = 4"]);
+		assert_eq!(b.get("a"), Some(4.0));
+		assert_eq!(b.get("b"), Some(6.0));
+		assert_eq!(evaluate(&"a^2 - b/3".parse().unwrap(), &b).unwrap(), 14.0);
+		assert!(match evaluate(&"c + 1".parse().unwrap(), &b) { Err(EvalError::Unbound(ref n)) => n == "c", _ => false });
+		assert!(match evaluate(&"1/(a - 4)".parse().unwrap(), &b) { Err(EvalError::DivideByZero) => true, _ => false });
+	}
+	#[test]
+	fn evaluation_edge_cases() {
+		let b = bindings(&["x = 3", "y = y + 1", "z = w"]);
+		let eval = |s: &str| evaluate(&s.parse().unwrap(), &b);
+		// Only constant definitions become bindings
+		assert_eq!(b.get("y"), None);
+		assert_eq!(b.get("z"), None);
+		assert_eq!(eval("-x^2").unwrap(), -9.0);
+		assert_eq!(eval("2^3^2").unwrap(), 512.0);
+		assert!(match eval("(0-4)^0.5") { Err(EvalError::Domain(_)) => true, _ => false });
+		assert!(match eval("0^(0-1)") { Err(EvalError::DivideByZero) => true, _ => false });
+		// Equalities evaluate to their common value
+		assert_eq!(eval("x = 6/2 = 1.5*2").unwrap(), 3.0);
+		assert!(match eval("x = 4") { Err(EvalError::Unequal(a, b)) => a == 3.0 && b == 4.0, _ => false });
+	}
+	#[test]
+	fn roots() {
+		let b = bindings(&["k = 2"]);
+		let roots = find_roots(&"x^3 = k".parse().unwrap(), "x", &b, -10.0, 10.0).unwrap();
+		assert_eq!(roots.len(), 1);
+		assert!((roots[0].0 - 2f64.powf(1.0 / 3.0)).abs() < 1e-12);
+		let roots = find_roots(&"x^2 - 1".parse().unwrap(), "x", &b, -10.0, 10.0).unwrap();
+		assert_eq!(roots.iter().map(|r| r.0.round()).collect::<Vec<_>>(), vec![-1.0, 1.0]);
+		// Sign changes at a pole aren't roots
+		assert_eq!(find_roots(&"1/x".parse().unwrap(), "x", &b, -1.0, 2.0).unwrap().len(), 0);
+		assert!(find_roots(&"x = y".parse().unwrap(), "x", &b, -1.0, 1.0).is_err());
+	}
+	#[test]
+	fn root_lines_keep_precision() {
+		let x = 2f64.powf(1.0 / 3.0);
+		let e = root_line("x", x);
+		// Survives being saved and loaded
+		let e2: Expression = e.to_string().parse().unwrap();
+		assert_eq!(e, e2);
+		let (_, v) = get_binding(&e2, &Bindings::new()).unwrap();
+		assert!((v - x).abs() < 1e-12, "{} from {}", v, e2);
+		assert_eq!(root_line("x", -0.5).to_string(), "x=-0.5");
+		assert_eq!(root_line("x", 3.0).to_string(), "x=3");
+	}
+}
@@ -38,12 +38,58 @@ pub struct ExprNode
 	pub operation: Op,
 	pub values: Vec<SubExpression>,
 }
+/// Greek letter names (as used in identifiers, e.g. `theta_0`) and their glyphs
+pub const GREEK: &[(&str, char)] = &[
+	("alpha", 'α'), ("beta", 'β'), ("gamma", 'γ'), ("delta", 'δ'), ("epsilon", 'ε'), ("zeta", 'ζ'), ("eta", 'η'), ("theta", 'θ'),
+	("iota", 'ι'), ("kappa", 'κ'), ("lambda", 'λ'), ("mu", 'μ'), ("nu", 'ν'), ("xi", 'ξ'), ("pi", 'π'), ("rho", 'ρ'),
+	("sigma", 'σ'), ("tau", 'τ'), ("upsilon", 'υ'), ("phi", 'φ'), ("chi", 'χ'), ("psi", 'ψ'), ("omega", 'ω'),
+	("Gamma", 'Γ'), ("Delta", 'Δ'), ("Theta", 'Θ'), ("Lambda", 'Λ'), ("Xi", 'Ξ'), ("Pi", 'Π'), ("Sigma", 'Σ'),
+	("Upsilon", 'Υ'), ("Phi", 'Φ'), ("Psi", 'Ψ'), ("Omega", 'Ω'),
+	];
+/// Look up the glyph for a Greek letter name
+pub fn greek_letter(name: &str) -> Option<char>
+{
+	GREEK.iter().find(|g| g.0 == name).map(|g| g.1)
+}
+/// Split an identifier into its stem, the number of trailing primes (`'`), and the subscript (after the first `_`)
+pub fn split_ident(name: &str) -> (&str, usize, Option<&str>)
+{
+	let (base, sub) = match name.find('_')
+		{
+		Some(p) if p > 0 => (&name[..p], Some(&name[p+1..])),
+		_ => (name, None),
+		};
+	let stem = base.trim_end_matches('\'');
+	(stem, base.len() - stem.len(), sub)
+}
+/// Degree of the root to write for a fractional power (`1/n`), if any
+pub fn root_degree(exp: &Expression) -> Option<u32>
+{
+	match crate::rational::Rational::from_expr(exp)
+	{
+	Some(r) if r.num() == 1 && r.den() >= 2 && r.den() <= u32::max_value() as i128 => Some(r.den() as u32),
+	_ => None,
+	}
+}
+
 #[derive(Debug)]
 pub enum ParseError {
 	Empty,
+	/// Description of the problem (e.g. "Unexpected `=`")
 	Unexpected(String),
 	BadToken(String),
 }
+impl std::fmt::Display for ParseError
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self
+		{
+		ParseError::Empty => f.write_str("Empty expression"),
+		ParseError::Unexpected(s) => f.write_str(s),
+		ParseError::BadToken(t) => write!(f, "Bad token `{}`", t),
+		}
+	}
+}
 
 #[derive(PartialEq,PartialOrd,Eq,Ord)]
 enum Precedence
@@ -79,6 +125,16 @@ impl Expression {
 	pub fn needs_parens(&self, op: Op) -> bool {
 		Precedence::of_expr(self) <= Precedence::of_op(op)
 	}
+	/// Check if this expression needs brackets when it's the base of a power
+	pub fn needs_parens_as_base(&self) -> bool {
+		// Negated bases are bracketed too, as `-x^2` reads as `-(x^2)`
+		match self
+		{
+		Expression::Negative(_) => true,
+		Expression::Literal(v) => *v < 0.0,
+		_ => self.needs_parens(Op::ExpRoot),
+		}
+	}
 }
 
 
@@ -92,65 +148,60 @@ impl std::str::FromStr for Expression
 impl std::fmt::Display for Expression
 {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		match self
-		{
-		Expression::Negative(sn) =>
-			match **sn
-			{
-			Expression::Literal(_) => write!(f, "-{}", sn),
-			Expression::Variable(_) => write!(f, "-{}", sn),
-			_ => write!(f, "-({})", sn),
-			},
-		Expression::SubNode(sn) => std::fmt::Display::fmt(sn, f),
-		Expression::Literal(v) => std::fmt::Display::fmt(&v, f),
-		Expression::Variable(n) => std::fmt::Display::fmt(&n[..], f),
-		}
+		let mut r = TextRenderer { f: f, res: Ok( () ) };
+		crate::render::walk(&mut r, self, None);
+		r.res
 	}
 }
 impl std::fmt::Display for ExprNode
 {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		use std::fmt::Write;
 		assert!(self.values.len() > 1);
-
-		let emit_with_parens = |v: &Expression, f: &mut std::fmt::Formatter| {
-			let needs_parens = v.needs_parens(self.operation);
-			if needs_parens {
-				f.write_char('(')?;
-			}
-			std::fmt::Display::fmt(v, f)?;
-			if needs_parens {
-				f.write_char(')')?;
-			}
-			Ok( () )
-			};
-
-		for (i,v) in self.values.iter().enumerate()
-		{
-			if i == 0 {
-			}
-			else {
-				match self.operation
-				{
-				Op::AddSub => if v.inverse {
-						f.write_char('-')?;
-					}
-					else {
-						f.write_char('+')?;
-					},
-				Op::MulDiv => if v.inverse {
-						f.write_char('/')?;
-					}
-					else {
-						f.write_char('*')?;
-					},
-				Op::ExpRoot => { assert!(!v.inverse); f.write_char('^')? },
-				Op::Equality => f.write_char('=')?,
-				}
-			}
-			emit_with_parens(&v.val, f)?;
+		let mut r = TextRenderer { f: f, res: Ok( () ) };
+		crate::render::walk_node(&mut r, self);
+		r.res
+	}
+}
+/// Plain text rendering (the same syntax as the parser accepts)
+struct TextRenderer<'a, 'b>
+{
+	f: &'a mut std::fmt::Formatter<'b>,
+	/// First error from the formatter
+	res: std::fmt::Result,
+}
+impl TextRenderer<'_, '_>
+{
+	fn put(&mut self, v: impl std::fmt::Display) {
+		if self.res.is_ok() {
+			self.res = write!(self.f, "{}", v);
 		}
-		Ok( () )
+	}
+}
+impl crate::render::Renderer for TextRenderer<'_, '_>
+{
+	fn operator(&mut self, op: Op, inverse: bool, _next: &Expression) {
+		self.put(match op
+			{
+			Op::AddSub => if inverse { '-' } else { '+' },
+			Op::MulDiv => if inverse { '/' } else { '*' },
+			Op::ExpRoot => { assert!(!inverse); '^' },
+			Op::Equality => '=',
+			});
+	}
+	fn negate(&mut self) {
+		self.put('-');
+	}
+	fn literal(&mut self, v: f32) {
+		self.put(v);
+	}
+	fn variable(&mut self, name: &str) {
+		self.put(name);
+	}
+	fn open_paren(&mut self) {
+		self.put('(');
+	}
+	fn close_paren(&mut self) {
+		self.put(')');
 	}
 }
 
@@ -160,11 +211,33 @@ enum Token<'a> {
 	Whitespace,
 	Comment(&'a str),
 	Ident(&'a str),
+	/// Pattern metavariable (`?a`, only accepted when parsing rules)
+	MetaVar(&'a str),
 	Literal(f32),
 	Op(char),
+	/// Superscript exponent (e.g. `²`)
+	Superscript(&'a str),
+	/// Root sign (`√`, `∛` or `∜`)
+	Root(char),
 	ParenOpen,
 	ParenClose,
 }
+impl std::fmt::Display for Token<'_>
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self
+		{
+		Token::Eof => f.write_str("end of input"),
+		Token::Whitespace => f.write_str("whitespace"),
+		Token::Comment(_) => f.write_str("comment"),
+		Token::Ident(t) | Token::MetaVar(t) | Token::Superscript(t) => write!(f, "`{}`", t),
+		Token::Literal(v) => write!(f, "`{}`", v),
+		Token::Op(c) | Token::Root(c) => write!(f, "`{}`", c),
+		Token::ParenOpen => f.write_str("`(`"),
+		Token::ParenClose => f.write_str("`)`"),
+		}
+	}
+}
 ::plex::lexer! {
 	fn lex_next_token(text: 'a) -> Result<Token<'a>, ParseError>;
 
@@ -176,13 +249,21 @@ enum Token<'a> {
             } else {
 				Err(ParseError::BadToken(text.to_owned()))
             },
-	r#"[a-zA-Z][a-zA-Z0-9_']*"# => Ok(Token::Ident(text)),
+	// Greek letters and subscript digits are accepted (see `unicode`)
+	r#"[a-zA-Zα-ωΑ-Ω][a-zA-Z0-9_'α-ωΑ-Ω₀-₉]*"# => Ok(Token::Ident(text)),
+	// Pattern metavariables (see `rewrite`)
+	r#"\?[a-zA-Z][a-zA-Z0-9_']*"# => Ok(Token::MetaVar(text)),
 	r#"\+"# => Ok(Token::Op('+')),
 	r#"-"#  => Ok(Token::Op('-')),
 	r#"\*"# => Ok(Token::Op('*')),
 	r#"/"#  => Ok(Token::Op('/')),
 	r#"\^"# => Ok(Token::Op('^')),
 	r#"="#  => Ok(Token::Op('=')),
+	r#"[·×]"# => Ok(Token::Op('*')),
+	r#"−"#  => Ok(Token::Op('-')),
+	r#"÷"#  => Ok(Token::Op('/')),
+	r#"⁻?[⁰¹²³⁴⁵⁶⁷⁸⁹]+"# => Ok(Token::Superscript(text)),
+	r#"[√∛∜]"# => Ok(Token::Root(text.chars().next().unwrap())),
 
 	r#"\("# => Ok(Token::ParenOpen),
 	r#"\)"# => Ok(Token::ParenClose),
@@ -193,14 +274,17 @@ struct Lexer<'a>
 	//base: &'a str,
 	remaining: &'a str,
 	cur_token: Token<'a>,
+	/// Accept `?name` metavariables (see `rewrite`)
+	metavars: bool,
 }
 impl<'a> Lexer<'a>
 {
-	fn new(s: &'a str) -> Result<Lexer<'a>, ParseError> {
+	fn new(s: &'a str, metavars: bool) -> Result<Lexer<'a>, ParseError> {
 		let mut rv = Lexer {
 			//base: s,
 			remaining: s,
 			cur_token: Token::Eof,
+			metavars: metavars,
 			};
 		rv.consume()?;
 		Ok(rv)
@@ -240,7 +324,19 @@ impl<'a> Lexer<'a>
 impl Expression
 {
 	pub fn opt_parse_from_str_with_comment(s: &str) -> Result< (Option<Expression>, String), ParseError > {
-		let mut l = Lexer::new(s)?;
+		Self::opt_parse(s, false)
+	}
+	/// Parse a rewrite rule pattern (or replacement), which can contain `?name` metavariables
+	pub fn parse_pattern_with_comment(s: &str) -> Result< (Expression, String), ParseError > {
+		match Self::opt_parse(s, true)
+		{
+		Err(v) => Err(v),
+		Ok( (None, _) ) => Err(ParseError::Empty),
+		Ok( (Some(e), c) ) => Ok( (e, c) ),
+		}
+	}
+	fn opt_parse(s: &str, metavars: bool) -> Result< (Option<Expression>, String), ParseError > {
+		let mut l = Lexer::new(s, metavars)?;
 		let rv = if let Token::Comment(_) = l.cur() {
 				None
 			}
@@ -254,7 +350,7 @@ impl Expression
 				String::new()
 			};
 		if l.cur() != Token::Eof {
-			return Err( ParseError::Unexpected( format!("{:?}", l.cur()) ) );
+			return Err( ParseError::Unexpected( format!("Unexpected {}", l.cur()) ) );
 		}
 		Ok( (rv, c,) )
 	}
@@ -374,6 +470,13 @@ impl Expression
 
 		loop
 		{
+			// Superscript exponents (e.g. `x²`)
+			if let Token::Superscript(s) = lexer.cur() {
+				lexer.consume()?;
+				values.push(SubExpression { inverse: false, val: v });
+				v = crate::unicode::parse_superscript(s);
+				continue ;
+			}
 			if lexer.consume_if(Token::Op('^'))? {
 				false
 			}
@@ -381,7 +484,13 @@ impl Expression
 				break;
 			};
 			values.push(SubExpression { inverse: false, val: v });
-			v = Self::parse_5(lexer)?;
+			// Allow negative exponents without parentheses (e.g. `t^-2`)
+			v = if lexer.consume_if(Token::Op('-'))? {
+					Expression::Negative( Box::new(Self::parse_5(lexer)?) )
+				}
+				else {
+					Self::parse_5(lexer)?
+				};
 		}
 		if values.len() > 0
 		{
@@ -407,20 +516,110 @@ impl Expression
 				Expression::Literal(v)
 				},
 			Token::Ident(i) => {
+				lexer.consume()?;
+				Expression::Variable(crate::unicode::parse_ident(i))
+				},
+			// `√x` is `x^(1/2)`
+			Token::Root(c) => {
+				lexer.consume()?;
+				let v = Self::parse_5(lexer)?;
+				Expression::SubNode(ExprNode {
+					operation: Op::ExpRoot,
+					values: vec![
+						SubExpression { inverse: false, val: v },
+						SubExpression { inverse: false, val: Expression::SubNode(ExprNode {
+							operation: Op::MulDiv,
+							values: vec![
+								SubExpression { inverse: false, val: Expression::Literal(1.) },
+								SubExpression { inverse: true, val: Expression::Literal(crate::unicode::sign_degree(c)) },
+								],
+							}) },
+						],
+					})
+				},
+			Token::MetaVar(i) if lexer.metavars => {
 				lexer.consume()?;
 				Expression::Variable(i.to_owned())
 				},
+			Token::MetaVar(i) => return Err(ParseError::BadToken(i.to_owned())),
 			Token::ParenOpen => {
 				lexer.consume()?;
 				let rv = Self::parse_1(lexer)?;
 				if !lexer.consume_if(Token::ParenClose)? {
-					return Err(ParseError::Unexpected( format!("{:?}", lexer.cur()) ));
+					return Err(ParseError::Unexpected( format!("Unexpected {}", lexer.cur()) ));
 				}
 				return Ok(rv)
 				},
-			_ => return Err(ParseError::Unexpected( format!("{:?}", lexer.cur()) )),
+			_ => return Err(ParseError::Unexpected( format!("Unexpected {}", lexer.cur()) )),
 			})
 	}
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn unicode(e: &Expression) -> String {
+		crate::ui_helpers::render_tokens(e, None, crate::ui_helpers::Style::Unicode).iter().map(|t| &t.text[..]).collect()
+	}
+
+	#[test]
+	fn negative_power_base() {
+		let e: Expression = "(-x)^2".parse().unwrap();
+		assert_eq!(e.to_string(), "(-x)^2");
+		assert_eq!(e.to_string().parse::<Expression>().unwrap(), e);
+		let tex = crate::latex::Latex(&e).to_string();
+		assert_eq!(tex, "\\left(-x\\right)^{2}");
+		assert_eq!(crate::latex::parse(&tex).unwrap(), e, "{}", tex);
+		let mml = crate::mathml::MathMl(&e).to_string();
+		assert!(mml.contains("<msup><mrow><mo>(</mo>"), "{}", mml);
+		let (a, b, c) = crate::ui_helpers::split_expression(&e, &crate::ui_helpers::Selection::new());
+		assert_eq!(a + &b + &c, "(-x)^2");
+		assert_eq!(unicode(&e), "(−x)²");
+
+		// The negation of a power is unchanged
+		let e: Expression = "-(x^2)".parse().unwrap();
+		assert_eq!(e.to_string(), "-(x^2)");
+		assert_eq!(crate::latex::Latex(&e).to_string(), "-\\left(x^{2}\\right)");
+		assert_eq!(crate::latex::parse(&crate::latex::Latex(&e).to_string()).unwrap(), e);
+		assert_eq!(unicode(&e), "−(x²)");
+	}
+	#[test]
+	fn shared_helpers() {
+		let degree = |s: &str| root_degree(&s.parse().unwrap());
+		assert_eq!(degree("1/2"), Some(2));
+		assert_eq!(degree("1/3"), Some(3));
+		assert_eq!(degree("2/3"), None);
+		assert_eq!(degree("2"), None);
+		// Too large for a degree
+		assert_eq!(degree("1/(2^40)"), None);
+
+		assert_eq!(split_ident("x"), ("x", 0, None));
+		assert_eq!(split_ident("f''"), ("f", 2, None));
+		assert_eq!(split_ident("v'_max"), ("v", 1, Some("max")));
+		assert_eq!(split_ident("_a"), ("_a", 0, None));
+	}
+	#[test]
+	fn text_roundtrip() {
+		for s in &["a=b*c+d/e", "a^b^c", "(a^b)^c", "a/(b*c)", "a-(b-c)", "-(a+b)*c", "2^-x", "x^(1/2)"] {
+			let e: Expression = s.parse().unwrap();
+			assert_eq!(e.to_string().parse::<Expression>().unwrap(), e, "{} -> {}", s, e);
+		}
+	}
 
+	fn error(s: &str) -> String {
+		match s.parse::<Expression>()
+		{
+		Ok(e) => panic!("`{}` parsed as {}", s, e),
+		Err(e) => format!("{}", e),
+		}
+	}
+
+	#[test]
+	fn error_messages() {
+		assert_eq!(error(""), "Unexpected end of input");
+		assert_eq!(error("a = = b"), "Unexpected `=`");
+		assert_eq!(error("(a + b"), "Unexpected end of input");
+	}
+}
@@ -0,0 +1,582 @@
+//!
+//! Structured (JSON) file format, storing the full expression trees, selections and derivations
+//!
+
+use crate::expression::{Expression, ExprNode, SubExpression, Op};
+use crate::ui_helpers::Selection;
+use std::fmt::Write;
+
+/// Current version of the format (files with a newer version are rejected)
+pub const VERSION: u32 = 1;
+/// Value of the `format` field, identifying the file
+const FORMAT_NAME: &str = "equation";
+
+/// Parsed JSON value
+#[derive(Debug,Clone,PartialEq)]
+pub enum Value
+{
+	Null,
+	Bool(bool),
+	/// Number as written (so literals are read back exactly)
+	Number(String),
+	String(String),
+	Array(Vec<Value>),
+	/// Members in file order
+	Object(Vec<(String, Value)>),
+}
+impl Value
+{
+	fn get(&self, key: &str) -> Option<&Value> {
+		match self
+		{
+		Value::Object(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+		_ => None,
+		}
+	}
+	fn as_str(&self) -> Option<&str> {
+		match self
+		{
+		Value::String(ref s) => Some(s),
+		_ => None,
+		}
+	}
+	fn as_usize(&self) -> Option<usize> {
+		match self
+		{
+		Value::Number(ref s) => s.parse().ok(),
+		_ => None,
+		}
+	}
+	fn as_array(&self) -> Option<&[Value]> {
+		match self
+		{
+		Value::Array(ref v) => Some(v),
+		_ => None,
+		}
+	}
+}
+impl std::fmt::Display for Value
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self
+		{
+		Value::Null => f.write_str("null"),
+		Value::Bool(v) => write!(f, "{}", v),
+		Value::Number(ref s) => f.write_str(s),
+		Value::String(ref s) => write_string(f, s),
+		Value::Array(ref values) => {
+			f.write_str("[")?;
+			for (i,v) in values.iter().enumerate()
+			{
+				if i > 0 {
+					f.write_str(", ")?;
+				}
+				write!(f, "{}", v)?;
+			}
+			f.write_str("]")
+			},
+		Value::Object(ref members) => {
+			f.write_str("{")?;
+			for (i,(k,v)) in members.iter().enumerate()
+			{
+				if i > 0 {
+					f.write_str(", ")?;
+				}
+				write_string(f, k)?;
+				write!(f, ": {}", v)?;
+			}
+			f.write_str("}")
+			},
+		}
+	}
+}
+
+fn write_string(f: &mut impl Write, s: &str) -> std::fmt::Result
+{
+	f.write_char('"')?;
+	for c in s.chars()
+	{
+		match c
+		{
+		'"' => f.write_str("\\\"")?,
+		'\\' => f.write_str("\\\\")?,
+		'\n' => f.write_str("\\n")?,
+		'\t' => f.write_str("\\t")?,
+		'\u{0}' ..= '\u{1F}' => write!(f, "\\u{:04x}", c as u32)?,
+		_ => f.write_char(c)?,
+		}
+	}
+	f.write_char('"')
+}
+
+/// Error from parsing JSON, with the byte offset where it occurred
+#[derive(Debug)]
+pub struct JsonError
+{
+	pub pos: usize,
+	pub message: String,
+}
+impl std::fmt::Display for JsonError
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{} (at character {})", self.message, self.pos + 1)
+	}
+}
+
+/// Parse a single JSON value (with nothing but whitespace after it)
+pub fn parse(s: &str) -> Result<Value, JsonError>
+{
+	let mut p = Parser { s: s, pos: 0 };
+	let rv = p.value()?;
+	p.skip_whitespace();
+	if p.pos < s.len() {
+		return Err(p.error("Unexpected text after the end of the document"));
+	}
+	Ok(rv)
+}
+
+struct Parser<'a>
+{
+	s: &'a str,
+	pos: usize,
+}
+impl Parser<'_>
+{
+	fn error(&self, message: &str) -> JsonError {
+		JsonError { pos: self.pos, message: message.to_owned() }
+	}
+	fn peek(&self) -> Option<char> {
+		self.s[self.pos..].chars().next()
+	}
+	fn skip_whitespace(&mut self) {
+		while let Some(c) = self.peek()
+		{
+			if !(c == ' ' || c == '\t' || c == '\n' || c == '\r') {
+				break;
+			}
+			self.pos += 1;
+		}
+	}
+	/// Consume `c` (after any whitespace) if it's next
+	fn consume_if(&mut self, c: char) -> bool {
+		self.skip_whitespace();
+		if self.peek() == Some(c) {
+			self.pos += 1;
+			true
+		}
+		else {
+			false
+		}
+	}
+	fn expect(&mut self, c: char) -> Result<(), JsonError> {
+		if self.consume_if(c) {
+			Ok( () )
+		}
+		else {
+			Err(self.error(&format!("Expected `{}`", c)))
+		}
+	}
+
+	fn value(&mut self) -> Result<Value, JsonError>
+	{
+		self.skip_whitespace();
+		match self.peek()
+		{
+		Some('{') => {
+			self.pos += 1;
+			let mut members = Vec::new();
+			if !self.consume_if('}') {
+				loop
+				{
+					self.skip_whitespace();
+					let key = self.string()?;
+					self.expect(':')?;
+					members.push( (key, self.value()?) );
+					if self.consume_if('}') {
+						break;
+					}
+					self.expect(',')?;
+				}
+			}
+			Ok(Value::Object(members))
+			},
+		Some('[') => {
+			self.pos += 1;
+			let mut values = Vec::new();
+			if !self.consume_if(']') {
+				loop
+				{
+					values.push(self.value()?);
+					if self.consume_if(']') {
+						break;
+					}
+					self.expect(',')?;
+				}
+			}
+			Ok(Value::Array(values))
+			},
+		Some('"') => Ok(Value::String(self.string()?)),
+		Some('-') | Some('0' ..= '9') => {
+			let start = self.pos;
+			let len = self.s[start..].find(|c: char| !(c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E')).unwrap_or(self.s.len() - start);
+			let text = &self.s[start .. start + len];
+			if text.parse::<f64>().is_err() {
+				return Err(self.error(&format!("Bad number `{}`", text)));
+			}
+			self.pos += len;
+			Ok(Value::Number(text.to_owned()))
+			},
+		Some(_) => {
+			for &(word, ref v) in [("null", Value::Null), ("true", Value::Bool(true)), ("false", Value::Bool(false))].iter()
+			{
+				if self.s[self.pos..].starts_with(word) {
+					self.pos += word.len();
+					return Ok(v.clone());
+				}
+			}
+			Err(self.error("Expected a value"))
+			},
+		None => Err(self.error("Unexpected end of document")),
+		}
+	}
+
+	fn string(&mut self) -> Result<String, JsonError>
+	{
+		if self.peek() != Some('"') {
+			return Err(self.error("Expected a string"));
+		}
+		self.pos += 1;
+		let mut rv = String::new();
+		loop
+		{
+			let c = match self.peek()
+				{
+				Some(c) => c,
+				None => return Err(self.error("Unterminated string")),
+				};
+			self.pos += c.len_utf8();
+			match c
+			{
+			'"' => break,
+			'\\' => {
+				let e = self.peek().ok_or_else(|| self.error("Unterminated string"))?;
+				self.pos += e.len_utf8();
+				match e
+				{
+				'"' | '\\' | '/' => rv.push(e),
+				'b' => rv.push('\u{8}'),
+				'f' => rv.push('\u{C}'),
+				'n' => rv.push('\n'),
+				'r' => rv.push('\r'),
+				't' => rv.push('\t'),
+				'u' => {
+					let mut code = self.hex4()?;
+					// Surrogate pair
+					if 0xD800 <= code && code < 0xDC00 && self.s[self.pos..].starts_with("\\u") {
+						self.pos += 2;
+						let low = self.hex4()?;
+						code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+					}
+					rv.push(std::char::from_u32(code).unwrap_or('\u{FFFD}'));
+					},
+				_ => return Err(self.error(&format!("Unknown escape `\\{}`", e))),
+				}
+				},
+			_ => rv.push(c),
+			}
+		}
+		Ok(rv)
+	}
+	fn hex4(&mut self) -> Result<u32, JsonError> {
+		let digits = self.s.get(self.pos .. self.pos + 4).ok_or_else(|| self.error("Bad `\\u` escape"))?;
+		let rv = u32::from_str_radix(digits, 16).map_err(|_| self.error("Bad `\\u` escape"))?;
+		self.pos += 4;
+		Ok(rv)
+	}
+}
+
+fn op_name(op: Op) -> &'static str
+{
+	match op
+	{
+	Op::Equality => "=",
+	Op::AddSub => "+",
+	Op::MulDiv => "*",
+	Op::ExpRoot => "^",
+	}
+}
+
+/// Encode an expression (literals are numbers, variables are strings, and nodes are objects)
+pub fn expr_to_value(e: &Expression) -> Value
+{
+	match e
+	{
+	Expression::Literal(v) => Value::Number(format!("{}", v)),
+	Expression::Variable(ref n) => Value::String(n.clone()),
+	Expression::Negative(ref v) => Value::Object(vec![ ("negative".to_owned(), expr_to_value(v)) ]),
+	Expression::SubNode(ref sn) => Value::Object(vec![
+		("op".to_owned(), Value::String(op_name(sn.operation).to_owned())),
+		("values".to_owned(), Value::Array(sn.values.iter().map(|v| Value::Object(vec![
+			("inverse".to_owned(), Value::Bool(v.inverse)),
+			("value".to_owned(), expr_to_value(&v.val)),
+			])).collect())),
+		]),
+	}
+}
+/// Decode an expression (reverse of `expr_to_value`)
+pub fn value_to_expr(v: &Value) -> Result<Expression, String>
+{
+	match v
+	{
+	Value::Number(ref s) => s.parse().map(Expression::Literal).map_err(|_| format!("Bad literal `{}`", s)),
+	Value::String(ref s) => Ok(Expression::Variable(s.clone())),
+	Value::Object(_) => {
+		if let Some(inner) = v.get("negative") {
+			return Ok(Expression::Negative(Box::new(value_to_expr(inner)?)));
+		}
+		let op = match v.get("op").and_then(|o| o.as_str())
+			{
+			Some("=") => Op::Equality,
+			Some("+") => Op::AddSub,
+			Some("*") => Op::MulDiv,
+			Some("^") => Op::ExpRoot,
+			Some(o) => return Err(format!("Unknown operation `{}`", o)),
+			None => return Err(format!("Expected `op` or `negative`")),
+			};
+		let values = v.get("values").and_then(|v| v.as_array()).ok_or_else(|| format!("Expected `values` list"))?;
+		if values.len() < 2 {
+			return Err(format!("`{}` node needs at least two values", op_name(op)));
+		}
+		let mut rv = Vec::new();
+		for sv in values
+		{
+			rv.push(SubExpression {
+				inverse: sv.get("inverse") == Some(&Value::Bool(true)),
+				val: value_to_expr(sv.get("value").ok_or_else(|| format!("Expected `value`"))?)?,
+				});
+		}
+		Ok(Expression::SubNode(ExprNode { operation: op, values: rv }))
+		},
+	_ => Err(format!("Expected an expression, got `{}`", v)),
+	}
+}
+
+fn sel_to_value(sel: &Selection) -> Value
+{
+	Value::Object(vec![
+		("path".to_owned(), Value::Array(sel.path.iter().map(|i| Value::Number(i.to_string())).collect())),
+		("first".to_owned(), Value::Number(sel.first.to_string())),
+		("last".to_owned(), Value::Number(sel.last.to_string())),
+		])
+}
+fn value_to_sel(v: &Value) -> Result<Selection, String>
+{
+	let index = |key: &str| v.get(key).and_then(|i| i.as_usize()).ok_or_else(|| format!("Expected index `{}` in selection", key));
+	let path = v.get("path").and_then(|p| p.as_array()).ok_or_else(|| format!("Expected `path` in selection"))?;
+	Ok(Selection {
+		path: path.iter().map(|i| i.as_usize().ok_or_else(|| format!("Bad selection path entry `{}`", i))).collect::<Result<_,_>>()?,
+		first: index("first")?,
+		last: index("last")?,
+		})
+}
+
+/// Export lines and unit declarations as a structured document (one line per row)
+pub fn export(lines: &[crate::Line], units: &crate::units::Units) -> String
+{
+	let mut rv = String::new();
+	let _ = write!(rv, "{{\n\"format\": \"{}\",\n\"version\": {},\n", FORMAT_NAME, VERSION);
+	let units: Vec<Value> = units.declarations().map(Value::String).collect();
+	let _ = write!(rv, "\"units\": {},\n", Value::Array(units));
+	rv.push_str("\"lines\": [\n");
+	for (i,l) in lines.iter().enumerate()
+	{
+		let mut members = vec![
+			("expr".to_owned(), l.expr.as_ref().map(expr_to_value).unwrap_or(Value::Null)),
+			];
+		if l.comment != "" {
+			members.push( ("comment".to_owned(), Value::String(l.comment.clone())) );
+		}
+		if l.sel != Selection::new() {
+			members.push( ("selection".to_owned(), sel_to_value(&l.sel)) );
+		}
+		if let Some(ref d) = l.derivation {
+			members.push( ("derivation".to_owned(), Value::Object(vec![
+				("sources".to_owned(), Value::Array(d.sources.iter().map(|i| Value::Number(i.to_string())).collect())),
+				("operation".to_owned(), Value::String(d.operation.clone())),
+				("selection".to_owned(), sel_to_value(&d.sel)),
+				])) );
+		}
+		if let Some(ref raw) = l.raw {
+			members.push( ("raw".to_owned(), Value::String(raw.clone())) );
+		}
+		let _ = write!(rv, "\t{}{}\n", Value::Object(members), if i + 1 < lines.len() { "," } else { "" });
+	}
+	rv.push_str("]\n}\n");
+	rv
+}
+
+/// Check for the start of a structured document (instead of the plain one-line-per-expression format)
+pub fn is_document(s: &str) -> bool
+{
+	s.trim_start().starts_with('{')
+}
+
+/// Load a structured document (see `export`), with unknown fields ignored
+pub fn load(s: &str) -> Result<crate::EquationSet, String>
+{
+	let doc = parse(s).map_err(|e| format!("line {}: {}", s[..e.pos].matches('\n').count() + 1, e.message))?;
+	if doc.get("format").and_then(|f| f.as_str()) != Some(FORMAT_NAME) {
+		return Err(format!("Not an equation file (expected `\"format\": \"{}\"`)", FORMAT_NAME));
+	}
+	match doc.get("version").and_then(|v| v.as_usize())
+	{
+	Some(v) if v as u32 <= VERSION => {},
+	Some(v) => return Err(format!("File version {} is newer than supported ({})", v, VERSION)),
+	None => return Err(format!("Missing `version`")),
+	}
+
+	let mut rv = crate::EquationSet {
+		dirty: false,
+		lines: Vec::new(),
+		units: crate::units::Units::new(),
+		errors: Vec::new(),
+		};
+	for u in doc.get("units").and_then(|u| u.as_array()).unwrap_or(&[])
+	{
+		let u = u.as_str().ok_or_else(|| format!("Expected unit declaration string, got `{}`", u))?;
+		rv.units.parse_declaration(u)?;
+	}
+	let lines = doc.get("lines").and_then(|l| l.as_array()).ok_or_else(|| format!("Expected `lines` list"))?;
+	for (i,l) in lines.iter().enumerate()
+	{
+		let line_err = |e: String| format!("lines[{}]: {}", i, e);
+		let expr = match l.get("expr")
+			{
+			None | Some(Value::Null) => None,
+			Some(e) => Some(value_to_expr(e).map_err(line_err)?),
+			};
+		let mut sel = match l.get("selection")
+			{
+			Some(v) => value_to_sel(v).map_err(line_err)?,
+			None => Selection::new(),
+			};
+		// A selection that doesn't fit the expression would break the UI
+		if !expr.as_ref().map(|e| sel.is_valid(e)).unwrap_or(false) {
+			sel = Selection::new();
+		}
+		let derivation = match l.get("derivation")
+			{
+			Some(d) if expr.is_some() => {
+				let sources = d.get("sources").and_then(|s| s.as_array()).ok_or_else(|| line_err(format!("Expected derivation `sources`")))?;
+				let sources = sources.iter().map(|s| s.as_usize().ok_or_else(|| line_err(format!("Bad source line `{}`", s)))).collect::<Result<Vec<_>,_>>()?;
+				let operation = d.get("operation").and_then(|o| o.as_str()).ok_or_else(|| line_err(format!("Expected derivation `operation`")))?;
+				let d_sel = match d.get("selection")
+					{
+					Some(v) => value_to_sel(v).map_err(line_err)?,
+					None => Selection::new(),
+					};
+				Some(crate::derivation::Derivation::new(sources, operation, &d_sel))
+				},
+			_ => None,
+			};
+		rv.lines.push(crate::Line {
+			expr: expr,
+			comment: l.get("comment").and_then(|c| c.as_str()).unwrap_or("").to_owned(),
+			sel: sel,
+			derivation: derivation,
+			raw: l.get("raw").and_then(|r| r.as_str()).map(|r| r.to_owned()),
+			});
+	}
+	Ok(rv)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn values() {
+		let v = parse(r#" {"a": [1, -2.5e3, true, null], "b": {}, "c": []} "#).unwrap();
+		assert_eq!(v.get("a"), Some(&Value::Array(vec![
+			Value::Number("1".to_owned()), Value::Number("-2.5e3".to_owned()), Value::Bool(true), Value::Null,
+			])));
+		assert_eq!(v.get("b"), Some(&Value::Object(vec![])));
+		// Written back in the same form
+		assert_eq!(v.to_string(), r#"{"a": [1, -2.5e3, true, null], "b": {}, "c": []}"#);
+	}
+	#[test]
+	fn string_escapes() {
+		let s = "quote \" slash \\ newline \n tab \t bell \u{7} snake \u{1F40D}";
+		let v = Value::String(s.to_owned());
+		assert_eq!(parse(&v.to_string()).unwrap(), v);
+		assert_eq!(parse(r#""\u00e9\/\ud83d\udc0d""#).unwrap(), Value::String("é/\u{1F40D}".to_owned()));
+	}
+	#[test]
+	fn errors() {
+		let err = |s: &str| parse(s).unwrap_err().to_string();
+		assert_eq!(err("[1, 2"), "Expected `,` (at character 6)");
+		assert_eq!(err("{\"a\" 1}"), "Expected `:` (at character 6)");
+		assert_eq!(err("\"abc"), "Unterminated string (at character 5)");
+		assert_eq!(err("\"\\q\""), "Unknown escape `\\q` (at character 4)");
+		assert_eq!(err("\"\\u12\""), "Bad `\\u` escape (at character 4)");
+		assert_eq!(err("1 2"), "Unexpected text after the end of the document (at character 3)");
+		assert_eq!(err("nope"), "Expected a value (at character 1)");
+		assert_eq!(err("1-"), "Bad number `1-` (at character 1)");
+	}
+	#[test]
+	fn expressions() {
+		for s in &["a = b*c - d/e", "-(x + 1)^2", "x^y^z", "theta_0' = 0.1"] {
+			let e: Expression = s.parse().unwrap();
+			assert_eq!(value_to_expr(&expr_to_value(&e)).unwrap(), e);
+		}
+		assert!(value_to_expr(&parse(r#"{"op": "%", "values": [1, 2]}"#).unwrap()).is_err());
+		assert!(value_to_expr(&parse(r#"{"op": "+", "values": [1]}"#).unwrap()).is_err());
+		assert!(value_to_expr(&parse(r#"{"values": [1, 2]}"#).unwrap()).is_err());
+		assert!(value_to_expr(&Value::Bool(true)).is_err());
+	}
+
+	fn lines(v: &[&str]) -> Vec<crate::Line> {
+		v.iter().map(|l| crate::Line::from_str(l).unwrap()).collect()
+	}
+	#[test]
+	fn document_roundtrip() {
+		let mut units = crate::units::Units::new();
+		units.parse_declaration("unit t: s").unwrap();
+		let mut ls = lines(&["a = b + c # a \"quoted\" comment", "# only a comment"]);
+		ls[0].sel = "[1] 1-1".parse().unwrap();
+		let mut derived = crate::Line::from_expr("a = c + b".parse().unwrap());
+		derived.derivation = Some(crate::derivation::Derivation::new(vec![0], "apply rule Swap \"it\"", &"[1] 0-1".parse().unwrap()));
+		ls.push(derived);
+		ls.push(crate::Line::raw_text("not = = parsed"));
+
+		let doc = export(&ls, &units);
+		assert!(is_document(&doc));
+		let es = load(&doc).unwrap();
+		assert_eq!(es.lines.len(), 4);
+		for (a, b) in ls.iter().zip(es.lines.iter())
+		{
+			assert_eq!(a.expr, b.expr);
+			assert_eq!(a.comment, b.comment);
+			assert_eq!(a.sel, b.sel);
+			assert_eq!(a.raw, b.raw);
+			assert_eq!(a.derivation.as_ref().map(|d| d.to_string()), b.derivation.as_ref().map(|d| d.to_string()));
+		}
+		assert_eq!(es.units.declarations().collect::<Vec<_>>(), vec!["unit t: s".to_owned()]);
+		// And exporting again gives the same document
+		assert_eq!(export(&es.lines, &es.units), doc);
+	}
+	#[test]
+	fn document_checks() {
+		let err = |s: &str| load(s).err().expect("loaded");
+		assert_eq!(err(r#"{"format": "equation", "version": 2, "lines": []}"#), format!("File version 2 is newer than supported ({})", VERSION));
+		assert!(load(r#"{"format": "other", "version": 1, "lines": []}"#).is_err());
+		assert!(load(r#"{"format": "equation", "lines": []}"#).is_err());
+		assert!(load(r#"{"format": "equation", "version": 1}"#).is_err());
+		assert_eq!(err("{\n\"format\": \"equation\",\n\"version\": 1,\n\"lines\": [\n"), "line 5: Unexpected end of document");
+		// Unknown fields are ignored, and a selection that doesn't fit is reset
+		let es = load(r#"{"format": "equation", "version": 1, "extra": 1, "lines": [{"expr": "x", "selection": {"path": [3], "first": 0, "last": 0}, "colour": "red"}]}"#).unwrap();
+		assert_eq!(es.lines[0].sel, Selection::new());
+		assert!(!is_document("a = b # {"));
+	}
+}
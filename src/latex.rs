@@ -0,0 +1,683 @@
+//!
+//! LaTeX export and import
+//!
+
+use crate::expression::{Expression, SubExpression, Op, greek_letter, split_ident};
+use crate::manip::make_node;
+use std::fmt::Write;
+
+/// Wrapper to render an expression as LaTeX (using `Display`)
+pub struct Latex<'a>(pub &'a Expression);
+
+impl std::fmt::Display for Latex<'_>
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write_expr(f, self.0, false)
+	}
+}
+
+/// Export a set of lines as an `align` environment (aligned on the first `=` of each line)
+pub fn export(lines: &[crate::Line]) -> String
+{
+	let mut rv = String::new();
+	rv.push_str("\\begin{align}\n");
+	for (i,l) in lines.iter().enumerate()
+	{
+		let mut line = String::new();
+		if let Some(ref e) = l.expr {
+			let _ = write_expr(&mut line, e, true);
+		}
+		if !line.contains('&') {
+			line.insert(0, '&');
+		}
+		rv.push_str(&line);
+		if l.comment.trim() != "" {
+			let _ = write!(rv, " && \\text{{{}}}", escape_text(l.comment.trim()));
+		}
+		if i + 1 < lines.len() {
+			rv.push_str(" \\\\");
+		}
+		rv.push('\n');
+	}
+	rv.push_str("\\end{align}\n");
+	rv
+}
+
+/// Escape characters that are special in LaTeX text mode
+fn escape_text(s: &str) -> String
+{
+	let mut rv = String::new();
+	for c in s.chars()
+	{
+		match c
+		{
+		'\\' => rv.push_str("\\textbackslash{}"),
+		'~' => rv.push_str("\\textasciitilde{}"),
+		'^' => rv.push_str("\\textasciicircum{}"),
+		'{' | '}' | '$' | '&' | '#' | '_' | '%' => { rv.push('\\'); rv.push(c); },
+		_ => rv.push(c),
+		}
+	}
+	rv
+}
+
+/// Render an identifier, converting `_` into subscripts and trailing `'` into primes
+fn write_ident(f: &mut impl Write, name: &str) -> std::fmt::Result
+{
+	let (stem, primes, sub) = split_ident(name);
+	if stem.len() == 1 {
+		f.write_str(stem)?;
+	}
+	else if greek_letter(stem).is_some() {
+		write!(f, "\\{}", stem)?;
+	}
+	else {
+		write!(f, "\\mathrm{{{}}}", stem)?;
+	}
+	if let Some(sub) = sub {
+		f.write_str("_{")?;
+		write_ident(f, sub)?;
+		f.write_str("}")?;
+	}
+	f.write_str(&"'".repeat(primes))
+}
+
+/// Render an expression, with `align` set the first `=` becomes an alignment point
+fn write_expr(f: &mut impl Write, e: &Expression, align: bool) -> std::fmt::Result
+{
+	let mut r = LatexRenderer { f: f, align: align, res: Ok( () ) };
+	crate::render::walk(&mut r, e, None);
+	r.res
+}
+
+struct LatexRenderer<'a, W: Write>
+{
+	f: &'a mut W,
+	/// The next `=` is an alignment point
+	align: bool,
+	/// First error from the writer
+	res: std::fmt::Result,
+}
+impl<W: Write> LatexRenderer<'_, W>
+{
+	fn put(&mut self, s: &str) {
+		if self.res.is_ok() {
+			self.res = self.f.write_str(s);
+		}
+	}
+}
+impl<W: Write> crate::render::Renderer for LatexRenderer<'_, W>
+{
+	fn features(&self) -> crate::render::Features {
+		crate::render::Features { fractions: true, grouped_exponents: true }
+	}
+	fn operator(&mut self, op: Op, inverse: bool, next: &Expression) {
+		let s = match op
+			{
+			Op::Equality if self.align => { self.align = false; " &= " },
+			Op::Equality => " = ",
+			Op::AddSub => if inverse { " - " } else { " + " },
+			// An explicit `\cdot` is only needed where juxtaposition would be ambiguous (before a number)
+			Op::MulDiv =>
+				match next
+				{
+				Expression::Literal(_) | Expression::Negative(_) => " \\cdot ",
+				_ => " ",
+				},
+			Op::ExpRoot => "^",
+			};
+		self.put(s);
+	}
+	fn negate(&mut self) {
+		self.put("-");
+	}
+	fn literal(&mut self, v: f32) {
+		self.put(&format!("{}", v));
+	}
+	fn variable(&mut self, name: &str) {
+		if self.res.is_ok() {
+			self.res = write_ident(self.f, name);
+		}
+	}
+	fn open_paren(&mut self) {
+		self.put("\\left(");
+	}
+	fn close_paren(&mut self) {
+		self.put("\\right)");
+	}
+
+	fn power_exponent(&mut self, _exp: &Expression) {
+		self.put("^{");
+	}
+	fn power_end(&mut self) {
+		self.put("}");
+	}
+	fn root_degree(&self, exp: &Expression) -> Option<u32> {
+		crate::expression::root_degree(exp)
+	}
+	fn root_start(&mut self, degree: u32, _radicand: &Expression) {
+		if degree == 2 {
+			self.put("\\sqrt{");
+		}
+		else {
+			self.put(&format!("\\sqrt[{}]{{", degree));
+		}
+	}
+	fn root_end(&mut self, _degree: u32, _radicand: &Expression) {
+		self.put("}");
+	}
+	fn fraction_start(&mut self) {
+		self.put("\\frac{");
+	}
+	fn fraction_middle(&mut self) {
+		self.put("}{");
+	}
+	fn fraction_end(&mut self) {
+		self.put("}");
+	}
+}
+
+/// Error from parsing LaTeX, with the byte offset where it occurred
+#[derive(Debug)]
+pub struct TexError
+{
+	pub pos: usize,
+	pub message: String,
+}
+impl std::fmt::Display for TexError
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{} (at character {})", self.message, self.pos + 1)
+	}
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+enum Token<'a> {
+	Eof,
+	Whitespace,
+	/// `\name` (without the backslash)
+	Command(&'a str),
+	/// Single-letter variable
+	Letter(&'a str),
+	Number(&'a str),
+	Symbol(char),
+}
+::plex::lexer! {
+	fn lex_tex_token(text: 'a) -> Token<'a>;
+
+	// Alignment points are ignored
+	r#"[ \t\r\n~&]+"# => Token::Whitespace,
+	// Spacing commands
+	r#"\\[,;:! ]"# => Token::Whitespace,
+	r#"\\[a-zA-Z]+"# => Token::Command(&text[1..]),
+	r#"[a-zA-Z]"# => Token::Letter(text),
+	r#"[0-9]+(\.[0-9]*)?"# => Token::Number(text),
+	r"." => Token::Symbol(text.chars().next().unwrap()),
+}
+
+struct TexParser<'a>
+{
+	base: &'a str,
+	remaining: &'a str,
+	cur: Token<'a>,
+	/// Offset of the current token
+	pos: usize,
+}
+impl<'a> TexParser<'a>
+{
+	fn new(s: &'a str) -> TexParser<'a> {
+		let mut rv = TexParser { base: s, remaining: s, cur: Token::Eof, pos: 0 };
+		rv.consume();
+		rv
+	}
+	fn consume(&mut self) -> Token<'a> {
+		loop
+		{
+			let pos = self.base.len() - self.remaining.len();
+			let t = match lex_tex_token(self.remaining)
+				{
+				Some( (t, rem) ) => { self.remaining = rem; t },
+				None => Token::Eof,
+				};
+			if t == Token::Whitespace {
+				continue ;
+			}
+			self.pos = pos;
+			return std::mem::replace(&mut self.cur, t);
+		}
+	}
+	fn consume_if(&mut self, t: Token<'_>) -> bool {
+		if self.cur == t {
+			self.consume();
+			true
+		}
+		else {
+			false
+		}
+	}
+	fn error(&self, message: String) -> TexError {
+		TexError { pos: self.pos, message: message }
+	}
+	fn unexpected(&self) -> TexError {
+		self.error(match self.cur
+			{
+			Token::Eof => format!("Unexpected end of input"),
+			Token::Command(c) => format!("Unexpected `\\{}`", c),
+			Token::Letter(t) | Token::Number(t) => format!("Unexpected `{}`", t),
+			Token::Symbol(c) => format!("Unexpected `{}`", c),
+			Token::Whitespace => unreachable!(),
+			})
+	}
+	fn expect(&mut self, c: char) -> Result<(), TexError> {
+		if self.consume_if(Token::Symbol(c)) { Ok( () ) } else { Err(self.unexpected()) }
+	}
+
+	fn parse_equation(&mut self) -> Result<Expression, TexError>
+	{
+		let mut values = vec![ SubExpression { inverse: false, val: self.parse_sum()? } ];
+		while self.consume_if(Token::Symbol('=')) {
+			values.push(SubExpression { inverse: false, val: self.parse_sum()? });
+		}
+		Ok(make_node(Op::Equality, values))
+	}
+	fn parse_sum(&mut self) -> Result<Expression, TexError>
+	{
+		let mut values = vec![];
+		let mut inverse = self.consume_if(Token::Symbol('-'));
+		if !inverse {
+			self.consume_if(Token::Symbol('+'));
+		}
+		loop
+		{
+			values.push(SubExpression { inverse: inverse, val: self.parse_product()? });
+			inverse = if self.consume_if(Token::Symbol('-')) {
+					true
+				}
+				else if self.consume_if(Token::Symbol('+')) {
+					false
+				}
+				else {
+					break;
+				};
+		}
+		Ok(make_node(Op::AddSub, values))
+	}
+	/// Does the current token start a value? (for implicit multiplication)
+	fn starts_value(&self) -> bool {
+		match self.cur
+		{
+		Token::Letter(_) | Token::Number(_) | Token::Symbol('(') | Token::Symbol('{') => true,
+		Token::Command(c) => c != "cdot" && c != "times" && c != "right",
+		_ => false,
+		}
+	}
+	fn parse_product(&mut self) -> Result<Expression, TexError>
+	{
+		let mut values = vec![];
+		let mut inverse = false;
+		loop
+		{
+			let (v, is_frac) = self.parse_power()?;
+			match v
+			{
+			// A `\frac` is merged into the surrounding product (`\frac{1}{2} j` is `1/2*j`)
+			Expression::SubNode(sn) if is_frac && !inverse => values.extend(sn.values),
+			v => values.push(SubExpression { inverse: inverse, val: v }),
+			}
+			inverse = if self.consume_if(Token::Command("cdot")) || self.consume_if(Token::Command("times")) || self.consume_if(Token::Symbol('*')) {
+					false
+				}
+				else if self.consume_if(Token::Symbol('/')) {
+					true
+				}
+				else if self.starts_value() {
+					false
+				}
+				else {
+					break;
+				};
+		}
+		Ok(make_node(Op::MulDiv, values))
+	}
+	/// Returns the value, and whether it was a bare `\frac`
+	fn parse_power(&mut self) -> Result<(Expression, bool), TexError>
+	{
+		if self.consume_if(Token::Symbol('-')) {
+			let (v, _) = self.parse_power()?;
+			return Ok( (Expression::Negative(Box::new(v)), false) );
+		}
+		let (base, is_frac) = self.parse_atom()?;
+		if self.consume_if(Token::Symbol('^'))
+		{
+			let exp = self.parse_group()?;
+			Ok( (make_node(Op::ExpRoot, vec![
+				SubExpression { inverse: false, val: base },
+				SubExpression { inverse: false, val: exp },
+				]), false) )
+		}
+		else
+		{
+			Ok( (base, is_frac) )
+		}
+	}
+	/// A braced group, or a single value
+	fn parse_group(&mut self) -> Result<Expression, TexError>
+	{
+		if self.consume_if(Token::Symbol('{')) {
+			let rv = self.parse_sum()?;
+			self.expect('}')?;
+			Ok(rv)
+		}
+		else {
+			Ok(self.parse_atom()?.0)
+		}
+	}
+	/// Argument to a command (`\frac`, `\sqrt`), where an unbraced number is only a single digit (e.g. `\frac12`)
+	fn parse_arg(&mut self) -> Result<Expression, TexError>
+	{
+		if let Token::Number(t) = self.cur {
+			if t.len() > 1 {
+				// Leave the rest of the digits as the current token
+				self.cur = Token::Number(&t[1..]);
+				self.pos += 1;
+				return match t[..1].parse()
+					{
+					Ok(v) => Ok(Expression::Literal(v)),
+					Err(_) => Err(self.error(format!("Bad number `{}`", &t[..1]))),
+					};
+			}
+		}
+		self.parse_group()
+	}
+	fn parse_atom(&mut self) -> Result<(Expression, bool), TexError>
+	{
+		Ok(match self.cur
+			{
+			Token::Number(t) => {
+				self.consume();
+				match t.parse()
+				{
+				Ok(v) => (Expression::Literal(v), false),
+				Err(_) => return Err(self.error(format!("Bad number `{}`", t))),
+				}
+				},
+			Token::Letter(t) => {
+				self.consume();
+				(Expression::Variable(self.parse_ident_suffix(t.to_owned())?), false)
+				},
+			Token::Symbol('(') | Token::Symbol('{') => {
+				let close = if self.consume() == Token::Symbol('(') { ')' } else { '}' };
+				let rv = self.parse_sum()?;
+				self.expect(close)?;
+				(rv, false)
+				},
+			Token::Command("left") => {
+				self.consume();
+				let close = match self.consume()
+					{
+					Token::Symbol('(') => ')',
+					Token::Symbol('[') => ']',
+					_ => return Err(self.error(format!("Expected `(` or `[` after `\\left`"))),
+					};
+				let rv = self.parse_sum()?;
+				if !self.consume_if(Token::Command("right")) {
+					return Err(self.unexpected());
+				}
+				self.expect(close)?;
+				(rv, false)
+				},
+			Token::Command("frac") | Token::Command("dfrac") | Token::Command("tfrac") => {
+				self.consume();
+				let num = self.parse_arg()?;
+				let den = self.parse_arg()?;
+				(make_node(Op::MulDiv, vec![
+					SubExpression { inverse: false, val: num },
+					SubExpression { inverse: true, val: den },
+					]), true)
+				},
+			// `\sqrt[n]{x}` is `x^(1/n)`
+			Token::Command("sqrt") => {
+				self.consume();
+				let n = if self.consume_if(Token::Symbol('[')) {
+						let n = self.parse_sum()?;
+						self.expect(']')?;
+						n
+					}
+					else {
+						Expression::Literal(2.)
+					};
+				let base = self.parse_arg()?;
+				(make_node(Op::ExpRoot, vec![
+					SubExpression { inverse: false, val: base },
+					SubExpression { inverse: false, val: make_node(Op::MulDiv, vec![
+						SubExpression { inverse: false, val: Expression::Literal(1.) },
+						SubExpression { inverse: true, val: n },
+						]) },
+					]), false)
+				},
+			// Multi-letter identifiers
+			Token::Command("mathrm") | Token::Command("mathit") => {
+				self.consume();
+				self.expect('{')?;
+				let name = self.parse_text('}')?;
+				(Expression::Variable(self.parse_ident_suffix(name)?), false)
+				},
+			Token::Command(c) if greek_letter(c).is_some() => {
+				self.consume();
+				(Expression::Variable(self.parse_ident_suffix(c.to_owned())?), false)
+				},
+			Token::Command(c) => return Err(self.error(format!("Unsupported command `\\{}`", c))),
+			_ => return Err(self.unexpected()),
+			})
+	}
+	/// Primes and subscripts following an identifier
+	fn parse_ident_suffix(&mut self, mut name: String) -> Result<String, TexError>
+	{
+		loop
+		{
+			if self.consume_if(Token::Symbol('\'')) {
+				name.push('\'');
+			}
+			else if self.consume_if(Token::Command("prime")) {
+				name.push('\'');
+			}
+			else if self.consume_if(Token::Symbol('_')) {
+				name.push('_');
+				if self.consume_if(Token::Symbol('{')) {
+					name += &self.parse_text('}')?;
+				}
+				else {
+					match self.consume()
+					{
+					Token::Letter(t) | Token::Number(t) => name.push_str(t),
+					_ => return Err(self.error(format!("Expected a subscript"))),
+					}
+				}
+			}
+			else {
+				return Ok(name);
+			}
+		}
+	}
+	/// Identifier text (e.g. in a subscript or `\mathrm`) up to the closing character
+	fn parse_text(&mut self, close: char) -> Result<String, TexError>
+	{
+		let mut rv = String::new();
+		loop
+		{
+			match self.cur
+			{
+			Token::Symbol(c) if c == close => {
+				self.consume();
+				return Ok(rv);
+				},
+			Token::Letter(t) | Token::Number(t) => rv.push_str(t),
+			Token::Symbol('\'') => rv.push('\''),
+			Token::Symbol('_') => rv.push('_'),
+			Token::Symbol('{') => {
+				self.consume();
+				rv += &self.parse_text('}')?;
+				continue ;
+				},
+			Token::Command("mathrm") | Token::Command("mathit") => {},
+			Token::Command(c) if greek_letter(c).is_some() => rv.push_str(c),
+			_ => return Err(self.unexpected()),
+			}
+			self.consume();
+		}
+	}
+}
+
+/// Parse a LaTeX equation or expression (e.g. `v = v_0 + \frac{1}{2} a t^2`)
+pub fn parse(s: &str) -> Result<Expression, TexError>
+{
+	let mut p = TexParser::new(s);
+	if p.cur == Token::Eof {
+		return Err(p.error(format!("Empty expression")));
+	}
+	let rv = p.parse_equation()?;
+	if p.cur != Token::Eof {
+		return Err(p.unexpected());
+	}
+	Ok(rv)
+}
+
+/// Parse a LaTeX document (e.g. the output of `export`) into expressions and their `\text{}` comments
+///
+/// Equations are separated by `\\`, and environment markers and `%` comments are ignored. A `\text{}` on its own is a comment-only line.
+pub fn parse_document(s: &str) -> Result<Vec<(Option<Expression>, String)>, TexError>
+{
+	// Blank out a range (keeping offsets intact for error positions)
+	fn blank(s: &mut String, start: usize, end: usize) {
+		s.replace_range(start .. end, &" ".repeat(end - start));
+	}
+	fn find_close(s: &str, open: usize) -> Option<usize> {
+		let mut depth = 0;
+		for (i,c) in s[open..].char_indices()
+		{
+			match c
+			{
+			'{' => depth += 1,
+			'}' => { depth -= 1; if depth == 0 { return Some(open + i); } },
+			_ => {},
+			}
+		}
+		None
+	}
+	let mut rv = Vec::new();
+	let mut offset = 0;
+	for chunk in s.split("\\\\")
+	{
+		let base = offset;
+		offset += chunk.len() + 2;
+		let mut text = chunk.to_owned();
+		// `%` comments (unless escaped)
+		let mut i = 0;
+		while let Some(p) = text[i..].find('%')
+		{
+			let p = i + p;
+			if p > 0 && text.as_bytes()[p-1] == b'\\' {
+				i = p + 1;
+				continue ;
+			}
+			let end = text[p..].find('\n').map(|e| p + e).unwrap_or(text.len());
+			blank(&mut text, p, end);
+			i = end;
+		}
+		for marker in ["\\begin{", "\\end{"].iter()
+		{
+			while let Some(p) = text.find(marker)
+			{
+				let end = find_close(&text, p + marker.len() - 1).map(|e| e + 1).unwrap_or(text.len());
+				blank(&mut text, p, end);
+			}
+		}
+		let mut comment = String::new();
+		if let Some(p) = text.find("\\text{")
+		{
+			let open = p + "\\text".len();
+			let end = find_close(&text, open).ok_or(TexError { pos: base + p, message: format!("Unterminated `\\text`") })?;
+			comment = unescape_text(&text[open+1 .. end]);
+			blank(&mut text, p, end + 1);
+		}
+		// (alignment markers are ignored by the parser)
+		if text.trim_matches(|c: char| c.is_whitespace() || c == '&') == "" {
+			// Comment-only line
+			if comment != "" {
+				rv.push( (None, comment) );
+			}
+			continue ;
+		}
+		let e = parse(&text).map_err(|e| TexError { pos: base + e.pos, message: e.message })?;
+		rv.push( (Some(e), comment) );
+	}
+	Ok(rv)
+}
+/// Reverse of `escape_text`
+fn unescape_text(s: &str) -> String
+{
+	s.replace("\\textbackslash{}", "\\")
+		.replace("\\textasciitilde{}", "~")
+		.replace("\\textasciicircum{}", "^")
+		.replace("\\{", "{").replace("\\}", "}").replace("\\$", "$").replace("\\&", "&")
+		.replace("\\#", "#").replace("\\_", "_").replace("\\%", "%")
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn tex(s: &str) -> String {
+		match parse(s)
+		{
+		Ok(e) => e.to_string(),
+		Err(e) => panic!("`{}` failed at {} - {}", s, e.pos, e.message),
+		}
+	}
+
+	#[test]
+	fn commands() {
+		assert_eq!(tex(r"v = v_0 + \frac{1}{2} a t^2"), "v=v_0+1/2*a*t^2");
+		assert_eq!(tex(r"\frac{a+b}{c}"), "(a+b)/c");
+		assert_eq!(tex(r"\sqrt{x} + \sqrt[3]{y}"), "x^(1/2)+y^(1/3)");
+		assert_eq!(tex(r"\alpha_{max} \cdot \mathrm{rate}'"), "alpha_max*rate'");
+		assert_eq!(tex(r"\left(a - b\right)^{2}"), "(a-b)^2");
+	}
+	#[test]
+	fn single_digit_arguments() {
+		assert_eq!(tex(r"\frac12"), "1/2");
+		assert_eq!(tex(r"\frac12 x"), "1/2*x");
+		assert_eq!(tex(r"\frac1{x}"), "1/x");
+		assert_eq!(tex(r"\frac{10}2"), "10/2");
+		assert_eq!(tex(r"\sqrt2"), "2^(1/2)");
+		assert_eq!(tex(r"\sqrt25"), "2^(1/2)*5");
+		assert_eq!(tex(r"\frac ab"), "a/b");
+	}
+	#[test]
+	fn errors() {
+		let e = parse(r"a + \foo").unwrap_err();
+		assert_eq!(e.pos, 4);
+		assert!(e.message.contains("foo"));
+		assert!(parse(r"\frac1").is_err());
+		assert!(parse("").is_err());
+		let e = parse_document("a = b \\\\\nc = {d").unwrap_err();
+		assert_eq!(e.pos, "a = b \\\\\nc = {d".len());
+	}
+	#[test]
+	fn document_roundtrip() {
+		let lines = vec![
+			crate::Line::from_str("v = a*t # speed_{max} & 100%").unwrap(),
+			crate::Line::from_str("#just a comment").unwrap(),
+			crate::Line::from_str("(-x)^2 = x^2").unwrap(),
+			];
+		let doc = export(&lines);
+		let parsed = parse_document(&doc).unwrap();
+		assert_eq!(parsed.len(), 3);
+		for (l, (e, comment)) in lines.iter().zip(parsed.iter())
+		{
+			assert_eq!(l.expr.as_ref(), e.as_ref());
+			assert_eq!(l.comment.trim(), comment);
+		}
+	}
+}
@@ -0,0 +1,242 @@
+//!
+//! Two-dimensional layout of expressions (stacked fractions, raised exponents) for the terminal
+//!
+
+use crate::expression::{Expression, ExprNode, Op};
+use crate::ui_helpers::Selection;
+
+#[derive(Debug,Copy,Clone)]
+pub struct Cell
+{
+	pub ch: char,
+	/// Part of the selected sub-expression
+	pub hilight: bool,
+}
+
+/// Rectangular grid of characters, with the row that lines up with surrounding text
+#[derive(Debug,Clone)]
+pub struct Block
+{
+	/// All rows have the same width
+	pub rows: Vec<Vec<Cell>>,
+	pub baseline: usize,
+}
+impl Block
+{
+	fn blank(w: usize, h: usize, hilight: bool) -> Block {
+		Block {
+			rows: vec![ vec![Cell { ch: ' ', hilight: hilight }; w]; h ],
+			baseline: 0,
+			}
+	}
+	fn text(s: &str, hilight: bool) -> Block {
+		Block {
+			rows: vec![ s.chars().map(|c| Cell { ch: c, hilight: hilight }).collect() ],
+			baseline: 0,
+			}
+	}
+	pub fn width(&self) -> usize {
+		self.rows.get(0).map(|r| r.len()).unwrap_or(0)
+	}
+	pub fn height(&self) -> usize {
+		self.rows.len()
+	}
+	/// Copy another block in with its top-left at the given position
+	fn blit(&mut self, o: &Block, y: usize, x: usize) {
+		for (r,row) in o.rows.iter().enumerate()
+		{
+			self.rows[y + r][x .. x + row.len()].copy_from_slice(row);
+		}
+	}
+
+	/// Place blocks side by side, lining up their baselines
+	fn hjoin(parts: Vec<Block>, hilight: bool) -> Block {
+		let above = parts.iter().map(|p| p.baseline).max().unwrap_or(0);
+		let below = parts.iter().map(|p| p.height() - p.baseline).max().unwrap_or(1);
+		let mut rv = Block::blank(parts.iter().map(|p| p.width()).sum(), above + below, hilight);
+		rv.baseline = above;
+		let mut x = 0;
+		for p in parts.iter()
+		{
+			rv.blit(p, above - p.baseline, x);
+			x += p.width();
+		}
+		rv
+	}
+	/// Stack a numerator over a denominator, with the bar on the baseline
+	fn fraction(num: Block, den: Block, bar_hilight: bool, hilight: bool) -> Block {
+		let w = std::cmp::max(num.width(), den.width()) + 2;
+		let mut rv = Block::blank(w, num.height() + 1 + den.height(), hilight);
+		rv.baseline = num.height();
+		rv.blit(&num, 0, (w - num.width()) / 2);
+		rv.blit(&Block::text(&"-".repeat(w), bar_hilight), num.height(), 0);
+		rv.blit(&den, num.height() + 1, (w - den.width()) / 2);
+		rv
+	}
+	/// Attach an exponent to the top-right of a base
+	fn raise(base: Block, exp: Block, hilight: bool) -> Block {
+		let mut rv = Block::blank(base.width() + exp.width(), exp.height() + base.height(), hilight);
+		rv.baseline = exp.height() + base.baseline;
+		rv.blit(&base, exp.height(), 0);
+		rv.blit(&exp, 0, base.width());
+		rv
+	}
+	/// Surround with brackets as tall as the contents
+	fn parens(inner: Block, hilight: bool) -> Block {
+		let h = inner.height();
+		let side = |top: char, mid: char, bottom: char| {
+			let mut rv = Block::blank(1, h, hilight);
+			for (r,row) in rv.rows.iter_mut().enumerate()
+			{
+				row[0].ch = if h == 1 { if top == '/' { '(' } else { ')' } } else if r == 0 { top } else if r == h-1 { bottom } else { mid };
+			}
+			rv.baseline = inner.baseline;
+			rv
+			};
+		let (l, r) = (side('/', '|', '\\'), side('\\', '|', '/'));
+		Block::hjoin(vec![l, inner, r], hilight)
+	}
+}
+
+/// Lay out an expression, flagging the cells of the selected sub-expression(s)
+pub fn layout(e: &Expression, sel: Option<&Selection>) -> Block
+{
+	match sel
+	{
+	Some(sel) => lay_expr(e, sel, 0, false),
+	None => lay_expr(e, &Selection::new(), !0, false),
+	}
+}
+
+/// `path_pos` is the depth within the selection path (`!0` when off the path)
+fn lay_expr(e: &Expression, sel: &Selection, path_pos: usize, hilight: bool) -> Block
+{
+	match e
+	{
+	Expression::Negative(ref v) => {
+		let inner_hilight = hilight || path_pos == sel.path.len();
+		let inner = lay_expr(v, sel, if path_pos < sel.path.len() { path_pos + 1 } else { !0 }, inner_hilight);
+		let inner = match **v
+			{
+			Expression::Literal(_) | Expression::Variable(_) => inner,
+			_ => Block::parens(inner, inner_hilight),
+			};
+		Block::hjoin(vec![Block::text("-", hilight), inner], hilight)
+		},
+	Expression::Literal(v) => Block::text(&format!("{}", v), hilight),
+	Expression::Variable(ref n) => Block::text(n, hilight),
+	Expression::SubNode(ref sn) => lay_node(sn, sel, path_pos, hilight),
+	}
+}
+fn lay_node(sn: &ExprNode, sel: &Selection, path_pos: usize, hilight: bool) -> Block
+{
+	let in_range = |i: usize| hilight || (path_pos == sel.path.len() && sel.first <= i && i <= sel.last);
+	let child = |i: usize, parens: bool| {
+		let pos = if path_pos < sel.path.len() && sel.path[path_pos] == i { path_pos + 1 } else { !0 };
+		let b = lay_expr(&sn.values[i].val, sel, pos, in_range(i));
+		if parens { Block::parens(b, in_range(i)) } else { b }
+		};
+	// Factors joined with `*` (operators are highlighted when both neighbours are)
+	let product = |idxs: &[usize]| {
+		let mut parts = Vec::new();
+		for (j,&i) in idxs.iter().enumerate()
+		{
+			if j > 0 {
+				parts.push(Block::text("*", in_range(idxs[j-1]) && in_range(i)));
+			}
+			parts.push(child(i, idxs.len() > 1 && sn.values[i].val.needs_parens(Op::MulDiv)));
+		}
+		Block::hjoin(parts, hilight)
+		};
+
+	match sn.operation
+	{
+	Op::Equality | Op::AddSub => {
+		let mut parts = Vec::new();
+		for (i,v) in sn.values.iter().enumerate()
+		{
+			let op = match sn.operation
+				{
+				Op::Equality => " = ",
+				_ if v.inverse => if i == 0 { "-" } else { " - " },
+				_ => " + ",
+				};
+			if i > 0 || v.inverse {
+				parts.push(Block::text(op, i > 0 && in_range(i-1) && in_range(i)));
+			}
+			parts.push(child(i, v.val.needs_parens(sn.operation)));
+		}
+		Block::hjoin(parts, hilight)
+		},
+	// Divided entries are stacked under the multiplied ones
+	Op::MulDiv => {
+		let num: Vec<usize> = (0 .. sn.values.len()).filter(|&i| !sn.values[i].inverse).collect();
+		let den: Vec<usize> = (0 .. sn.values.len()).filter(|&i| sn.values[i].inverse).collect();
+		if den.is_empty() {
+			return product(&num);
+		}
+		let num = if num.is_empty() { Block::text("1", hilight) } else { product(&num) };
+		let bar_hilight = (0 .. sn.values.len()).all(|i| in_range(i));
+		Block::fraction(num, product(&den), bar_hilight, hilight)
+		},
+	// Right-associative, so each exponent is raised off the previous one
+	Op::ExpRoot => {
+		let n = sn.values.len();
+		let mut exp = child(n - 1, false);
+		for i in (1 .. n-1).rev()
+		{
+			exp = Block::raise(child(i, false), exp, hilight);
+		}
+		Block::raise(child(0, sn.values[0].val.needs_parens_as_base()), exp, hilight)
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	/// Rows of the layout, with highlighted cells in upper case (`^` when not a letter)
+	fn draw(s: &str, sel: Option<&str>) -> Vec<String> {
+		let e: Expression = s.parse().unwrap();
+		let sel: Option<Selection> = sel.map(|s| s.parse().unwrap());
+		let b = layout(&e, sel.as_ref());
+		assert!(b.rows.iter().all(|r| r.len() == b.width()));
+		b.rows.iter().map(|r| r.iter().map(|c| match c
+			{
+			Cell { ch, hilight: false } => ch.to_string(),
+			Cell { ch, hilight: true } if ch.is_alphabetic() => ch.to_uppercase().to_string(),
+			Cell { .. } => "^".to_owned(),
+			}).collect::<String>().trim_end().to_owned()).collect()
+	}
+
+	#[test]
+	fn fractions_and_powers() {
+		assert_eq!(draw("j*t^3/6", None), vec![
+			"    3",
+			" j*t",
+			"------",
+			"  6",
+			]);
+		assert_eq!(draw("a = b + c", None), vec!["a = b + c"]);
+		assert_eq!(draw("x^2^n", None), vec!["  n", " 2", "x"]);
+		assert_eq!(draw("-(a + b)", None), vec!["-(a + b)"]);
+		assert_eq!(draw("(-x)^2", None), vec!["    2", "(-x)"]);
+	}
+	#[test]
+	fn tall_brackets() {
+		assert_eq!(draw("(a/b + 1)*c", None), vec![
+			"/ a     \\",
+			"|--- + 1|*c",
+			"\\ b     /",
+			]);
+	}
+	#[test]
+	fn selection() {
+		assert_eq!(draw("a = b + c", Some("[1] 0-0")), vec!["a = B + c"]);
+		assert_eq!(draw("a = b + c", Some("[1] 0-1")), vec!["a = B^^^C"]);
+		assert_eq!(draw("a/b", Some("[] 1-1")), vec![" a", "---", " B"]);
+		assert_eq!(draw("a/b", Some("[] 0-1")), vec![" A", "^^^", " B"]);
+	}
+}
@@ -0,0 +1,200 @@
+//!
+//! Solving systems of linear equations
+//!
+
+use crate::expression::{Expression, SubExpression, Op};
+use crate::manip::make_node;
+use crate::mpoly::{self, MPoly};
+
+#[derive(Debug)]
+pub enum SolveError
+{
+	/// An input line isn't an equation with two sides (index into the equation list)
+	NotEquation(usize),
+	/// An equation isn't linear in the unknowns (or has non-polynomial coefficients)
+	NotLinear(usize),
+	/// There are fewer equations than unknowns, leaving these unknowns free
+	Underdetermined(Vec<String>),
+	/// The coefficient matrix has no inverse, leaving these unknowns free
+	Singular(Vec<String>),
+	/// The equations contradict each other
+	Inconsistent,
+	/// Coefficients became too large to handle exactly
+	Overflow,
+}
+impl std::fmt::Display for SolveError
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self
+		{
+		SolveError::NotEquation(i) => write!(f, "Equation {} isn't of the form `a = b`", i + 1),
+		SolveError::NotLinear(i) => write!(f, "Equation {} isn't linear in the unknowns", i + 1),
+		SolveError::Underdetermined(free) => write!(f, "Underdetermined system, {} not fixed", free.join(", ")),
+		SolveError::Singular(free) => write!(f, "Singular system, {} not fixed", free.join(", ")),
+		SolveError::Inconsistent => write!(f, "Equations are inconsistent"),
+		SolveError::Overflow => write!(f, "Coefficients are too large to solve exactly"),
+		}
+	}
+}
+
+/// A fraction of two polynomials
+#[derive(Clone)]
+struct Fraction
+{
+	num: MPoly,
+	den: MPoly,
+}
+impl Fraction
+{
+	/// Cancel common factors, and scale so the denominator has coprime integer coefficients (one if constant)
+	fn reduce(self) -> Result<Fraction, SolveError>
+	{
+		let g = mpoly::gcd(&self.num, &self.den).ok_or(SolveError::Overflow)?;
+		let num = self.num.div_exact(&g).ok_or(SolveError::Overflow)?;
+		let den = self.den.div_exact(&g).ok_or(SolveError::Overflow)?;
+		// The GCD with zero is the normalised form
+		let norm_den = mpoly::gcd(&den, &MPoly::zero()).ok_or(SolveError::Overflow)?;
+		let scale = den.div_exact(&norm_den).and_then(|c| c.as_constant()).ok_or(SolveError::Overflow)?;
+		let num = num.checked_scale(scale.recip().unwrap()).ok_or(SolveError::Overflow)?;
+		Ok(Fraction { num: num, den: norm_den })
+	}
+	fn to_expr(&self) -> Expression
+	{
+		if self.den.as_constant().is_some() {
+			self.num.to_expr()
+		}
+		else {
+			make_node(Op::MulDiv, vec![
+				SubExpression { inverse: false, val: self.num.to_expr() },
+				SubExpression { inverse: true, val: self.den.to_expr() },
+				])
+		}
+	}
+}
+
+/// `a*b - c*d` (`None` on overflow)
+fn checked_det(a: &MPoly, b: &MPoly, c: &MPoly, d: &MPoly) -> Option<MPoly>
+{
+	a.checked_mul(b)?.checked_sub( &c.checked_mul(d)? )
+}
+
+/// Solve a set of equations for the given unknowns using (fraction-free) Gaussian elimination
+///
+/// Coefficients can be polynomials in any other variables, the results are `unknown = value` equations in the same
+/// order as `unknowns`.
+pub fn solve(equations: &[&Expression], unknowns: &[String]) -> Result<Vec<Expression>, SolveError>
+{
+	let n = unknowns.len();
+	// Build the augmented matrix `[A | b]` from `lhs - rhs = 0`
+	let mut rows: Vec<Vec<MPoly>> = Vec::new();
+	for (i,e) in equations.iter().enumerate()
+	{
+		let diff = match e
+			{
+			Expression::SubNode(ref sn) if sn.operation == Op::Equality && sn.values.len() == 2 => make_node(Op::AddSub, vec![
+				SubExpression { inverse: false, val: sn.values[0].val.clone() },
+				SubExpression { inverse: true, val: sn.values[1].val.clone() },
+				]),
+			_ => return Err(SolveError::NotEquation(i)),
+			};
+		let (mut coeffs, constant) = MPoly::from_expr(&diff)
+			.and_then(|p| p.linear_coeffs(unknowns))
+			.ok_or(SolveError::NotLinear(i))?;
+		coeffs.push(constant.checked_neg().ok_or(SolveError::Overflow)?);
+		rows.push(coeffs);
+	}
+
+	// Forward elimination (Bareiss), dividing by the previous pivot to keep the entries small
+	let mut prev = MPoly::constant(crate::rational::Rational::one());
+	let mut pivots = Vec::new();
+	for col in 0 .. n
+	{
+		let r = pivots.len();
+		let p = match (r .. rows.len()).find(|&i| !rows[i][col].is_zero())
+			{
+			Some(p) => p,
+			None => continue,
+			};
+		rows.swap(r, p);
+		for i in r+1 .. rows.len()
+		{
+			for j in col+1 ..= n
+			{
+				let v = checked_det(&rows[r][col], &rows[i][j], &rows[i][col], &rows[r][j]).ok_or(SolveError::Overflow)?;
+				rows[i][j] = v.div_exact(&prev).unwrap_or(v);
+			}
+			rows[i][col] = MPoly::zero();
+		}
+		prev = rows[r][col].clone();
+		pivots.push(col);
+	}
+
+	// Any remaining rows are `0 = b`
+	if rows[pivots.len() ..].iter().any(|r| !r[n].is_zero()) {
+		return Err(SolveError::Inconsistent);
+	}
+	if pivots.len() < n
+	{
+		let free = (0 .. n).filter(|c| !pivots.contains(c)).map(|c| unknowns[c].clone()).collect();
+		return Err(if equations.len() < n { SolveError::Underdetermined(free) } else { SolveError::Singular(free) });
+	}
+
+	// Back substitution
+	let mut values: Vec<Option<Fraction>> = vec![None; n];
+	for (r,&col) in pivots.iter().enumerate().rev()
+	{
+		let mut acc = Fraction { num: rows[r][n].clone(), den: MPoly::constant(crate::rational::Rational::one()) };
+		for j in col+1 .. n
+		{
+			let x = values[j].as_ref().unwrap();
+			acc = Fraction {
+				num: checked_det(&acc.num, &x.den, &rows[r][j], &x.num.checked_mul(&acc.den).ok_or(SolveError::Overflow)?).ok_or(SolveError::Overflow)?,
+				den: acc.den.checked_mul(&x.den).ok_or(SolveError::Overflow)?,
+				}.reduce()?;
+		}
+		let den = acc.den.checked_mul(&rows[r][col]).ok_or(SolveError::Overflow)?;
+		values[col] = Some(Fraction { num: acc.num, den: den }.reduce()?);
+	}
+
+	Ok(unknowns.iter().zip(values.into_iter()).map(|(u,v)| make_node(Op::Equality, vec![
+		SubExpression { inverse: false, val: Expression::Variable(u.clone()) },
+		SubExpression { inverse: false, val: v.unwrap().to_expr() },
+		])).collect())
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn solve_strs(eqs: &[&str], unknowns: &[&str]) -> Result<Vec<String>, SolveError> {
+		let eqs: Vec<Expression> = eqs.iter().map(|s| s.parse().unwrap()).collect();
+		let unknowns: Vec<String> = unknowns.iter().map(|s| s.to_string()).collect();
+		solve(&eqs.iter().collect::<Vec<_>>(), &unknowns).map(|v| v.iter().map(|e| e.to_string()).collect())
+	}
+
+	#[test]
+	fn numeric_systems() {
+		assert_eq!(solve_strs(&["x + y = 3", "x - y = 1"], &["x", "y"]).unwrap(), vec!["x=2", "y=1"]);
+		// Exact fractions
+		assert_eq!(solve_strs(&["2*x + 4*y = 1", "3*x = y"], &["x", "y"]).unwrap(), vec!["x=1/14", "y=3/14"]);
+		// Needs a row swap
+		assert_eq!(solve_strs(&["y = 2", "x + y = 5"], &["x", "y"]).unwrap(), vec!["x=3", "y=2"]);
+	}
+	#[test]
+	fn symbolic_coefficients() {
+		assert_eq!(solve_strs(&["a*x + y = c", "x - y = 0"], &["x", "y"]).unwrap(), vec!["x=c/(a+1)", "y=c/(a+1)"]);
+	}
+	#[test]
+	fn bad_systems() {
+		assert!(match solve_strs(&["x + y = 1"], &["x", "y"]) { Err(SolveError::Underdetermined(ref v)) => v == &["y"], _ => false });
+		assert!(match solve_strs(&["x + y = 1", "2*x + 2*y = 2"], &["x", "y"]) { Err(SolveError::Singular(ref v)) => v == &["y"], _ => false });
+		assert!(match solve_strs(&["x + y = 1", "x + y = 2"], &["x", "y"]) { Err(SolveError::Inconsistent) => true, _ => false });
+		assert!(match solve_strs(&["x*y = 1", "x = 2"], &["x", "y"]) { Err(SolveError::NotLinear(0)) => true, _ => false });
+		assert!(match solve_strs(&["x = 1", "y"], &["x", "y"]) { Err(SolveError::NotEquation(1)) => true, _ => false });
+		// Coefficients too large to eliminate exactly are an error rather than a panic
+		let big = "999999999*999999999*999999999";
+		let eqs = [format!("{0}*x + 3*y = 1", big), format!("7*x + {0}*y = 2", big), format!("x + y + {0}*z = 3", big)];
+		assert!(match solve_strs(&[&eqs[0], &eqs[1], &eqs[2]], &["x", "y", "z"]) { Err(SolveError::Overflow) => true, _ => false });
+	}
+}
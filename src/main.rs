@@ -6,6 +6,20 @@ mod expression;
 mod curses_ui;
 mod ui_helpers;
 mod manip;
+mod rewrite;
+mod derivation;
+mod eval;
+mod rational;
+mod poly;
+mod mpoly;
+mod linear;
+mod units;
+mod latex;
+mod mathml;
+mod layout;
+mod unicode;
+mod render;
+mod json;
 
 #[derive(StructOpt)]
 #[structopt(name="equation", about="Algebraic equation editor")]
@@ -13,131 +27,491 @@ struct Opts
 {
 	#[structopt(parse(from_os_str))]
 	infile: Option<std::path::PathBuf>,
+	/// Disable saving from the editor
 	#[structopt(short="R", long="read-only")]
 	readonly: bool,
+	/// Verify all recorded derivations in the input file and exit
+	#[structopt(long="check")]
+	check: bool,
+	/// Rewrite rule file (defaults to `~/.equation_rules` if present)
+	#[structopt(long="rules", parse(from_os_str))]
+	rules: Option<std::path::PathBuf>,
+	/// Write the input file to stdout in another format (`latex`, `mathml` or `json`) and exit
+	#[structopt(long="export")]
+	export: Option<String>,
 }
 
 fn main()
 {
 	let opts: Opts = structopt::StructOpt::from_args();
 
-	let mut lines = if let Some(ref v) = opts.infile
+	let (mut lines, units, load_errors) = if let Some(ref v) = opts.infile
 		{
-			EquationSet::from_file(v).unwrap().lines
+			let es = match EquationSet::from_file(v)
+				{
+				Ok(es) => es,
+				Err(e) => {
+					eprintln!("Unable to load {}: {}", v.display(), e);
+					std::process::exit(1);
+					},
+				};
+			// Unparsable lines are kept (and flagged in the UI), but also reported here
+			for (line, msg) in es.errors.iter()
+			{
+				eprintln!("{}:{}: {}", v.display(), line, msg);
+			}
+			(es.lines, es.units, es.errors.len())
+		}
+		else
+		{
+			(vec![
+				Line::from_str("s = s_0 + u*t + 0.5*a_0*t^2 + 1/6*j*t^3").unwrap(),
+				Line::from_str("v = v_0 + a_0*t + 0.5*j*t^2").unwrap(),
+				Line::from_str("a = a_0 + j*t").unwrap(),
+				], units::Units::new(), 0)
+		};
+	// The UI needs at least one line to work on
+	if lines.is_empty() {
+		lines.push(Line::comment_only());
+	}
+
+	let rules_path = match opts.rules
+		{
+		Some(ref v) => Some(v.clone()),
+		None => std::env::var_os("HOME")
+			.map(|h| std::path::Path::new(&h).join(".equation_rules"))
+			.filter(|p| p.exists()),
+		};
+	let rules = if let Some(ref p) = rules_path
+		{
+			match rewrite::load_rules(p)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				eprintln!("Unable to load rules from {}: {}", p.display(), e);
+				std::process::exit(1);
+				},
+			}
 		}
 		else
 		{
-			vec![
-				Line::from_str("s = s_0 + u*t + 0.5*a_0*t^2 + 1/6*j*t^3"),
-				Line::from_str("v = v_0 + a_0*t + 0.5*j*t^2"),
-				Line::from_str("a = a_0 + j*t"),
-				]
+			Vec::new()
 		};
 
-	curses_ui::mainloop(&mut lines);
+	if let Some(ref format) = opts.export
+	{
+		if opts.infile.is_none() {
+			eprintln!("--export requires an input file");
+			std::process::exit(2);
+		}
+		match &format[..]
+		{
+		"latex" => print!("{}", latex::export(&lines)),
+		"mathml" => print!("{}", mathml::export(&lines)),
+		"json" => print!("{}", json::export(&lines, &units)),
+		_ => {
+			eprintln!("Unknown export format `{}` (expected `latex`, `mathml` or `json`)", format);
+			std::process::exit(2);
+			},
+		}
+		return ;
+	}
+
+	if opts.check
+	{
+		if opts.infile.is_none() {
+			eprintln!("--check requires an input file");
+			std::process::exit(2);
+		}
+		let failures = derivation::check_all(&lines, &rules);
+		for (i, msg) in failures.iter()
+		{
+			println!("L{}: {}", i + 1, msg);
+		}
+		let count = lines.iter().filter(|l| l.derivation.is_some()).count();
+		println!("{} of {} derivation steps verified", count - failures.len(), count);
+		let mut unit_failures = 0;
+		for (i, l) in lines.iter().enumerate()
+		{
+			if let Some(Err(e)) = l.expr.as_ref().map(|e| units.check(e)) {
+				println!("L{}: units - {}", i + 1, e.message);
+				unit_failures += 1;
+			}
+		}
+		std::process::exit(if failures.len() > 0 || unit_failures > 0 || load_errors > 0 { 1 } else { 0 });
+	}
+
+	curses_ui::mainloop(&mut lines, &rules, &units, opts.infile.as_ref().map(|p| p.as_path()), opts.readonly);
 }
 
 pub struct EquationSet {
 	pub dirty: bool,
 	pub lines: Vec<Line>,
+	/// Declared units (from `#! unit` header lines)
+	pub units: units::Units,
+	/// Lines that couldn't be parsed (file line number and error), kept as raw text in `lines`
+	pub errors: Vec<(usize, String)>,
 }
 impl EquationSet
 {
 	pub fn from_file(p: &std::path::Path) -> std::io::Result<EquationSet>
 	{
-		use std::io::BufRead;
-		let f = std::io::BufReader::new( std::fs::File::open(p)? );
-
+		let s = std::fs::read_to_string(p)?;
 		let mut rv = EquationSet {
 			dirty: false,
 			lines: Vec::new(),
+			units: units::Units::new(),
+			errors: Vec::new(),
 			};
-		for line in f.lines()
+		// LaTeX documents (e.g. from `--export latex`)
+		if p.extension().map(|e| e == "tex").unwrap_or(false)
+		{
+			let doc = latex::parse_document(&s).map_err(|e| {
+				let line = s[..e.pos].matches('\n').count() + 1;
+				std::io::Error::new(std::io::ErrorKind::InvalidData, format!("line {}: {}", line, e.message))
+				})?;
+			for (expr, comment) in doc
+			{
+				let mut l = match expr
+					{
+					Some(e) => Line::from_expr(e),
+					None => Line::comment_only(),
+					};
+				l.comment = if comment == "" { comment } else { format!(" {}", comment) };
+				rv.lines.push(l);
+			}
+			return Ok( rv );
+		}
+
+		// Structured files (chosen by extension, or by starting with a JSON object)
+		if p.extension().map(|e| e == "json").unwrap_or(false) || json::is_document(&s) {
+			return json::load(&s).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+		}
+
+		for (i, line) in s.lines().enumerate()
 		{
-			rv.lines.push(Line::from_str(&line?));
+			if line.starts_with("#!") {
+				// Bad headers are kept (like unparsable lines) instead of failing the whole load
+				if let Err(e) = rv.units.parse_declaration(&line[2..]) {
+					rv.errors.push( (i + 1, e) );
+					rv.lines.push(Line::raw_text(line));
+				}
+				continue ;
+			}
+			let l = if line.trim() == "" {
+					// Blank lines are kept as spacers
+					Line::comment_only()
+				}
+				else {
+					match Line::from_str(line)
+					{
+					Ok(l) => l,
+					Err(e) => {
+						rv.errors.push( (i + 1, format!("{}", e)) );
+						Line::raw_text(line)
+						},
+					}
+				};
+			rv.lines.push(l);
 		}
 		Ok( rv )
 	}
 
 	pub fn save_to(&self, p: &std::path::Path) -> std::io::Result<()>
 	{
-		use std::io::Write;
-		let mut f = std::io::BufWriter::new( std::fs::File::create(p)? );
+		save_lines(p, &self.lines, &self.units)
+	}
+}
+
+/// Write lines (and unit declarations) to a file, in the format chosen by its extension
+pub fn save_lines(p: &std::path::Path, lines: &[Line], units: &units::Units) -> std::io::Result<()>
+{
+	use std::io::Write;
+	let mut f = std::io::BufWriter::new( std::fs::File::create(p)? );
 
-		for l in self.lines.iter()
+	// Keep LaTeX files as LaTeX (derivations and units aren't stored)
+	if p.extension().map(|e| e == "tex").unwrap_or(false) {
+		return f.write_all(latex::export(lines).as_bytes());
+	}
+	if p.extension().map(|e| e == "json").unwrap_or(false) {
+		return f.write_all(json::export(lines, units).as_bytes());
+	}
+	for d in units.declarations()
+	{
+		write!(f, "#! {}\n", d)?;
+	}
+	for l in lines.iter()
+	{
+		// Unparsed lines are written back unchanged
+		if let Some(ref raw) = l.raw {
+			write!(f, "{}\n", raw)?;
+			continue ;
+		}
+		match l.expr
 		{
-			write!(f, "{}", l.expr)?;
+		Some(ref e) => {
+			write!(f, "{}", e)?;
 			if l.comment.len() > 0 {
 				write!(f, " #{}", l.comment)?;
 			}
-			write!(f, "\n")?;
+			},
+		// Comment-only, or blank
+		// - Escaped if it would otherwise be read as a header (`#!`)
+		None => if l.comment.starts_with('!') || l.comment.starts_with('\\') {
+			write!(f, "#\\{}", l.comment)?;
+			}
+			else if l.comment.len() > 0 {
+			write!(f, "#{}", l.comment)?;
+			},
 		}
-
-		Ok( () )
+		if let Some(ref d) = l.derivation {
+			write!(f, " #@ {}", d)?;
+		}
+		write!(f, "\n")?;
 	}
-}
 
+	Ok( () )
+}
 
 #[derive(Clone)]
 pub struct Line {
-	expr: expression::Expression,
+	/// `None` for a line that only holds a comment (or is blank, or failed to parse)
+	expr: Option<expression::Expression>,
 	comment: String, 
 	sel: ui_helpers::Selection,
+	derivation: Option<derivation::Derivation>,
+	/// Source text of a line that failed to parse
+	raw: Option<String>,
 }
 impl Line {
 	fn from_expr(expr: expression::Expression) -> Line {
 		Line {
-			expr: expr,
+			expr: Some(expr),
 			comment: "".to_owned(), 
-			sel: crate::ui_helpers::Selection { path: vec![], first: 0, last: 0 },
+			sel: crate::ui_helpers::Selection::new(),
+			derivation: None,
+			raw: None,
 		}
 	}
-	fn from_str(s: &str) -> Line {
-		let (expr, comment) = expression::Expression::parse_from_str_with_comment(s).unwrap();
+	/// Create a line with no expression (a blank spacer until the caller fills in a comment)
+	fn comment_only() -> Line {
 		Line {
-			expr: expr,
-			comment: comment,
-			sel: crate::ui_helpers::Selection { path: vec![], first: 0, last: 0 },
+			expr: None,
+			comment: "".to_owned(), 
+			sel: crate::ui_helpers::Selection::new(),
+			derivation: None,
+			raw: None,
 		}
 	}
-
-	fn render_split(&self) -> (String,String,String) {
-		crate::ui_helpers::split_expression(&self.expr, &self.sel)
+	/// Keep the text of a line that couldn't be parsed
+	fn raw_text(s: &str) -> Line {
+		Line {
+			raw: Some(s.to_owned()),
+			.. Line::comment_only()
+		}
+	}
+	/// Parse a line of the plain format (`<expression> #<comment> #@ <derivation>`, or just `#<comment>`)
+	fn from_str(s: &str) -> Result<Line, expression::ParseError> {
+		let (expr, comment) = expression::Expression::opt_parse_from_str_with_comment(s)?;
+		// Split off the derivation record (`#@ from ...`) from the user's comment
+		let (comment, derivation) = match comment.find("#@")
+			{
+			Some(p) if expr.is_some() => (&comment[..p], comment[p+2..].parse().ok()),
+			_ => (&comment[..], None),
+			};
+		let comment = comment.trim_start_matches('#').trim_end();
+		// Comment-only lines starting with `!` (or `\`) are escaped when saved, see `save_lines`
+		let comment = if expr.is_none() && comment.starts_with('\\') { &comment[1..] } else { comment };
+		Ok(Line {
+			expr: expr,
+			comment: comment.to_owned(),
+			sel: crate::ui_helpers::Selection::new(),
+			derivation: derivation,
+			raw: None,
+		})
 	}
+	/// Create a new line derived from this one by replacing the current selection
+	fn derive(&self, e: expression::Expression, derivation: derivation::Derivation) -> Line {
+		let mut rv = Line {
+			expr: self.expr.clone(),
+			comment: String::new(),
+			sel: self.sel.clone(),
+			derivation: Some(derivation),
+			raw: None,
+			};
+		rv.replace_selection(e);
+		rv
+	}
+
+	// NOTE: Selections are only made on lines with an expression
 	fn render_selection(&self) -> String {
-		crate::ui_helpers::split_expression(&self.expr, &self.sel).1
+		crate::ui_helpers::split_expression(self.expr.as_ref().expect("selection on comment line"), &self.sel).1
 	}
 
 	fn extract_selection(&self) -> expression::Expression {
-		crate::ui_helpers::extract_subexpression(&self.expr, &self.sel)
+		crate::ui_helpers::extract_subexpression(self.expr.as_ref().expect("selection on comment line"), &self.sel)
 	}
 	fn replace_selection(&mut self, e: expression::Expression) {
-		crate::ui_helpers::replace_subexpression(&mut self.expr, &mut self.sel, e)
+		if let Some(ref mut expr) = self.expr {
+			crate::ui_helpers::replace_subexpression(expr, &mut self.sel, e)
+		}
 	}
 
 	fn move_out(&mut self) -> bool {
-		self.sel.move_out(&self.expr)
+		match self.expr { Some(ref e) => self.sel.move_out(e), None => false }
 	}
 	fn move_in(&mut self) -> bool {
-		self.sel.move_in(&self.expr)
+		match self.expr { Some(ref e) => self.sel.move_in(e), None => false }
 	}
 	fn shift_right(&mut self) -> bool {
-		self.sel.shift_right(&self.expr)
+		match self.expr { Some(ref e) => self.sel.shift_right(e), None => false }
 	}
 	fn shift_left(&mut self) -> bool {
-		self.sel.shift_left(&self.expr)
+		match self.expr { Some(ref e) => self.sel.shift_left(e), None => false }
 	}
 	fn expand_right(&mut self) -> bool {
-		self.sel.expand_right(&self.expr)
+		match self.expr { Some(ref e) => self.sel.expand_right(e), None => false }
 	}
 	fn expand_left(&mut self) -> bool {
-		self.sel.expand_left(&self.expr)
+		match self.expr { Some(ref e) => self.sel.expand_left(e), None => false }
 	}
 	fn shrink_right(&mut self) -> bool {
-		self.sel.shrink_right(&self.expr)
+		match self.expr { Some(ref e) => self.sel.shrink_right(e), None => false }
 	}
 	fn shrink_left(&mut self) -> bool {
-		self.sel.shrink_left(&self.expr)
+		match self.expr { Some(ref e) => self.sel.shrink_left(e), None => false }
+	}
+}
+
+/// Insert a line, keeping derivation source indexes up to date
+pub fn insert_line(lines: &mut Vec<Line>, idx: usize, line: Line)
+{
+	for l in lines.iter_mut()
+	{
+		if let Some(ref mut d) = l.derivation {
+			d.line_inserted(idx);
+		}
+	}
+	lines.insert(idx, line);
+}
+/// Remove a line, keeping derivation source indexes up to date (derivations depending on the removed line are dropped)
+pub fn remove_line(lines: &mut Vec<Line>, idx: usize) -> Line
+{
+	let rv = lines.remove(idx);
+	for l in lines.iter_mut()
+	{
+		let keep = match l.derivation
+			{
+			Some(ref mut d) => d.line_removed(idx),
+			None => true,
+			};
+		if !keep {
+			l.derivation = None;
+		}
 	}
+	rv
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn temp_path(name: &str) -> std::path::PathBuf {
+		std::env::temp_dir().join(format!("equation-{}-{}", std::process::id(), name))
+	}
+
+	#[test]
+	fn load_edit_save_load() {
+		let p = temp_path("roundtrip.txt");
+		std::fs::write(&p, "#! unit t: s\na = b*(c + d) # start\nnot = = parsed\n\n#just a comment\n").unwrap();
+		let mut es = EquationSet::from_file(&p).unwrap();
+		assert_eq!(es.lines.len(), 4);
+		assert_eq!(es.errors.len(), 1);
+
+		// Derive a new line from the first
+		let e: expression::Expression = "a = b*c + b*d".parse().unwrap();
+		let d = derivation::Derivation::new(vec![0], "distribute leading", &ui_helpers::Selection::new());
+		let mut new_line = Line::from_expr(e);
+		new_line.derivation = Some(d);
+		insert_line(&mut es.lines, 1, new_line);
+		es.save_to(&p).unwrap();
+		let saved = std::fs::read_to_string(&p).unwrap();
+
+		let es2 = EquationSet::from_file(&p).unwrap();
+		std::fs::remove_file(&p).unwrap();
+		assert_eq!(es2.lines.len(), 5);
+		assert_eq!(es2.lines[0].comment, " start");
+		assert_eq!(es2.lines[1].derivation.as_ref().map(|d| d.sources.clone()), Some(vec![0]));
+		assert_eq!(es2.lines[2].raw.as_ref().map(|s| &s[..]), Some("not = = parsed"));
+		assert_eq!(es2.units.declarations().collect::<Vec<_>>(), vec!["unit t: s".to_owned()]);
+		// A second save is identical
+		let p2 = temp_path("roundtrip2.txt");
+		es2.save_to(&p2).unwrap();
+		assert_eq!(std::fs::read_to_string(&p2).unwrap(), saved);
+		std::fs::remove_file(&p2).unwrap();
+	}
+
+	#[test]
+	fn line_comments() {
+		let l = Line::from_str("a = b # note #@ from L1 by \"substitute\" at [] 0-0").unwrap();
+		assert_eq!(l.comment, " note");
+		assert!(l.derivation.is_some());
+		let l = Line::from_str("# only a comment").unwrap();
+		assert!(l.expr.is_none());
+		assert_eq!(l.comment, " only a comment");
+		// The derivation marker is only special after an expression
+		let l = Line::from_str("#@ not a derivation").unwrap();
+		assert!(l.expr.is_none() && l.derivation.is_none());
+	}
+
+	#[test]
+	fn format_from_extension() {
+		let lines = vec![ Line::from_str("a = b # note").unwrap() ];
+		let p = temp_path("lines.json");
+		save_lines(&p, &lines, &units::Units::new()).unwrap();
+		let saved = std::fs::read_to_string(&p).unwrap();
+		assert!(json::is_document(&saved));
+		// Detected from the contents as well as the extension
+		let p2 = temp_path("lines-json.txt");
+		std::fs::write(&p2, &saved).unwrap();
+		for p in &[&p, &p2]
+		{
+			let es = EquationSet::from_file(p).unwrap();
+			std::fs::remove_file(p).unwrap();
+			assert_eq!(es.lines.len(), 1);
+			assert_eq!(es.lines[0].comment, " note");
+		}
+	}
+
+	#[test]
+	fn bad_headers_are_kept() {
+		let p = temp_path("badheader.txt");
+		std::fs::write(&p, "#! nonsense here
+#! unit t: s
+t = 2
+").unwrap();
+		let es = EquationSet::from_file(&p).unwrap();
+		std::fs::remove_file(&p).unwrap();
+		assert_eq!(es.errors.len(), 1);
+		assert_eq!(es.errors[0].0, 1);
+		assert_eq!(es.lines.len(), 2);
+		assert_eq!(es.lines[0].raw.as_ref().map(|s| &s[..]), Some("#! nonsense here"));
+		assert_eq!(es.units.declarations().count(), 1);
+	}
+
+	#[test]
+	fn comment_lines_are_escaped() {
+		let p = temp_path("comments.txt");
+		let lines: Vec<_> = ["!not a header", "\\backslash", "plain"].iter()
+			.map(|c| Line { comment: c.to_string(), .. Line::comment_only() })
+			.collect();
+		save_lines(&p, &lines, &units::Units::new()).unwrap();
+		let es = EquationSet::from_file(&p).unwrap();
+		std::fs::remove_file(&p).unwrap();
+		assert_eq!(es.errors.len(), 0);
+		assert_eq!(es.units.declarations().count(), 0);
+		let comments: Vec<_> = es.lines.iter().map(|l| &l.comment[..]).collect();
+		assert_eq!(comments, vec!["!not a header", "\\backslash", "plain"]);
+	}
+}
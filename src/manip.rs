@@ -3,15 +3,25 @@
 //!
 
 use crate::expression::{Expression, SubExpression, Op, ExprNode};
+use crate::rational::Rational;
 
-// Convert an expression into a common form
+/// Convert an expression into a common form (so equivalent expressions compare equal)
+///
+/// Polynomials are expanded with like terms collected, other expressions only have like factors and terms merged.
 pub fn normalise(e: Expression) -> Expression
 {
-	// - Sort
-	// - Multiply all into `ax + bx` form
-	// - Sort all components
-	// - Collect exponents
-	e
+	match e
+	{
+	Expression::SubNode(ExprNode { operation: Op::Equality, values }) => Expression::SubNode(ExprNode {
+		operation: Op::Equality,
+		values: values.into_iter().map(|v| SubExpression { inverse: v.inverse, val: normalise(v.val) }).collect(),
+		}),
+	e => match crate::mpoly::MPoly::from_expr(&e)
+		{
+		Some(p) => p.to_expr(),
+		None => collect_like_terms(e.clone()).unwrap_or(e),
+		},
+	}
 }
 
 /// Simplifies the expression tree (merging equal precedence sets)
@@ -65,6 +75,211 @@ pub fn simplify(e: &mut Expression)
 	}
 }
 
+/// Returns true if the named variable appears anywhere within the expression
+pub fn uses_variable(e: &Expression, name: &str) -> bool
+{
+	count_variable(e, name) > 0
+}
+/// Counts the number of times the named variable appears in the expression
+pub fn count_variable(e: &Expression, name: &str) -> usize
+{
+	match e
+	{
+	Expression::Negative(ref v) => count_variable(v, name),
+	Expression::SubNode(ref sn) => sn.values.iter().map(|v| count_variable(&v.val, name)).sum(),
+	Expression::Literal(_) => 0,
+	Expression::Variable(ref n) => if n == name { 1 } else { 0 },
+	}
+}
+
+/// Create a node from a list of values, collapsing single values and ensuring that the first entry isn't inverted
+pub fn make_node(operation: Op, mut values: Vec<SubExpression>) -> Expression
+{
+	if values.len() == 0 {
+		return match operation
+			{
+			Op::MulDiv | Op::ExpRoot => Expression::Literal(1.),
+			_ => Expression::Literal(0.),
+			};
+	}
+	if values[0].inverse {
+		match operation
+		{
+		Op::AddSub => {
+			let v = values.remove(0);
+			values.insert(0, SubExpression { inverse: false, val: Expression::Negative(Box::new(v.val)) });
+			},
+		Op::MulDiv => {
+			values.insert(0, SubExpression { inverse: false, val: Expression::Literal(1.) });
+			},
+		_ => panic!("Inverse leading value for {:?}", operation),
+		}
+	}
+	if values.len() == 1 {
+		values.pop().unwrap().val
+	}
+	else {
+		Expression::SubNode(ExprNode {
+			operation: operation,
+			values: values,
+			})
+	}
+}
+
+/// If the expression is an equality with the variable alone on one side, return the other side
+pub fn find_definition(e: &Expression, var: &str) -> Option<Expression>
+{
+	if let Expression::SubNode(ExprNode { operation: Op::Equality, ref values }) = e
+	{
+		for (i,v) in values.iter().enumerate()
+		{
+			match v.val
+			{
+			Expression::Variable(ref v2) if v2 == var => {
+				let other = if i == 0 { 1 } else { i - 1 };
+				return Some(values[other].val.clone());
+				},
+			_ => {},
+			}
+		}
+	}
+	None
+}
+
+/// If the expression is an equality with a variable on one side and `val` on another, return the variable name
+pub fn find_definition_of(e: &Expression, val: &Expression) -> Option<String>
+{
+	if let Expression::SubNode(ExprNode { operation: Op::Equality, ref values }) = e
+	{
+		for (i,v) in values.iter().enumerate()
+		{
+			match v.val
+			{
+			Expression::Variable(ref name) if values.iter().enumerate().any(|(j,v2)| j != i && v2.val == *val) => {
+				return Some(name.clone());
+				},
+			_ => {},
+			}
+		}
+	}
+	None
+}
+
+/// Rearrange an equation so the named variable is alone on the left, returning the other side
+///
+/// Only handles cases where the variable appears exactly once in the equation.
+pub fn solve_for(e: &Expression, name: &str) -> Option<Expression>
+{
+	let values = match e
+		{
+		Expression::SubNode(ExprNode { operation: Op::Equality, ref values }) => values,
+		_ => return None,
+		};
+	if count_variable(e, name) != 1 {
+		return None;
+	}
+	let idx = values.iter().position(|v| uses_variable(&v.val, name)).unwrap();
+	// For a chain (`a = b = c`) just use the next (or previous) side
+	let other = if idx == 0 { 1 } else { idx - 1 };
+	let mut rv = isolate(values[idx].val.clone(), values[other].val.clone(), name)?;
+	simplify(&mut rv);
+	Some(rv)
+}
+/// Given `lhs = rhs` where `lhs` contains the variable once, returns an expression equal to the variable
+fn isolate(lhs: Expression, rhs: Expression, name: &str) -> Option<Expression>
+{
+	match lhs
+	{
+	Expression::Variable(ref n) if n == name => Some(rhs),
+	Expression::Variable(_) | Expression::Literal(_) => None,
+	Expression::Negative(v) => isolate(*v, Expression::Negative(Box::new(rhs)), name),
+	Expression::SubNode(mut sn) => {
+		let idx = sn.values.iter().position(|v| uses_variable(&v.val, name))?;
+		let target = sn.values.remove(idx);
+		match sn.operation
+		{
+		Op::Equality => None,
+		// `t ± others = rhs` => `t = ±(rhs - others)`
+		Op::AddSub => {
+			let mut values = vec![ SubExpression { inverse: false, val: rhs } ];
+			values.extend( sn.values.into_iter().map(|v| SubExpression { inverse: !v.inverse, val: v.val }) );
+			let new_rhs = make_node(Op::AddSub, values);
+			isolate(target.val, if target.inverse { Expression::Negative(Box::new(new_rhs)) } else { new_rhs }, name)
+			},
+		Op::MulDiv =>
+			if !target.inverse {
+				// `t * others = rhs` => `t = rhs / others`
+				let mut values = vec![ SubExpression { inverse: false, val: rhs } ];
+				values.extend( sn.values.into_iter().map(|v| SubExpression { inverse: !v.inverse, val: v.val }) );
+				isolate(target.val, make_node(Op::MulDiv, values), name)
+			}
+			else {
+				// `others / t = rhs` => `t = others / rhs`
+				let mut values = sn.values;
+				values.push( SubExpression { inverse: true, val: rhs } );
+				isolate(target.val, make_node(Op::MulDiv, values), name)
+			},
+		Op::ExpRoot =>
+			if idx == 0 {
+				// `t ^ e = rhs` => `t = rhs ^ (1/e)`
+				let exp = make_node(Op::ExpRoot, sn.values);
+				let inv_exp = make_node(Op::MulDiv, vec![ SubExpression { inverse: true, val: exp } ]);
+				isolate(target.val, make_node(Op::ExpRoot, vec![
+					SubExpression { inverse: false, val: rhs },
+					SubExpression { inverse: false, val: inv_exp },
+					]), name)
+			}
+			else {
+				// TODO: Needs logarithms
+				None
+			},
+		}
+		},
+	}
+}
+
+/// Parse an operation to apply to both sides of an equation (e.g. `*2`, `-x`, `^(1/2)`)
+pub fn parse_side_op(s: &str) -> Option<(Op, bool, Expression)>
+{
+	let s = s.trim();
+	let (op, inverse) = match s.chars().next()?
+		{
+		'+' => (Op::AddSub, false),
+		'-' => (Op::AddSub, true),
+		'*' => (Op::MulDiv, false),
+		'/' => (Op::MulDiv, true),
+		'^' => (Op::ExpRoot, false),
+		_ => return None,
+		};
+	let val = s[1..].parse().ok()?;
+	Some( (op, inverse, val) )
+}
+/// Apply the same operation to every side of an equality
+pub fn apply_to_both_sides(e: &Expression, op: Op, inverse: bool, val: &Expression) -> Option<Expression>
+{
+	match e
+	{
+	Expression::SubNode(ExprNode { operation: Op::Equality, ref values }) => {
+		let values = values.iter().map(|side| {
+			let new_ent = SubExpression { inverse: inverse, val: val.clone() };
+			let val = match side.val
+				{
+				// Extend existing nodes of the same type (exponents are right-associative, so can't be extended)
+				Expression::SubNode(ref sn) if sn.operation == op && op != Op::ExpRoot => {
+					let mut sn = sn.clone();
+					sn.values.push(new_ent);
+					Expression::SubNode(sn)
+					},
+				ref v => make_node(op, vec![ SubExpression { inverse: false, val: v.clone() }, new_ent ]),
+				};
+			SubExpression { inverse: side.inverse, val: val }
+			}).collect();
+		Some(Expression::SubNode(ExprNode { operation: Op::Equality, values: values }))
+		},
+	_ => None,
+	}
+}
+
 pub fn factorise_trailing(e: Expression) -> Option<Expression>
 {
 	factorise_int(e, Factorise::Trailing)
@@ -116,106 +331,7 @@ fn factorise_int(e: Expression, ty: Factorise) -> Option<Expression>
 			}
 			match ty
 			{
-			Factorise::All => {
-				let common: Vec<SubExpression> = {
-					// Enumerate all components and collect into comparable types
-					let mut items = vec![ ];
-					for v in sn.values.iter()
-					{
-						let this_items: Vec<Ent> = match v.val
-							{
-							Expression::SubNode(ref isn) if isn.operation == Op::MulDiv => {
-								// Iterate all entries, add to list for this item
-								isn.values.iter().map( |i| Ent::DivMul(i) ).collect()
-								},
-							_ => {
-								// Add to list for this item
-								vec![ Ent::Direct(&v.val) ]
-								}
-							};
-						// TODO: Assert that there's no duplicates.
-						items.push( this_items );
-					}
-
-					// Find items common in all
-					let mut common_ents = vec![];
-					for v in items[0].iter()
-					{
-						let mut is_missing = false;
-						for l in items.iter()
-						{
-							if l.iter().find(|x| *x == v).is_none() {
-								is_missing = true;
-								break;
-							}
-						}
-						if !is_missing {
-							common_ents.push(match *v
-								{
-								Ent::Direct(e) => SubExpression { inverse: false, val: e.clone() },
-								Ent::DivMul(se) => se.clone(),
-								});
-						}
-					}
-					common_ents
-					};
-
-				// Replace these common items
-				if common.len() > 0
-				{
-					for ent in sn.values.iter_mut()
-					{
-						let v = match ent.val
-							{
-							Expression::SubNode(ref mut sn) =>
-								if sn.values.len() == common.len() {
-									Some( Expression::Literal(1.) )
-								}
-								else {
-									// Retain all values that aren't in the common list
-									// TODO: What about duplicates? Only the first should be removed. (or, just assert that above?)
-									sn.values.retain(|v| common.iter().any(|x| x != v));
-
-									if sn.values[0].inverse {
-										sn.values.insert(0, SubExpression { inverse: false, val: Expression::Literal(1.) });
-										None
-									}
-									else if sn.values.len() == 1 {
-										Some( sn.values.pop().unwrap().val )
-									}
-									else {
-										None
-									}
-								},
-							_ => {
-								assert!( common.len() == 1 );
-								assert!( common[0].inverse == false );
-								assert!( common[0].val == ent.val );
-								Some( Expression::Literal(1.) )
-								},
-							};
-
-						if let Some(v) = v {
-							ent.val = v;
-						}
-					}
-
-					let mut common = common;
-					if common[0].inverse {
-						common.insert(0, SubExpression { inverse: false, val: Expression::Literal(1.) });
-					}
-					common.push( SubExpression { inverse: false, val: Expression::SubNode(sn) } );
-
-					Some(Expression::SubNode(ExprNode {
-						operation: Op::MulDiv,
-						values: common,
-						}))
-				}
-				else
-				{
-					None
-				}
-				},
+			Factorise::All => factorise_common(&sn),
 			Factorise::Leading => {
 				fn get_first(v: &SubExpression)->&Expression {
 					match v.val
@@ -343,3 +459,941 @@ fn factorise_int(e: Expression, ty: Factorise) -> Option<Expression>
 	}
 }
 
+/// A term of a sum split into its sign, rational coefficient and powers of the remaining factors
+///
+/// Building and adjusting terms returns `None` if the coefficient or an exponent overflows.
+struct Term
+{
+	negative: bool,
+	coeff: Rational,
+	/// Each base appears only once (repeated bases have their exponents summed)
+	factors: Vec<(Expression, Rational)>,
+}
+impl Term
+{
+	fn from_expr(e: &Expression, inverse: bool) -> Option<Term>
+	{
+		let mut rv = Term { negative: inverse, coeff: Rational::one(), factors: vec![] };
+		rv.push(e, false)?;
+		if rv.coeff.is_negative() {
+			rv.coeff = rv.coeff.checked_neg()?;
+			rv.negative = !rv.negative;
+		}
+		Some(rv)
+	}
+	fn push(&mut self, e: &Expression, inverse: bool) -> Option<()>
+	{
+		if let Some(r) = Rational::from_expr(e).and_then(|r| if inverse { r.recip() } else { Some(r) }) {
+			self.coeff = self.coeff.checked_mul(r)?;
+			return Some( () );
+		}
+		let one = if inverse { -Rational::one() } else { Rational::one() };
+		match e
+		{
+		Expression::Negative(ref v) => {
+			self.negative = !self.negative;
+			self.push(v, inverse)
+			},
+		Expression::SubNode(ref sn) if sn.operation == Op::MulDiv => {
+			for v in sn.values.iter() {
+				self.push(&v.val, inverse != v.inverse)?;
+			}
+			Some( () )
+			},
+		Expression::SubNode(ref sn) if sn.operation == Op::ExpRoot => {
+			let (base, exp) = split_power(sn);
+			match Rational::from_expr(&exp)
+			{
+			Some(exp) => self.add_factor(base, exp.checked_mul(one)?),
+			None => self.add_factor(e.clone(), one),
+			}
+			},
+		_ => self.add_factor(e.clone(), one),
+		}
+	}
+	fn add_factor(&mut self, base: Expression, exp: Rational) -> Option<()>
+	{
+		match self.factors.iter().position(|f| f.0 == base)
+		{
+		Some(i) => self.factors[i].1 = self.factors[i].1.checked_add(exp)?,
+		None => self.factors.push( (base, exp) ),
+		}
+		Some( () )
+	}
+	fn get_exp(&self, base: &Expression) -> Rational
+	{
+		self.factors.iter().find(|f| f.0 == *base).map(|f| f.1).unwrap_or(Rational::zero())
+	}
+
+	/// Convert the magnitude back into an expression (the sign is left to the caller)
+	fn to_expr(&self) -> Expression
+	{
+		let mut values = vec![];
+		if !self.coeff.is_one() {
+			values.push(SubExpression { inverse: false, val: self.coeff.to_expr() });
+		}
+		// Multiplied factors first, so divisions don't need a leading `1`
+		for &neg in [false, true].iter()
+		{
+			for (base, exp) in self.factors.iter().filter(|f| !f.1.is_zero() && f.1.is_negative() == neg)
+			{
+				values.push(SubExpression { inverse: neg, val: make_power(base.clone(), exp.abs()) });
+			}
+		}
+		make_node(Op::MulDiv, values)
+	}
+}
+fn make_power(base: Expression, exp: Rational) -> Expression
+{
+	if exp.is_one() {
+		base
+	}
+	else {
+		make_node(Op::ExpRoot, vec![
+			SubExpression { inverse: false, val: base },
+			SubExpression { inverse: false, val: exp.to_expr() },
+			])
+	}
+}
+
+/// Extract everything common to all terms of a sum: shared bases (at their smallest power), the GCD of any polynomial
+/// factors, and the GCD of the numeric coefficients
+///
+/// Returns `None` if there's nothing common, or if the coefficients are too large to handle exactly.
+fn factorise_common(sn: &ExprNode) -> Option<Expression>
+{
+	use crate::mpoly::{self, MPoly};
+	let mut terms: Vec<Term> = sn.values.iter().map(|v| Term::from_expr(&v.val, v.inverse)).collect::<Option<_>>()?;
+	let mut common = vec![];
+
+	// Bases present in every term (with exponents of the same sign), taking the power closest to zero
+	for (base, exp) in terms[0].factors.clone()
+	{
+		let mut min_exp = exp;
+		for t in terms[1..].iter()
+		{
+			let e = t.get_exp(&base);
+			if e.is_zero() || e.is_negative() != exp.is_negative() {
+				min_exp = Rational::zero();
+				break;
+			}
+			if e.abs().checked_sub(min_exp.abs())?.is_negative() {
+				min_exp = e;
+			}
+		}
+		if !min_exp.is_zero() {
+			for t in terms.iter_mut() {
+				t.add_factor(base.clone(), min_exp.checked_neg()?)?;
+			}
+			common.push( (base, min_exp) );
+		}
+	}
+
+	// GCD of the remaining polynomial factors (sums raised to positive integer powers)
+	let poly_factors = |t: &Term| -> Vec<(usize, MPoly, i128)> {
+		t.factors.iter().enumerate()
+			.filter(|(_,f)| f.1.is_integer() && !f.1.is_negative() && !f.1.is_zero())
+			.filter(|(_,f)| match f.0 { Expression::SubNode(ref sn) => sn.operation == Op::AddSub, _ => false })
+			.filter_map(|(i,f)| Some( (i, MPoly::from_expr(&f.0)?, f.1.num()) ))
+			.collect()
+		};
+	let mut poly_gcd = None;
+	if terms.iter().all(|t| poly_factors(t).len() > 0)
+	{
+		let mut g = MPoly::zero();
+		for t in terms.iter()
+		{
+			let mut prod = MPoly::constant(Rational::one());
+			for (_, p, exp) in poly_factors(t) {
+				for _ in 0 .. exp {
+					prod = prod.checked_mul(&p)?;
+				}
+			}
+			g = mpoly::gcd(&g, &prod)?;
+		}
+		if g.as_constant().is_none()
+		{
+			// Divide the GCD out of each term's factors one at a time
+			let mut new_terms = vec![];
+			for t in terms.iter()
+			{
+				let mut t = Term { negative: t.negative, coeff: t.coeff, factors: t.factors.clone() };
+				let mut remaining = g.clone();
+				let mut new_factors = vec![];
+				for (i, p, exp) in poly_factors(&t)
+				{
+					t.factors[i].1 = Rational::zero();
+					for _ in 0 .. exp
+					{
+						let h = mpoly::gcd(&p, &remaining)?;
+						if h.as_constant().is_some() {
+							new_factors.push(t.factors[i].0.clone());
+							continue ;
+						}
+						remaining = remaining.div_exact(&h)?;
+						let q = p.div_exact(&h)?;
+						match q.as_constant()
+						{
+						Some(c) => t.coeff = t.coeff.checked_mul(c)?,
+						None => new_factors.push(q.to_expr()),
+						}
+					}
+				}
+				if remaining.as_constant().is_none() {
+					break;
+				}
+				for f in new_factors {
+					t.add_factor(f, Rational::one())?;
+				}
+				if t.coeff.is_negative() {
+					t.coeff = t.coeff.checked_neg()?;
+					t.negative = !t.negative;
+				}
+				new_terms.push(t);
+			}
+			if new_terms.len() == terms.len() {
+				terms = new_terms;
+				poly_gcd = Some(g.to_expr());
+			}
+		}
+	}
+
+	// Numeric GCD of the coefficients (GCD of the numerators over the LCM of the denominators)
+	let (mut num, mut den) = (0, 1);
+	for t in terms.iter()
+	{
+		num = crate::rational::gcd(num, t.coeff.num());
+		den = (den / crate::rational::gcd(den, t.coeff.den())).checked_mul(t.coeff.den())?;
+	}
+	let coeff = if num == 0 { Rational::one() } else { Rational::new(num, den) };
+	for t in terms.iter_mut() {
+		t.coeff = t.coeff.checked_div(coeff)?;
+	}
+
+	if common.len() == 0 && poly_gcd.is_none() && coeff.is_one() {
+		return None;
+	}
+	let mut values = vec![];
+	if !coeff.is_one() {
+		values.push(SubExpression { inverse: false, val: coeff.to_expr() });
+	}
+	for (base, exp) in common {
+		values.push(SubExpression { inverse: exp.is_negative(), val: make_power(base, exp.abs()) });
+	}
+	if let Some(g) = poly_gcd {
+		values.push(SubExpression { inverse: false, val: g });
+	}
+	let rest = terms.iter().map(|t| SubExpression { inverse: t.negative, val: t.to_expr() }).collect();
+	values.push(SubExpression { inverse: false, val: make_node(Op::AddSub, rest) });
+	Some(make_node(Op::MulDiv, values))
+}
+
+/// Fold literal arithmetic and remove identity operations (e.g. `x*1`, `x+0`, `x^1`)
+pub fn fold_constants(e: Expression) -> Expression
+{
+	match e
+	{
+	Expression::Negative(v) =>
+		match fold_constants(*v)
+		{
+		Expression::Negative(v) => *v,
+		Expression::Literal(v) if v == 0. => Expression::Literal(0.),
+		v => Expression::Negative(Box::new(v)),
+		},
+	Expression::SubNode(sn) => fold_constants_node(sn),
+	e => e,
+	}
+}
+fn fold_constants_node(sn: ExprNode) -> Expression
+{
+	match sn.operation
+	{
+	Op::Equality => Expression::SubNode(ExprNode {
+		operation: Op::Equality,
+		values: sn.values.into_iter().map(|v| SubExpression { inverse: v.inverse, val: fold_constants(v.val) }).collect(),
+		}),
+	Op::AddSub => {
+		// Flatten nested sums, and sum all literal terms
+		// - Inexact literals (ones that can't be represented as a rational) are summed separately
+		fn collect(values: &mut Vec<SubExpression>, constant: &mut Rational, inexact: &mut Option<f64>, inverse: bool, e: Expression)
+		{
+			match e
+			{
+			Expression::Negative(v) => collect(values, constant, inexact, !inverse, *v),
+			Expression::SubNode(ExprNode { operation: Op::AddSub, values: inner }) =>
+				for v in inner {
+					collect(values, constant, inexact, inverse ^ v.inverse, v.val);
+				},
+			e => match Rational::from_expr(&e)
+				{
+				Some(r) => match if inverse { constant.checked_sub(r) } else { constant.checked_add(r) }
+					{
+					Some(v) => *constant = v,
+					// Too large to sum exactly, leave the term alone
+					None => values.push(SubExpression { inverse: inverse, val: e }),
+					},
+				None => match e
+					{
+					Expression::Literal(v) => *inexact = Some(inexact.unwrap_or(0.0) + if inverse { -v as f64 } else { v as f64 }),
+					e => values.push(SubExpression { inverse: inverse, val: e }),
+					},
+				},
+			}
+		}
+		let mut constant = Rational::zero();
+		let mut inexact = None;
+		let mut values = Vec::new();
+		for v in sn.values {
+			collect(&mut values, &mut constant, &mut inexact, v.inverse, fold_constants(v.val));
+		}
+		if let Some(v) = inexact {
+			let v = v + constant.to_f64();
+			if v != 0.0 {
+				values.push(SubExpression { inverse: v < 0.0, val: Expression::Literal(v.abs() as f32) });
+			}
+		}
+		else if !constant.is_zero() {
+			values.push(SubExpression { inverse: constant.is_negative(), val: constant.abs().to_expr() });
+		}
+		// Prefer a leading positive term (`b - a` instead of `-a + b`)
+		if let Some(p) = values.iter().position(|v| !v.inverse) {
+			let v = values.remove(p);
+			values.insert(0, v);
+		}
+		make_node(Op::AddSub, values)
+		},
+	Op::MulDiv => {
+		// Flatten nested products, pull out negations, and multiply all literal factors into a leading coefficient
+		// - Inexact literals are multiplied separately
+		fn collect(values: &mut Vec<SubExpression>, coeff: &mut Rational, inexact: &mut Option<f64>, negative: &mut bool, inverse: bool, e: Expression)
+		{
+			match e
+			{
+			Expression::Negative(v) => {
+				*negative = !*negative;
+				collect(values, coeff, inexact, negative, inverse, *v)
+				},
+			Expression::SubNode(ExprNode { operation: Op::MulDiv, values: inner }) =>
+				for v in inner {
+					collect(values, coeff, inexact, negative, inverse ^ v.inverse, v.val);
+				},
+			e => match Rational::from_expr(&e)
+				{
+				// Division by a literal zero (or a product too large to represent) is left as-is
+				Some(r) => match if inverse { coeff.checked_div(r) } else { coeff.checked_mul(r) }
+					{
+					Some(v) => *coeff = v,
+					None => values.push(SubExpression { inverse: inverse, val: e }),
+					},
+				None => match e
+					{
+					Expression::Literal(v) if v != 0. => *inexact = Some(inexact.unwrap_or(1.0) * if inverse { 1.0 / v as f64 } else { v as f64 }),
+					e => values.push(SubExpression { inverse: inverse, val: e }),
+					},
+				},
+			}
+		}
+		let mut coeff = Rational::one();
+		let mut inexact = None;
+		let mut negative = false;
+		let mut values = Vec::new();
+		for v in sn.values {
+			collect(&mut values, &mut coeff, &mut inexact, &mut negative, v.inverse, fold_constants(v.val));
+		}
+		if coeff.is_zero() {
+			return Expression::Literal(0.);
+		}
+		if coeff.is_negative() {
+			negative = !negative;
+			coeff = coeff.abs();
+		}
+		let mut rv = Vec::new();
+		if let Some(v) = inexact {
+			let v = v * coeff.to_f64();
+			if v < 0.0 {
+				negative = !negative;
+			}
+			rv.push(SubExpression { inverse: false, val: Expression::Literal(v.abs() as f32) });
+		}
+		else if !coeff.is_one() || values.len() == 0 {
+			match coeff.to_expr()
+			{
+			Expression::SubNode(csn) => rv.extend(csn.values),
+			c => rv.push(SubExpression { inverse: false, val: c }),
+			}
+		}
+		rv.extend(values);
+		let rv = make_node(Op::MulDiv, rv);
+		if negative { Expression::Negative(Box::new(rv)) } else { rv }
+		},
+	Op::ExpRoot => {
+		// Exponents are right-associative, so fold from the right
+		let mut values: Vec<Expression> = sn.values.into_iter().map(|v| fold_constants(v.val)).collect();
+		let mut rv = values.pop().unwrap();
+		while let Some(base) = values.pop() {
+			rv = fold_power(base, rv);
+		}
+		rv
+		},
+	}
+}
+fn fold_power(base: Expression, exp: Expression) -> Expression
+{
+	match Rational::from_expr(&exp)
+	{
+	Some(ref r) if r.is_zero() => return Expression::Literal(1.),
+	Some(ref r) if r.is_one() => return base,
+	// Bounded the same as `Rational::from_expr`, large powers are left unevaluated
+	Some(ref r) if r.is_integer() && r.num().abs() <= 64 =>
+		if let Some(v) = Rational::from_expr(&base).and_then(|b| b.checked_pow(r.num() as i32)) {
+			return v.to_expr();
+		},
+	_ => {},
+	}
+	match Rational::from_expr(&base)
+	{
+	Some(ref b) if b.is_one() => return Expression::Literal(1.),
+	_ => {},
+	}
+	// Keep chains flat (`a^(b^c)` is `a^b^c`)
+	let mut values = vec![ SubExpression { inverse: false, val: base } ];
+	match exp
+	{
+	Expression::SubNode(ExprNode { operation: Op::ExpRoot, values: inner }) => values.extend(inner),
+	e => values.push(SubExpression { inverse: false, val: e }),
+	}
+	make_node(Op::ExpRoot, values)
+}
+
+/// Split an exponent node into the base and the (right-associative) exponent
+pub fn split_power(sn: &ExprNode) -> (Expression, Expression)
+{
+	assert!(sn.operation == Op::ExpRoot);
+	(sn.values[0].val.clone(), make_node(Op::ExpRoot, sn.values[1..].to_vec()))
+}
+
+/// Symbolically differentiate an expression with respect to a variable
+///
+/// Returns `None` if the derivative can't be expressed (e.g. needs logarithms of a non-literal)
+pub fn differentiate(e: &Expression, var: &str) -> Option<Expression>
+{
+	Some(fold_constants(differentiate_raw(e, var)?))
+}
+fn differentiate_raw(e: &Expression, var: &str) -> Option<Expression>
+{
+	if !uses_variable(e, var) {
+		return Some(Expression::Literal(0.));
+	}
+	Some(match e
+	{
+	Expression::Literal(_) => Expression::Literal(0.),
+	Expression::Variable(ref n) => Expression::Literal(if n == var { 1. } else { 0. }),
+	Expression::Negative(ref v) => Expression::Negative(Box::new(differentiate_raw(v, var)?)),
+	Expression::SubNode(ref sn) =>
+		match sn.operation
+		{
+		Op::Equality => return None,
+		Op::AddSub => {
+			let mut values = Vec::new();
+			for v in sn.values.iter() {
+				values.push(SubExpression { inverse: v.inverse, val: differentiate_raw(&v.val, var)? });
+			}
+			make_node(Op::AddSub, values)
+			},
+		// Product rule (with the quotient rule for divided factors)
+		Op::MulDiv => {
+			let mut terms = Vec::new();
+			for (i,v) in sn.values.iter().enumerate()
+			{
+				if !uses_variable(&v.val, var) {
+					continue ;
+				}
+				let d = differentiate_raw(&v.val, var)?;
+				let mut factors: Vec<SubExpression> = sn.values.iter().enumerate().filter(|&(j,_)| j != i).map(|(_,v)| v.clone()).collect();
+				factors.push(SubExpression { inverse: false, val: d });
+				if v.inverse {
+					// d(1/f) = -f'/f^2
+					factors.push(SubExpression { inverse: true, val: make_node(Op::ExpRoot, vec![
+						SubExpression { inverse: false, val: v.val.clone() },
+						SubExpression { inverse: false, val: Expression::Literal(2.) },
+						]) });
+				}
+				terms.push(SubExpression { inverse: v.inverse, val: make_node(Op::MulDiv, factors) });
+			}
+			make_node(Op::AddSub, terms)
+			},
+		Op::ExpRoot => {
+			let (base, exp) = split_power(sn);
+			let base_dep = uses_variable(&base, var);
+			let exp_dep = uses_variable(&exp, var);
+			if base_dep && !exp_dep {
+				// Power rule: d(f^n) = n*f^(n-1)*f'
+				let new_exp = make_node(Op::AddSub, vec![
+					SubExpression { inverse: false, val: exp.clone() },
+					SubExpression { inverse: true, val: Expression::Literal(1.) },
+					]);
+				make_node(Op::MulDiv, vec![
+					SubExpression { inverse: false, val: exp },
+					SubExpression { inverse: false, val: make_node(Op::ExpRoot, vec![
+						SubExpression { inverse: false, val: base.clone() },
+						SubExpression { inverse: false, val: new_exp },
+						]) },
+					SubExpression { inverse: false, val: differentiate_raw(&base, var)? },
+					])
+			}
+			else if !base_dep {
+				// Exponent rule: d(a^g) = a^g*ln(a)*g' (only possible with a literal base, as there's no logarithm)
+				let b = Rational::from_expr(&base)?.to_f64();
+				if b <= 0.0 {
+					return None;
+				}
+				make_node(Op::MulDiv, vec![
+					SubExpression { inverse: false, val: e.clone() },
+					SubExpression { inverse: false, val: Expression::Literal(b.ln() as f32) },
+					SubExpression { inverse: false, val: differentiate_raw(&exp, var)? },
+					])
+			}
+			else {
+				return None;
+			}
+			},
+		},
+	})
+}
+
+/// Build the equation `d/dx*(e) = <derivative of e>`
+pub fn derivative_equation(e: &Expression, var: &str) -> Option<Expression>
+{
+	let d = differentiate(e, var)?;
+	let lhs = Expression::SubNode(ExprNode {
+		operation: Op::MulDiv,
+		values: vec![
+			SubExpression { inverse: false, val: Expression::Variable("d".to_owned()) },
+			SubExpression { inverse: true, val: Expression::Variable(format!("d{}", var)) },
+			SubExpression { inverse: false, val: e.clone() },
+			],
+		});
+	Some(make_node(Op::Equality, vec![
+		SubExpression { inverse: false, val: lhs },
+		SubExpression { inverse: false, val: d },
+		]))
+}
+
+/// Returns a variable name based on `base` that isn't used in any of the passed expressions
+pub fn fresh_variable<'a>(exprs: impl Iterator<Item=&'a Expression> + Clone, base: &str) -> String
+{
+	let is_used = |name: &str| exprs.clone().any(|e| uses_variable(e, name));
+	if !is_used(base) {
+		return base.to_owned();
+	}
+	(1 ..).map(|i| format!("{}_{}", base, i)).find(|n| !is_used(n)).unwrap()
+}
+
+/// Integrate a polynomial (including rational powers) with respect to a variable, adding the named constant of integration
+///
+/// Returns `None` if the expression isn't in the supported class
+pub fn integrate(e: &Expression, var: &str, constant: &str) -> Option<Expression>
+{
+	let mut terms = Vec::new();
+	integrate_terms(e, var, false, &mut terms)?;
+	terms.push(SubExpression { inverse: false, val: Expression::Variable(constant.to_owned()) });
+	Some(fold_constants(make_node(Op::AddSub, terms)))
+}
+fn integrate_terms(e: &Expression, var: &str, inverse: bool, terms: &mut Vec<SubExpression>) -> Option<()>
+{
+	match e
+	{
+	Expression::Negative(ref v) => integrate_terms(v, var, !inverse, terms),
+	Expression::SubNode(ref sn) if sn.operation == Op::AddSub => {
+		for v in sn.values.iter() {
+			integrate_terms(&v.val, var, inverse ^ v.inverse, terms)?;
+		}
+		Some( () )
+		},
+	_ => {
+		terms.push(SubExpression { inverse: inverse, val: integrate_term(e, var)? });
+		Some( () )
+		},
+	}
+}
+/// Integrate a single term of the form `c * x^n`
+fn integrate_term(e: &Expression, var: &str) -> Option<Expression>
+{
+	// Get the power of the variable in a factor (`None` if it's not of the form `x^n` or constant)
+	fn get_power(e: &Expression, var: &str) -> Option<Rational>
+	{
+		match e
+		{
+		_ if !uses_variable(e, var) => Some(Rational::zero()),
+		Expression::Variable(_) => Some(Rational::one()),
+		Expression::SubNode(ref sn) if sn.operation == Op::ExpRoot => {
+			let (base, exp) = split_power(sn);
+			match base
+			{
+			Expression::Variable(ref n) if n == var => Rational::from_expr(&exp),
+			_ => None,
+			}
+			},
+		_ => None,
+		}
+	}
+	// Flatten nested products and pull out negations (e.g. `-(j*t)` or `2*-t`)
+	fn collect_factors(e: &Expression, inverse: bool, negative: &mut bool, out: &mut Vec<SubExpression>)
+	{
+		match e
+		{
+		Expression::Negative(ref v) => {
+			*negative = !*negative;
+			collect_factors(v, inverse, negative, out)
+			},
+		Expression::SubNode(ref sn) if sn.operation == Op::MulDiv =>
+			for v in sn.values.iter() {
+				collect_factors(&v.val, inverse ^ v.inverse, negative, out);
+			},
+		_ => out.push(SubExpression { inverse: inverse, val: e.clone() }),
+		}
+	}
+	let mut power = Rational::zero();
+	let mut coeff = Vec::new();
+	let mut negative = false;
+	let mut factors = Vec::new();
+	collect_factors(e, false, &mut negative, &mut factors);
+	for f in factors
+	{
+		let p = get_power(&f.val, var)?;
+		if p.is_zero() {
+			coeff.push(f);
+		}
+		else {
+			power = if f.inverse { power.checked_sub(p)? } else { power.checked_add(p)? };
+		}
+	}
+	// `x^-1` would need a logarithm
+	let new_power = power.checked_add(Rational::one())?;
+	if new_power.is_zero() {
+		return None;
+	}
+	coeff.push(SubExpression { inverse: false, val: make_node(Op::ExpRoot, vec![
+		SubExpression { inverse: false, val: Expression::Variable(var.to_owned()) },
+		SubExpression { inverse: false, val: new_power.to_expr() },
+		]) });
+	coeff.push(SubExpression { inverse: true, val: new_power.to_expr() });
+	let rv = make_node(Op::MulDiv, coeff);
+	Some(if negative { Expression::Negative(Box::new(rv)) } else { rv })
+}
+
+/// Build the integral line (`name = <integral>`, or just the integral if no name is given)
+pub fn integral_equation(e: &Expression, var: &str, constant: &str, name: &str) -> Option<Expression>
+{
+	let i = integrate(e, var, constant)?;
+	if name == "" {
+		Some(i)
+	}
+	else {
+		Some(make_node(Op::Equality, vec![
+			SubExpression { inverse: false, val: Expression::Variable(name.to_owned()) },
+			SubExpression { inverse: false, val: i },
+			]))
+	}
+}
+
+/// Replace all uses of a variable with an expression
+pub fn substitute_variable(e: &Expression, name: &str, val: &Expression) -> Expression
+{
+	match e
+	{
+	Expression::Variable(ref n) if n == name => val.clone(),
+	Expression::Negative(ref v) => Expression::Negative(Box::new(substitute_variable(v, name, val))),
+	Expression::SubNode(ref sn) => Expression::SubNode(ExprNode {
+		operation: sn.operation,
+		values: sn.values.iter().map(|v| SubExpression { inverse: v.inverse, val: substitute_variable(&v.val, name, val) }).collect(),
+		}),
+	Expression::Literal(_) | Expression::Variable(_) => e.clone(),
+	}
+}
+
+/// Merge repeated factors into powers, and like terms into one (with a summed coefficient)
+///
+/// Factors are sorted, so that equal products compare equal. Returns `None` if a coefficient overflows.
+fn collect_like_terms(e: Expression) -> Option<Expression>
+{
+	// Split a factor into a base and a rational exponent (`(f^a)^n` is `f^(a*n)` for integer `n`)
+	fn split_factor(e: Expression) -> Option<(Expression, Rational)>
+	{
+		let (mut base, mut exp) = match e
+			{
+			Expression::SubNode(ref sn) if sn.operation == Op::ExpRoot => {
+				let (base, exp) = split_power(sn);
+				match Rational::from_expr(&exp)
+				{
+				Some(r) => (base, r),
+				None => return Some( (e.clone(), Rational::one()) ),
+				}
+				},
+			e => (e, Rational::one()),
+			};
+		while let Expression::SubNode(ref sn) = base.clone()
+		{
+			if sn.operation != Op::ExpRoot || !exp.is_integer() {
+				break;
+			}
+			let (inner_base, inner_exp) = split_power(sn);
+			match Rational::from_expr(&inner_exp)
+			{
+			Some(r) => {
+				exp = exp.checked_mul(r)?;
+				base = inner_base;
+				},
+			None => break,
+			}
+		}
+		Some( (base, exp) )
+	}
+	// Split a term into a rational coefficient and the remaining (sorted) factors
+	fn split_term(e: Expression) -> Option<(Rational, Expression)>
+	{
+		let (negative, e) = match e
+			{
+			Expression::Negative(v) => (true, *v),
+			e => (false, e),
+			};
+		let values = match e
+			{
+			Expression::SubNode(ExprNode { operation: Op::MulDiv, values }) => values,
+			e => vec![ SubExpression { inverse: false, val: e } ],
+			};
+		let mut coeff = if negative { -Rational::one() } else { Rational::one() };
+		let mut powers: Vec<(Expression, Rational)> = Vec::new();
+		for v in values
+		{
+			if let Some(r) = Rational::from_expr(&v.val) {
+				coeff = if v.inverse { coeff.checked_div(r)? } else { coeff.checked_mul(r)? };
+				continue ;
+			}
+			let (base, exp) = split_factor(v.val)?;
+			let exp = if v.inverse { exp.checked_neg()? } else { exp };
+			match powers.iter().position(|p| p.0 == base)
+			{
+			Some(i) => powers[i].1 = powers[i].1.checked_add(exp)?,
+			None => powers.push( (base, exp) ),
+			}
+		}
+		let mut factors: Vec<SubExpression> = powers.into_iter()
+			.filter(|p| !p.1.is_zero())
+			.map(|(base, exp)| SubExpression {
+				inverse: exp.is_negative(),
+				val: if exp.abs().is_one() { base } else { make_node(Op::ExpRoot, vec![
+					SubExpression { inverse: false, val: base },
+					SubExpression { inverse: false, val: exp.abs().to_expr() },
+					]) },
+				})
+			.collect();
+		factors.sort_by(|a, b| (a.inverse, a.val.to_string()).cmp(&(b.inverse, b.val.to_string())));
+		if factors.is_empty() {
+			factors.push(SubExpression { inverse: false, val: Expression::Literal(1.) });
+		}
+		Some( (coeff, make_node(Op::MulDiv, factors)) )
+	}
+
+	let e = fold_constants(e);
+	let e = match e
+		{
+		Expression::Negative(v) => Expression::Negative(Box::new(collect_like_terms(*v)?)),
+		Expression::SubNode(sn) => {
+			let mut values = Vec::new();
+			for v in sn.values {
+				values.push(SubExpression { inverse: v.inverse, val: collect_like_terms(v.val)? });
+			}
+			Expression::SubNode(ExprNode { operation: sn.operation, values: values })
+			},
+		e => e,
+		};
+	let terms = match e
+		{
+		Expression::SubNode(ExprNode { operation: Op::AddSub, values }) => values,
+		Expression::SubNode(ExprNode { operation: Op::Equality, .. }) => return Some(e),
+		e => vec![ SubExpression { inverse: false, val: e } ],
+		};
+	let mut sums: Vec<(Expression, Rational)> = Vec::new();
+	for t in terms
+	{
+		let (coeff, rest) = split_term(t.val)?;
+		let coeff = if t.inverse { coeff.checked_neg()? } else { coeff };
+		match sums.iter().position(|s| s.0 == rest)
+		{
+		Some(i) => sums[i].1 = sums[i].1.checked_add(coeff)?,
+		None => sums.push( (rest, coeff) ),
+		}
+	}
+	let terms = sums.into_iter()
+		.map(|(rest, coeff)| SubExpression { inverse: false, val: make_node(Op::MulDiv, vec![
+			SubExpression { inverse: false, val: coeff.to_expr() },
+			SubExpression { inverse: false, val: rest },
+			]) })
+		.collect();
+	Some(fold_constants(make_node(Op::AddSub, terms)))
+}
+
+/// Largest order accepted by `taylor_series`
+pub const MAX_SERIES_ORDER: usize = 20;
+
+/// Expand an expression as a power series in `var` around `point`, truncated after the `order`th power
+pub fn taylor_series(e: &Expression, var: &str, point: &Expression, order: usize) -> Option<Expression>
+{
+	if uses_variable(point, var) || order > MAX_SERIES_ORDER {
+		return None;
+	}
+	let offset = fold_constants(make_node(Op::AddSub, vec![
+		SubExpression { inverse: false, val: Expression::Variable(var.to_owned()) },
+		SubExpression { inverse: true, val: point.clone() },
+		]));
+	let mut terms = Vec::new();
+	// The `k`th derivative divided by `k!` (dividing at each step, so the literals stay small)
+	let mut deriv = e.clone();
+	for k in 0 ..= order
+	{
+		if k > 0 {
+			// Tidy each derivative, otherwise the expression doubles in size with every step
+			deriv = collect_like_terms(make_node(Op::MulDiv, vec![
+				SubExpression { inverse: false, val: differentiate(&deriv, var)? },
+				SubExpression { inverse: true, val: Expression::Literal(k as f32) },
+				]))?;
+		}
+		// f^(k)(p) / k! * (x - p)^k
+		let coeff = fold_constants(substitute_variable(&deriv, var, point));
+		let term = fold_constants(make_node(Op::MulDiv, vec![
+			SubExpression { inverse: false, val: coeff },
+			SubExpression { inverse: false, val: make_node(Op::ExpRoot, vec![
+				SubExpression { inverse: false, val: offset.clone() },
+				SubExpression { inverse: false, val: Expression::Literal(k as f32) },
+				]) },
+			]));
+		// NOTE: Not folding the final sum, as that would merge the `(x - p)` terms
+		match term
+		{
+		Expression::Literal(v) if v == 0. => {},
+		Expression::Negative(v) => terms.push(SubExpression { inverse: true, val: *v }),
+		term => terms.push(SubExpression { inverse: false, val: term }),
+		}
+	}
+	Some(make_node(Op::AddSub, terms))
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn fold(s: &str) -> String {
+		fold_constants(s.parse().unwrap()).to_string()
+	}
+
+	#[test]
+	fn fold_literals() {
+		assert_eq!(fold("1/3 + 1/6"), "0.5");
+		assert_eq!(fold("2*x*3/4"), "1.5*x");
+		assert_eq!(fold("-(-x)"), "x");
+		assert_eq!(fold("2^3^2"), "512");
+		assert_eq!(fold("x/0"), "x/0");
+		assert_eq!(fold("x^1 + 0*y"), "x");
+	}
+	#[test]
+	fn fold_large_values() {
+		// Powers outside the exact range stay unevaluated (and are quick)
+		assert_eq!(fold("(-1)^1000000000"), "(-1)^1000000000");
+		assert_eq!(fold("2^1000"), "2^1000");
+		// Products and sums that overflow are left alone instead of panicking
+		let big = "1000000000";
+		let e = format!("{0}*{0}*{0}*{0}*{0}*{0}*{0}*x", big);
+		assert!(fold(&e).ends_with("*x"));
+		let e = format!("{0}^4*{0}^4 + {0}^4*{0}^4 + {0}^4*{0}^4", big);
+		fold(&e);
+	}
+	#[test]
+	fn definitions() {
+		let e: Expression = "y = x_0 + 2".parse().unwrap();
+		assert_eq!(find_definition(&e, "y").map(|v| v.to_string()), Some("x_0+2".to_owned()));
+		assert_eq!(find_definition(&e, "x_0"), None);
+		// Either side of the equality
+		let e: Expression = "x_0 + 2 = y".parse().unwrap();
+		assert_eq!(find_definition(&e, "y").map(|v| v.to_string()), Some("x_0+2".to_owned()));
+		assert_eq!(find_definition_of(&e, &"x_0 + 2".parse().unwrap()), Some("y".to_owned()));
+		assert_eq!(find_definition_of(&e, &"x_0".parse().unwrap()), None);
+		// Not an equality
+		assert_eq!(find_definition(&"y + 1".parse().unwrap(), "y"), None);
+	}
+	#[test]
+	fn solving() {
+		let solve = |s: &str, v: &str| solve_for(&s.parse().unwrap(), v).map(|e| e.to_string());
+		assert_eq!(solve("x_0 + 2 = y", "x_0"), Some("y-2".to_owned()));
+		assert_eq!(solve("y = 3*x", "x"), Some("y/3".to_owned()));
+		assert_eq!(solve("y = 3/x", "x"), Some("3/y".to_owned()));
+		assert_eq!(solve("y = -x", "x"), Some("-y".to_owned()));
+		// The variable must appear exactly once
+		assert_eq!(solve("y = x*x", "x"), None);
+		assert_eq!(solve("y = z", "x"), None);
+		// Needs logarithms
+		assert_eq!(solve("y = 2^x", "x"), None);
+	}
+
+	#[test]
+	fn common_factors() {
+		let factor = |s: &str| factorise_all(s.parse().unwrap()).map(|e| e.to_string());
+		assert_eq!(factor("x^2*y + x*y^2"), Some("x*y*(x+y)".to_owned()));
+		assert_eq!(factor("6*a + 9*b"), Some("3*(2*a+3*b)".to_owned()));
+		assert_eq!(factor("a/x + b/x"), Some("1/x*(a+b)".to_owned()));
+		assert_eq!(factor("(x^2 - 1)*a + (x + 1)*b"), Some("(x+1)*(a*(x-1)+b)".to_owned()));
+		assert_eq!(factor("a + b"), None);
+		// Too large to handle exactly (instead of panicking)
+		assert_eq!(factor("999999999*999999999*999999999*999999999*999999999*x + x"), None);
+		assert_eq!(factor("(x+1)^200*a + (x^2-1)*b"), None);
+	}
+
+	fn integ(s: &str, var: &str) -> Option<String> {
+		integrate(&s.parse().unwrap(), var, "C").map(|e| e.to_string())
+	}
+	#[test]
+	fn integration() {
+		assert_eq!(integ("a_0 + j*t", "t"), Some("a_0*t+0.5*j*t^2+C".to_owned()));
+		assert_eq!(integ("3*x^2 - 1/x^2", "x"), Some("x^3+x^-1+C".to_owned()));
+		assert_eq!(integ("x^(1/2)", "x"), Some("2/3*x^1.5+C".to_owned()));
+		// Negated products
+		assert_eq!(integ("-(j*t)", "t"), Some("C-0.5*j*t^2".to_owned()));
+		assert_eq!(integ("2*-t", "t"), Some("C-t^2".to_owned()));
+		// Unsupported terms
+		assert_eq!(integ("1/x", "x"), None);
+		assert_eq!(integ("(x+1)^2", "x"), None);
+		assert_eq!(integ("2^x", "x"), None);
+	}
+
+	fn series(s: &str, order: usize) -> Option<String> {
+		taylor_series(&s.parse().unwrap(), "x", &Expression::Literal(0.), order).map(|e| e.to_string())
+	}
+	#[test]
+	fn series_expansion() {
+		assert_eq!(series("1/(1-x)", 3), Some("1+x+x^2+x^3".to_owned()));
+		assert_eq!(series("(1+x)^2", 4), Some("1+2*x+x^2".to_owned()));
+		assert_eq!(series("3 + x", 0), Some("3".to_owned()));
+		assert_eq!(series("x*y - y*x", 2), Some("0".to_owned()));
+		// Around a point other than zero
+		let e = taylor_series(&"x^2".parse().unwrap(), "x", &Expression::Literal(1.), 2).unwrap();
+		assert_eq!(e.to_string(), "1+2*(x-1)+(x-1)^2");
+		// The point can't depend on the variable
+		assert_eq!(taylor_series(&"x".parse().unwrap(), "x", &"x+1".parse().unwrap(), 2), None);
+	}
+	#[test]
+	fn normalised_forms() {
+		let norm = |s: &str| normalise(s.parse().unwrap()).to_string();
+		assert_eq!(norm("(x+a)*(x-a)"), norm("x^2 - a^2"));
+		assert_eq!(norm("x*y*2 - y*x"), "x*y");
+		assert_eq!(norm("1/(1-x) * (1-x)^2"), norm("(1-x)^2/(1-x)"));
+		assert_eq!(norm("v = b*(c+d)"), "v=b*c+b*d");
+	}
+	#[test]
+	fn series_limits() {
+		// High orders stay quick, as each derivative is folded
+		let e = series("1/(1-x)", MAX_SERIES_ORDER).unwrap();
+		assert!(e.ends_with("+x^20"), "{}", e);
+		assert_eq!(series("1/(1-x)", MAX_SERIES_ORDER + 1), None);
+	}
+}
@@ -0,0 +1,233 @@
+//!
+//! Presentation MathML export
+//!
+
+use crate::expression::{Expression, Op, greek_letter, split_ident};
+use std::fmt::Write;
+
+/// Wrapper to render an expression as a MathML `<math>` element (using `Display`)
+pub struct MathMl<'a>(pub &'a Expression);
+
+impl std::fmt::Display for MathMl<'_>
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str("<math>")?;
+		write_expr(f, self.0)?;
+		f.write_str("</math>")
+	}
+}
+
+/// Export a set of lines as a block `<math>` element, with one table row per line (aligned on the first `=`)
+pub fn export(lines: &[crate::Line]) -> String
+{
+	let mut rv = String::new();
+	rv.push_str("<math display=\"block\">\n<mtable columnalign=\"right left left\">\n");
+	for l in lines.iter()
+	{
+		rv.push_str("<mtr>");
+		match l.expr
+		{
+		// The first side goes in its own cell, and the rest (starting with `=`) in the next
+		Some(Expression::SubNode(ref sn)) if sn.operation == Op::Equality => {
+			rv.push_str("<mtd>");
+			let _ = write_expr(&mut rv, &sn.values[0].val);
+			rv.push_str("</mtd><mtd><mrow>");
+			for v in sn.values[1..].iter()
+			{
+				rv.push_str("<mo>=</mo>");
+				let _ = write_expr(&mut rv, &v.val);
+			}
+			rv.push_str("</mrow></mtd>");
+			},
+		Some(ref e) => {
+			rv.push_str("<mtd></mtd><mtd>");
+			let _ = write_expr(&mut rv, e);
+			rv.push_str("</mtd>");
+			},
+		None => rv.push_str("<mtd></mtd><mtd></mtd>"),
+		}
+		if l.comment.trim() != "" {
+			let _ = write!(rv, "<mtd><mtext>{}</mtext></mtd>", escape(l.comment.trim()));
+		}
+		rv.push_str("</mtr>\n");
+	}
+	rv.push_str("</mtable>\n</math>\n");
+	rv
+}
+
+/// Escape characters that are special in XML
+fn escape(s: &str) -> String
+{
+	s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Render an identifier, converting `_` into subscripts, trailing `'` into primes, and Greek letter names into glyphs
+fn write_ident(f: &mut impl Write, name: &str) -> std::fmt::Result
+{
+	let (stem, primes, sub) = split_ident(name);
+	if sub.is_some() {
+		f.write_str("<msub>")?;
+	}
+	if primes > 0 {
+		f.write_str("<msup>")?;
+	}
+	match greek_letter(stem)
+	{
+	Some(c) => write!(f, "<mi>{}</mi>", c)?,
+	None if stem.chars().all(|c| c.is_ascii_digit()) => write!(f, "<mn>{}</mn>", stem)?,
+	None => write!(f, "<mi>{}</mi>", escape(stem))?,
+	}
+	if primes > 0 {
+		write!(f, "<mo>{}</mo></msup>", "\u{2032}".repeat(primes))?;
+	}
+	if let Some(sub) = sub {
+		write_ident(f, sub)?;
+		f.write_str("</msub>")?;
+	}
+	Ok( () )
+}
+
+fn write_expr(f: &mut impl Write, e: &Expression) -> std::fmt::Result
+{
+	let mut r = MathMlRenderer { f: f, res: Ok( () ) };
+	crate::render::walk(&mut r, e, None);
+	r.res
+}
+
+struct MathMlRenderer<'a, W: Write>
+{
+	f: &'a mut W,
+	/// First error from the writer
+	res: std::fmt::Result,
+}
+impl<W: Write> MathMlRenderer<'_, W>
+{
+	fn put(&mut self, s: &str) {
+		if self.res.is_ok() {
+			self.res = self.f.write_str(s);
+		}
+	}
+}
+impl<W: Write> crate::render::Renderer for MathMlRenderer<'_, W>
+{
+	fn features(&self) -> crate::render::Features {
+		crate::render::Features { fractions: true, grouped_exponents: true }
+	}
+	// Each node (and negation) is a `<mrow>`, except powers which are already a single element
+	fn node_enter(&mut self, node: crate::render::Node) {
+		if node != crate::render::Node::Op(Op::ExpRoot) {
+			self.put("<mrow>");
+		}
+	}
+	fn node_exit(&mut self, node: crate::render::Node) {
+		if node != crate::render::Node::Op(Op::ExpRoot) {
+			self.put("</mrow>");
+		}
+	}
+	fn operator(&mut self, op: Op, inverse: bool, next: &Expression) {
+		let s = match op
+			{
+			Op::Equality => "<mo>=</mo>",
+			Op::AddSub => if inverse { "<mo>\u{2212}</mo>" } else { "<mo>+</mo>" },
+			// Invisible multiplication, except before numbers
+			Op::MulDiv =>
+				match next
+				{
+				Expression::Literal(_) | Expression::Negative(_) => "<mo>\u{22C5}</mo>",
+				_ => "<mo>\u{2062}</mo>",
+				},
+			Op::ExpRoot => "<mo>^</mo>",
+			};
+		self.put(s);
+	}
+	fn negate(&mut self) {
+		self.put("<mo>\u{2212}</mo>");
+	}
+	fn literal(&mut self, v: f32) {
+		self.put(&format!("<mn>{}</mn>", v));
+	}
+	fn variable(&mut self, name: &str) {
+		if self.res.is_ok() {
+			self.res = write_ident(self.f, name);
+		}
+	}
+	fn open_paren(&mut self) {
+		self.put("<mrow><mo>(</mo>");
+	}
+	fn close_paren(&mut self) {
+		self.put("<mo>)</mo></mrow>");
+	}
+
+	fn power_start(&mut self) {
+		self.put("<msup>");
+	}
+	fn power_exponent(&mut self, _exp: &Expression) {
+		self.put("<mrow>");
+	}
+	fn power_end(&mut self) {
+		self.put("</mrow></msup>");
+	}
+	fn root_degree(&self, exp: &Expression) -> Option<u32> {
+		crate::expression::root_degree(exp)
+	}
+	fn root_start(&mut self, degree: u32, _radicand: &Expression) {
+		self.put(if degree == 2 { "<msqrt>" } else { "<mroot><mrow>" });
+	}
+	fn root_end(&mut self, degree: u32, _radicand: &Expression) {
+		if degree == 2 {
+			self.put("</msqrt>");
+		}
+		else {
+			self.put(&format!("</mrow><mn>{}</mn></mroot>", degree));
+		}
+	}
+	fn fraction_start(&mut self) {
+		self.put("<mfrac><mrow>");
+	}
+	fn fraction_middle(&mut self) {
+		self.put("</mrow><mrow>");
+	}
+	fn fraction_end(&mut self) {
+		self.put("</mrow></mfrac>");
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn mathml(s: &str) -> String {
+		let e: Expression = s.parse().unwrap();
+		let rv = MathMl(&e).to_string();
+		assert!(rv.starts_with("<math>") && rv.ends_with("</math>"), "{}", rv);
+		rv["<math>".len() .. rv.len() - "</math>".len()].to_owned()
+	}
+
+	#[test]
+	fn expressions() {
+		assert_eq!(mathml("a/b"), "<mrow><mfrac><mrow><mi>a</mi></mrow><mrow><mi>b</mi></mrow></mfrac></mrow>");
+		assert_eq!(mathml("-x"), "<mrow><mo>\u{2212}</mo><mi>x</mi></mrow>");
+		assert_eq!(mathml("x^2^3"), "<msup><mi>x</mi><mrow><msup><mn>2</mn><mrow><mn>3</mn></mrow></msup></mrow></msup>");
+		assert_eq!(mathml("a = b = c"), "<mrow><mi>a</mi><mo>=</mo><mi>b</mi><mo>=</mo><mi>c</mi></mrow>");
+		assert_eq!(mathml("(a+b)*c"), "<mrow><mrow><mo>(</mo><mrow><mi>a</mi><mo>+</mo><mi>b</mi></mrow><mo>)</mo></mrow><mo>\u{2062}</mo><mi>c</mi></mrow>");
+		// Multiplication is only visible before numbers
+		assert_eq!(mathml("2*x*3"), "<mrow><mn>2</mn><mo>\u{2062}</mo><mi>x</mi><mo>\u{22C5}</mo><mn>3</mn></mrow>");
+	}
+	#[test]
+	fn roots_and_identifiers() {
+		assert_eq!(mathml("x^(1/2)"), "<msqrt><mi>x</mi></msqrt>");
+		assert_eq!(mathml("x^(1/3)"), "<mroot><mrow><mi>x</mi></mrow><mn>3</mn></mroot>");
+		assert_eq!(mathml("alpha_0 + v'"), "<mrow><msub><mi>\u{3B1}</mi><mn>0</mn></msub><mo>+</mo><msup><mi>v</mi><mo>\u{2032}</mo></msup></mrow>");
+	}
+	#[test]
+	fn line_export() {
+		let lines: Vec<crate::Line> = ["a = b # x < y", "c + 1", "# only"].iter().map(|l| crate::Line::from_str(l).unwrap()).collect();
+		let rows: Vec<String> = export(&lines).lines().filter(|l| l.starts_with("<mtr>")).map(|l| l.to_owned()).collect();
+		assert_eq!(rows, vec![
+			"<mtr><mtd><mi>a</mi></mtd><mtd><mrow><mo>=</mo><mi>b</mi></mrow></mtd><mtd><mtext>x &lt; y</mtext></mtd></mtr>",
+			"<mtr><mtd></mtd><mtd><mrow><mi>c</mi><mo>+</mo><mn>1</mn></mrow></mtd></mtr>",
+			"<mtr><mtd></mtd><mtd></mtd><mtd><mtext>only</mtext></mtd></mtr>",
+			]);
+	}
+}
@@ -0,0 +1,401 @@
+//!
+//! Multivariate polynomials with exact rational coefficients (used for polynomial GCDs)
+//!
+
+use crate::expression::{Expression, SubExpression, Op};
+use crate::manip::{make_node, fold_constants};
+use crate::rational::Rational;
+use std::collections::BTreeMap;
+
+/// Product of variables raised to powers, sorted by variable name
+type Monomial = Vec<(String, u32)>;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct MPoly
+{
+	terms: BTreeMap<Monomial, Rational>,
+}
+
+fn mono_mul(a: &Monomial, b: &Monomial) -> Monomial
+{
+	let mut rv = a.clone();
+	for (v,p) in b.iter()
+	{
+		match rv.iter().position(|e| e.0 == *v)
+		{
+		Some(i) => rv[i].1 += p,
+		None => rv.push( (v.clone(), *p) ),
+		}
+	}
+	rv.sort();
+	rv
+}
+
+impl MPoly
+{
+	pub fn zero() -> MPoly {
+		MPoly { terms: BTreeMap::new() }
+	}
+	pub fn constant(c: Rational) -> MPoly {
+		let mut rv = MPoly::zero();
+		if !c.is_zero() {
+			rv.terms.insert(vec![], c);
+		}
+		rv
+	}
+	pub fn variable(name: &str) -> MPoly {
+		let mut rv = MPoly::zero();
+		rv.terms.insert(vec![ (name.to_owned(), 1) ], Rational::one());
+		rv
+	}
+
+	/// Convert an expression into a polynomial (only literals, variables, sums, products, division by literals and
+	/// non-negative integer powers)
+	///
+	/// Returns `None` if a coefficient is too large to represent exactly
+	pub fn from_expr(e: &Expression) -> Option<MPoly>
+	{
+		if let Some(r) = Rational::from_expr(e) {
+			return Some(MPoly::constant(r));
+		}
+		match e
+		{
+		Expression::Literal(_) => None,
+		Expression::Variable(ref n) => Some(MPoly::variable(n)),
+		Expression::Negative(ref v) => MPoly::from_expr(v)?.checked_neg(),
+		Expression::SubNode(ref sn) =>
+			match sn.operation
+			{
+			Op::Equality => None,
+			Op::AddSub => {
+				let mut rv = MPoly::zero();
+				for v in sn.values.iter()
+				{
+					let p = MPoly::from_expr(&v.val)?;
+					rv = rv.checked_add(&if v.inverse { p.checked_neg()? } else { p })?;
+				}
+				Some(rv)
+				},
+			Op::MulDiv => {
+				let mut rv = MPoly::constant(Rational::one());
+				for v in sn.values.iter()
+				{
+					rv = if v.inverse {
+							rv.checked_scale(Rational::from_expr(&v.val)?.recip()?)?
+						}
+						else {
+							rv.checked_mul(&MPoly::from_expr(&v.val)?)?
+						};
+				}
+				Some(rv)
+				},
+			Op::ExpRoot => {
+				let (base, exp) = crate::manip::split_power(sn);
+				let exp = Rational::from_expr(&exp)?;
+				if !exp.is_integer() || exp.is_negative() || exp.num() > 64 {
+					return None;
+				}
+				let base = MPoly::from_expr(&base)?;
+				let mut rv = MPoly::constant(Rational::one());
+				for _ in 0 .. exp.num() {
+					rv = rv.checked_mul(&base)?;
+				}
+				Some(rv)
+				},
+			},
+		}
+	}
+
+	/// Convert back into an expression
+	pub fn to_expr(&self) -> Expression
+	{
+		let mut terms = Vec::new();
+		// Highest total degree first, preferring to lead with a positive term
+		let mut ents: Vec<_> = self.terms.iter().collect();
+		ents.sort_by_key(|&(m,c)| (std::cmp::Reverse(m.iter().map(|v| v.1).sum::<u32>()), c.is_negative()));
+		for (m,c) in ents
+		{
+			let mut factors = vec![ SubExpression { inverse: false, val: c.abs().to_expr() } ];
+			for (v,p) in m.iter()
+			{
+				let x = Expression::Variable(v.clone());
+				factors.push(SubExpression { inverse: false, val: if *p == 1 { x } else {
+					make_node(Op::ExpRoot, vec![
+						SubExpression { inverse: false, val: x },
+						SubExpression { inverse: false, val: Expression::Literal(*p as f32) },
+						])
+					} });
+			}
+			terms.push(SubExpression { inverse: c.is_negative(), val: fold_constants(make_node(Op::MulDiv, factors)) });
+		}
+		make_node(Op::AddSub, terms)
+	}
+
+	pub fn is_zero(&self) -> bool {
+		self.terms.len() == 0
+	}
+	/// Returns the value if this polynomial is a constant
+	pub fn as_constant(&self) -> Option<Rational> {
+		match self.terms.len()
+		{
+		0 => Some(Rational::zero()),
+		1 => self.terms.get(&vec![]).cloned(),
+		_ => None,
+		}
+	}
+	/// Set of all variables used
+	pub fn variables(&self) -> Vec<String> {
+		let mut rv: Vec<String> = self.terms.keys().flat_map(|m| m.iter().map(|v| v.0.clone())).collect();
+		rv.sort();
+		rv.dedup();
+		rv
+	}
+
+	fn checked_add_term(&mut self, m: Monomial, c: Rational) -> Option<()> {
+		let v = self.terms.get(&m).unwrap_or(&Rational::zero()).checked_add(c)?;
+		if v.is_zero() {
+			self.terms.remove(&m);
+		}
+		else {
+			self.terms.insert(m, v);
+		}
+		Some( () )
+	}
+	// NOTE: Coefficients come from user input, so all arithmetic is checked (returning `None` on overflow)
+	pub fn checked_add(&self, o: &MPoly) -> Option<MPoly> {
+		let mut rv = self.clone();
+		for (m,c) in o.terms.iter() {
+			rv.checked_add_term(m.clone(), *c)?;
+		}
+		Some(rv)
+	}
+	pub fn checked_neg(&self) -> Option<MPoly> {
+		Some(MPoly { terms: self.terms.iter().map(|(m,c)| Some( (m.clone(), c.checked_neg()?) )).collect::<Option<_>>()? })
+	}
+	pub fn checked_sub(&self, o: &MPoly) -> Option<MPoly> {
+		self.checked_add(&o.checked_neg()?)
+	}
+	pub fn checked_mul(&self, o: &MPoly) -> Option<MPoly> {
+		let mut rv = MPoly::zero();
+		for (m1,c1) in self.terms.iter() {
+			for (m2,c2) in o.terms.iter() {
+				rv.checked_add_term(mono_mul(m1, m2), c1.checked_mul(*c2)?)?;
+			}
+		}
+		Some(rv)
+	}
+	pub fn checked_scale(&self, c: Rational) -> Option<MPoly> {
+		if c.is_zero() {
+			return Some(MPoly::zero());
+		}
+		Some(MPoly { terms: self.terms.iter().map(|(m,v)| Some( (m.clone(), v.checked_mul(c)?) )).collect::<Option<_>>()? })
+	}
+
+	/// Split into the coefficients of each unknown and the remaining constant term (`None` if not linear in the unknowns, or on overflow)
+	pub fn linear_coeffs(&self, unknowns: &[String]) -> Option<(Vec<MPoly>, MPoly)>
+	{
+		let mut coeffs = vec![MPoly::zero(); unknowns.len()];
+		let mut constant = MPoly::zero();
+		for (m,c) in self.terms.iter()
+		{
+			let mut found = None;
+			let mut rest = vec![];
+			for (v,p) in m.iter()
+			{
+				match unknowns.iter().position(|u| u == v)
+				{
+				Some(i) if *p == 1 && found.is_none() => found = Some(i),
+				Some(_) => return None,
+				None => rest.push( (v.clone(), *p) ),
+				}
+			}
+			match found
+			{
+			Some(i) => coeffs[i].checked_add_term(rest, *c)?,
+			None => constant.checked_add_term(rest, *c)?,
+			}
+		}
+		Some( (coeffs, constant) )
+	}
+
+	/// Degree in a single variable
+	fn degree_in(&self, var: &str) -> u32 {
+		self.terms.keys().map(|m| m.iter().find(|v| v.0 == var).map(|v| v.1).unwrap_or(0)).max().unwrap_or(0)
+	}
+	/// Coefficient of `var^power` (as a polynomial in the other variables)
+	fn coeff_in(&self, var: &str, power: u32) -> MPoly {
+		let mut rv = MPoly::zero();
+		for (m,c) in self.terms.iter()
+		{
+			if m.iter().find(|v| v.0 == var).map(|v| v.1).unwrap_or(0) == power {
+				rv.terms.insert(m.iter().filter(|v| v.0 != var).cloned().collect(), *c);
+			}
+		}
+		rv
+	}
+	/// Multiply by `var^power`
+	fn shift(&self, var: &str, power: u32) -> MPoly {
+		if power == 0 {
+			return self.clone();
+		}
+		let m = vec![ (var.to_owned(), power) ];
+		MPoly { terms: self.terms.iter().map(|(m2,c)| (mono_mul(m2, &m), *c)).collect() }
+	}
+
+	/// Exact division (`None` if `d` doesn't divide this polynomial, or on overflow)
+	pub fn div_exact(&self, d: &MPoly) -> Option<MPoly>
+	{
+		if d.is_zero() {
+			return None;
+		}
+		if let Some(c) = d.as_constant() {
+			return self.checked_scale(c.recip()?);
+		}
+		let var = d.variables().into_iter().next().unwrap();
+		let d_deg = d.degree_in(&var);
+		let d_lead = d.coeff_in(&var, d_deg);
+		let mut rem = self.clone();
+		let mut quot = MPoly::zero();
+		while !rem.is_zero()
+		{
+			let r_deg = rem.degree_in(&var);
+			if r_deg < d_deg {
+				return None;
+			}
+			let q = rem.coeff_in(&var, r_deg).div_exact(&d_lead)?.shift(&var, r_deg - d_deg);
+			rem = rem.checked_sub(&q.checked_mul(d)?)?;
+			quot = quot.checked_add(&q)?;
+		}
+		Some(quot)
+	}
+
+	/// Pseudo-remainder of `self` divided by `d` (both treated as polynomials in `var`)
+	fn pseudo_rem(&self, d: &MPoly, var: &str) -> Option<MPoly>
+	{
+		let d_deg = d.degree_in(var);
+		let d_lead = d.coeff_in(var, d_deg);
+		let mut rem = self.clone();
+		while !rem.is_zero() && rem.degree_in(var) >= d_deg
+		{
+			let r_deg = rem.degree_in(var);
+			let r_lead = rem.coeff_in(var, r_deg);
+			rem = rem.checked_mul(&d_lead)?.checked_sub( &d.checked_mul(&r_lead)?.shift(var, r_deg - d_deg) )?;
+		}
+		Some(rem)
+	}
+
+	/// GCD of the coefficients when treated as a polynomial in `var`
+	fn content_in(&self, var: &str) -> Option<MPoly>
+	{
+		let mut rv = MPoly::zero();
+		for i in 0 ..= self.degree_in(var)
+		{
+			rv = gcd(&rv, &self.coeff_in(var, i))?;
+		}
+		Some(rv)
+	}
+
+	/// Scale so the coefficients are coprime integers, with a positive leading term
+	fn normalise(&self) -> Option<MPoly>
+	{
+		let (mut num_gcd, mut den_lcm) = (0, 1i128);
+		for c in self.terms.values()
+		{
+			num_gcd = crate::rational::gcd(num_gcd, c.num());
+			den_lcm = (den_lcm / crate::rational::gcd(den_lcm, c.den())).checked_mul(c.den())?;
+		}
+		if num_gcd == 0 {
+			return Some(self.clone());
+		}
+		let lead_neg = self.terms.values().next_back().map(|c| c.is_negative()).unwrap_or(false);
+		let scale = Rational::new(if lead_neg { -den_lcm } else { den_lcm }, num_gcd);
+		self.checked_scale(scale)
+	}
+}
+
+/// Greatest common divisor of two polynomials (normalised to have coprime integer coefficients), `None` on overflow
+pub fn gcd(a: &MPoly, b: &MPoly) -> Option<MPoly>
+{
+	if a.is_zero() {
+		return b.normalise();
+	}
+	if b.is_zero() {
+		return a.normalise();
+	}
+	if a.as_constant().is_some() || b.as_constant().is_some() {
+		return Some(MPoly::constant(Rational::one()));
+	}
+	let mut vars = a.variables();
+	vars.extend(b.variables());
+	vars.sort();
+	let var = vars[0].clone();
+
+	// Split into content (in the other variables) and primitive part, then run Euclid's algorithm on the primitive parts
+	let ca = a.content_in(&var)?;
+	let cb = b.content_in(&var)?;
+	let content = gcd(&ca, &cb)?;
+	let mut p = a.div_exact(&ca)?;
+	let mut q = b.div_exact(&cb)?;
+	if p.degree_in(&var) < q.degree_in(&var) {
+		std::mem::swap(&mut p, &mut q);
+	}
+	while q.degree_in(&var) > 0
+	{
+		let r = p.pseudo_rem(&q, &var)?;
+		p = q;
+		q = if r.is_zero() { r } else { r.div_exact(&r.content_in(&var)?)? };
+		if q.is_zero() {
+			break;
+		}
+	}
+	// If the final remainder is non-zero (and constant in `var`), the primitive parts are coprime
+	let pp = if q.is_zero() { p.div_exact(&p.content_in(&var)?)? } else { MPoly::constant(Rational::one()) };
+	content.checked_mul(&pp)?.normalise()
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn mp(s: &str) -> MPoly {
+		MPoly::from_expr(&s.parse().unwrap()).unwrap()
+	}
+
+	#[test]
+	fn arithmetic() {
+		assert_eq!(mp("(x + y)^2"), mp("x^2 + 2*x*y + y^2"));
+		assert_eq!(mp("x*y - y*x"), MPoly::zero());
+		assert_eq!(mp("x/2 + x/2"), mp("x"));
+		assert_eq!(mp("(x + 1)*(x - 1)").to_expr().to_string(), "x^2-1");
+		assert!(MPoly::from_expr(&"x/y".parse().unwrap()).is_none());
+		assert!(MPoly::from_expr(&"x^(1/2)".parse().unwrap()).is_none());
+		assert_eq!(mp("3*x*y + 2").variables(), vec!["x".to_owned(), "y".to_owned()]);
+	}
+	#[test]
+	fn division() {
+		assert_eq!(mp("x^2 - y^2").div_exact(&mp("x + y")), Some(mp("x - y")));
+		assert_eq!(mp("x^2 + 1").div_exact(&mp("x + 1")), None);
+		assert_eq!(mp("4*x").div_exact(&mp("2")), Some(mp("2*x")));
+		assert_eq!(mp("x").div_exact(&MPoly::zero()), None);
+	}
+	#[test]
+	fn gcds() {
+		assert_eq!(gcd(&mp("x^2 - 1"), &mp("x^2 + 2*x + 1")), Some(mp("x + 1")));
+		assert_eq!(gcd(&mp("x^2*y + x*y^2"), &mp("x*y")), Some(mp("x*y")));
+		assert_eq!(gcd(&mp("(a + b)*(x - 1)"), &mp("(a + b)*(x + 2)")), Some(mp("a + b")));
+		// Normalised to coprime integer coefficients
+		assert_eq!(gcd(&mp("6*x + 9*y"), &mp("4*x + 6*y")), Some(mp("2*x + 3*y")));
+		assert_eq!(gcd(&mp("x + 1"), &mp("x + 2")), Some(mp("1")));
+		assert_eq!(gcd(&mp("x^2"), &MPoly::zero()), Some(mp("x^2")));
+		assert_eq!(gcd(&mp("x"), &mp("3")), Some(mp("1")));
+	}
+	#[test]
+	fn overflow() {
+		// Results that don't fit are `None` rather than a panic
+		let big = mp("999999999*999999999*999999999*999999999*x");
+		assert_eq!(big.checked_mul(&big), None);
+		assert!(MPoly::from_expr(&"(999*x + 1)^64".parse().unwrap()).is_none());
+		assert_eq!(gcd(&mp("(x + 1)^30*999^10"), &mp("(x - 1)*(x + 1)*7^40")), None);
+	}
+}
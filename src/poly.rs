@@ -0,0 +1,930 @@
+//!
+//! Single-variable polynomial view of expressions
+//!
+
+use crate::expression::{Expression, SubExpression, Op};
+use crate::manip::{fold_constants, make_node, uses_variable};
+use crate::rational::Rational;
+use crate::mpoly::MPoly;
+
+/// A polynomial in a single variable, with arbitrary (variable-free) expressions as coefficients
+#[derive(Debug,Clone)]
+pub struct Polynomial
+{
+	pub var: String,
+	/// Coefficients, indexed by power (i.e. `coeffs[2]` is the coefficient of `x^2`)
+	pub coeffs: Vec<Expression>,
+}
+
+/// Put a coefficient into a canonical form (expanded, with like terms collected), so symbolic coefficients cancel
+fn canonical(e: Expression) -> Expression
+{
+	match MPoly::from_expr(&e)
+	{
+	Some(p) => p.to_expr(),
+	None => fold_constants(e),
+	}
+}
+fn is_zero(e: &Expression) -> bool
+{
+	match Rational::from_expr(e)
+	{
+	Some(r) => r.is_zero(),
+	None => MPoly::from_expr(e).map(|p| p.is_zero()).unwrap_or(false),
+	}
+}
+fn add(a: &Expression, b: &Expression) -> Expression
+{
+	canonical(make_node(Op::AddSub, vec![
+		SubExpression { inverse: false, val: a.clone() },
+		SubExpression { inverse: false, val: b.clone() },
+		]))
+}
+fn mul(a: &Expression, b: &Expression) -> Expression
+{
+	canonical(make_node(Op::MulDiv, vec![
+		SubExpression { inverse: false, val: a.clone() },
+		SubExpression { inverse: false, val: b.clone() },
+		]))
+}
+fn div(a: &Expression, b: &Expression) -> Expression
+{
+	canonical(make_node(Op::MulDiv, vec![
+		SubExpression { inverse: false, val: a.clone() },
+		SubExpression { inverse: true, val: b.clone() },
+		]))
+}
+fn neg(a: &Expression) -> Expression
+{
+	canonical(Expression::Negative(Box::new(a.clone())))
+}
+
+impl Polynomial
+{
+	pub fn constant(var: &str, c: Expression) -> Polynomial
+	{
+		let mut rv = Polynomial { var: var.to_owned(), coeffs: vec![c] };
+		rv.trim();
+		rv
+	}
+
+	/// Interpret an expression as a polynomial in `var` (expanding products and integer powers)
+	///
+	/// Returns `None` if the expression isn't a polynomial in the variable (e.g. division by the variable)
+	pub fn from_expr(e: &Expression, var: &str) -> Option<Polynomial>
+	{
+		if !uses_variable(e, var) {
+			return Some(Polynomial::constant(var, canonical(e.clone())));
+		}
+		match e
+		{
+		Expression::Literal(_) => unreachable!(),
+		Expression::Variable(_) => Some(Polynomial { var: var.to_owned(), coeffs: vec![Expression::Literal(0.), Expression::Literal(1.)] }),
+		Expression::Negative(ref v) => Some(Polynomial::from_expr(v, var)?.neg()),
+		Expression::SubNode(ref sn) =>
+			match sn.operation
+			{
+			Op::Equality => None,
+			Op::AddSub => {
+				let mut rv = Polynomial::constant(var, Expression::Literal(0.));
+				for v in sn.values.iter()
+				{
+					let p = Polynomial::from_expr(&v.val, var)?;
+					rv = if v.inverse { rv.sub(&p) } else { rv.add(&p) };
+				}
+				Some(rv)
+				},
+			Op::MulDiv => {
+				let mut rv = Polynomial::constant(var, Expression::Literal(1.));
+				for v in sn.values.iter()
+				{
+					if v.inverse {
+						// Can only divide by constants
+						if uses_variable(&v.val, var) {
+							return None;
+						}
+						rv = rv.scale_div(&v.val);
+					}
+					else {
+						rv = rv.mul(&Polynomial::from_expr(&v.val, var)?);
+					}
+				}
+				Some(rv)
+				},
+			Op::ExpRoot => {
+				let (base, exp) = crate::manip::split_power(sn);
+				if uses_variable(&exp, var) {
+					return None;
+				}
+				let exp = Rational::from_expr(&exp)?;
+				if !exp.is_integer() || exp.is_negative() || exp.num() > 64 {
+					return None;
+				}
+				let base = Polynomial::from_expr(&base, var)?;
+				let mut rv = Polynomial::constant(var, Expression::Literal(1.));
+				for _ in 0 .. exp.num() {
+					rv = rv.mul(&base);
+				}
+				Some(rv)
+				},
+			},
+		}
+	}
+
+	/// Remove zero leading coefficients
+	fn trim(&mut self)
+	{
+		while self.coeffs.last().map(is_zero).unwrap_or(false) {
+			self.coeffs.pop();
+		}
+	}
+
+	/// Degree of the polynomial (`None` for the zero polynomial)
+	pub fn degree(&self) -> Option<usize>
+	{
+		if self.coeffs.len() == 0 { None } else { Some(self.coeffs.len() - 1) }
+	}
+	pub fn coeff(&self, power: usize) -> Expression
+	{
+		self.coeffs.get(power).cloned().unwrap_or(Expression::Literal(0.))
+	}
+	pub fn is_zero(&self) -> bool
+	{
+		self.coeffs.len() == 0
+	}
+	/// Get the coefficients as exact rationals (if they're all literals)
+	pub fn rational_coeffs(&self) -> Option<Vec<Rational>>
+	{
+		self.coeffs.iter().map(Rational::from_expr).collect()
+	}
+
+	pub fn add(&self, o: &Polynomial) -> Polynomial
+	{
+		let n = std::cmp::max(self.coeffs.len(), o.coeffs.len());
+		let mut rv = Polynomial {
+			var: self.var.clone(),
+			coeffs: (0 .. n).map(|i| add(&self.coeff(i), &o.coeff(i))).collect(),
+			};
+		rv.trim();
+		rv
+	}
+	pub fn neg(&self) -> Polynomial
+	{
+		Polynomial {
+			var: self.var.clone(),
+			coeffs: self.coeffs.iter().map(neg).collect(),
+			}
+	}
+	pub fn sub(&self, o: &Polynomial) -> Polynomial
+	{
+		self.add(&o.neg())
+	}
+	pub fn mul(&self, o: &Polynomial) -> Polynomial
+	{
+		if self.is_zero() || o.is_zero() {
+			return Polynomial::constant(&self.var, Expression::Literal(0.));
+		}
+		let mut coeffs = vec![Expression::Literal(0.); self.coeffs.len() + o.coeffs.len() - 1];
+		for (i,a) in self.coeffs.iter().enumerate()
+		{
+			for (j,b) in o.coeffs.iter().enumerate()
+			{
+				coeffs[i+j] = add(&coeffs[i+j], &mul(a, b));
+			}
+		}
+		let mut rv = Polynomial { var: self.var.clone(), coeffs: coeffs };
+		rv.trim();
+		rv
+	}
+	/// Multiply all coefficients by a constant
+	pub fn scale(&self, c: &Expression) -> Polynomial
+	{
+		let mut rv = Polynomial { var: self.var.clone(), coeffs: self.coeffs.iter().map(|v| mul(v, c)).collect() };
+		rv.trim();
+		rv
+	}
+	/// Divide all coefficients by a constant
+	pub fn scale_div(&self, c: &Expression) -> Polynomial
+	{
+		Polynomial { var: self.var.clone(), coeffs: self.coeffs.iter().map(|v| div(v, c)).collect() }
+	}
+
+	/// Polynomial long division, returning the quotient and remainder
+	///
+	/// Returns `None` when dividing by zero
+	pub fn div_rem(&self, d: &Polynomial) -> Option<(Polynomial, Polynomial)>
+	{
+		let d_deg = d.degree()?;
+		let lead = &d.coeffs[d_deg];
+		let mut rem = self.coeffs.clone();
+		let mut quot = vec![Expression::Literal(0.); rem.len().saturating_sub(d_deg)];
+		while rem.len() > d_deg
+		{
+			let top = rem.len() - 1;
+			let q = div(&rem[top], lead);
+			let shift = top - d_deg;
+			// The leading term cancels by construction, so is just dropped (symbolic coefficients might not fold to zero)
+			rem.pop();
+			for i in 0 .. d_deg
+			{
+				rem[shift + i] = add(&rem[shift + i], &neg(&mul(&q, &d.coeffs[i])));
+			}
+			quot[shift] = q;
+		}
+		let mut q = Polynomial { var: self.var.clone(), coeffs: quot };
+		let mut r = Polynomial { var: self.var.clone(), coeffs: rem };
+		q.trim();
+		r.trim();
+		Some( (q, r) )
+	}
+
+	/// Convert back into an expression, in descending order of power
+	pub fn to_expr(&self) -> Expression
+	{
+		let mut terms = Vec::new();
+		for (i,c) in self.coeffs.iter().enumerate().rev()
+		{
+			if is_zero(c) {
+				continue ;
+			}
+			let term = match i
+				{
+				0 => c.clone(),
+				_ => {
+					let x = Expression::Variable(self.var.clone());
+					let x = if i == 1 { x } else { make_node(Op::ExpRoot, vec![
+						SubExpression { inverse: false, val: x },
+						SubExpression { inverse: false, val: Expression::Literal(i as f32) },
+						]) };
+					mul(c, &x)
+					},
+				};
+			match term
+			{
+			Expression::Negative(v) => terms.push(SubExpression { inverse: true, val: *v }),
+			term => terms.push(SubExpression { inverse: false, val: term }),
+			}
+		}
+		make_node(Op::AddSub, terms)
+	}
+}
+
+/// Get the coefficients `(a, b, c)` of an expression that is quadratic in `var`
+fn quadratic_coeffs(e: &Expression, var: &str) -> Option<(Expression, Expression, Expression)>
+{
+	let p = Polynomial::from_expr(e, var)?;
+	if p.degree() != Some(2) {
+		return None;
+	}
+	Some( (p.coeff(2), p.coeff(1), p.coeff(0)) )
+}
+/// Build `x - r`
+fn sub_root(var: &str, root: Expression) -> Expression
+{
+	fold_constants(make_node(Op::AddSub, vec![
+		SubExpression { inverse: false, val: Expression::Variable(var.to_owned()) },
+		SubExpression { inverse: true, val: root },
+		]))
+}
+
+/// Factor a quadratic into `a*(x - r1)*(x - r2)` using its roots
+///
+/// Rational roots are used exactly (as `(q*x - p)` factors), otherwise the roots are left in `(-b ± (b^2-4*a*c)^(1/2))/(2*a)` form.
+pub fn factor_quadratic(e: &Expression, var: &str) -> Option<Expression>
+{
+	let (a, b, c) = quadratic_coeffs(e, var)?;
+	let x = || Expression::Variable(var.to_owned());
+
+	if let (Some(ra), Some(rb), Some(rc)) = (Rational::from_expr(&a), Rational::from_expr(&b), Rational::from_expr(&c))
+	{
+		let disc = rb * rb - Rational::from_int(4) * ra * rc;
+		if disc.is_negative() {
+			// No real roots
+			return None;
+		}
+		if let Some(s) = disc.sqrt()
+		{
+			let two_a = Rational::from_int(2) * ra;
+			let roots = [ (-rb + s).checked_div(two_a)?, (-rb - s).checked_div(two_a)? ];
+			// Each root `p/q` gives a factor `(q*x - p)`, with the leftover scaling as a leading coefficient
+			let mut factors = Vec::new();
+			let mut k = ra;
+			for r in roots.iter()
+			{
+				k = k.checked_div(Rational::from_int(r.den()))?;
+				let qx = mul(&Rational::from_int(r.den()).to_expr(), &x());
+				factors.push(sub_root_expr(qx, Rational::from_int(r.num()).to_expr()));
+			}
+			let mut values = vec![ SubExpression { inverse: false, val: k.to_expr() } ];
+			if factors[0] == factors[1] {
+				values.push(SubExpression { inverse: false, val: make_node(Op::ExpRoot, vec![
+					SubExpression { inverse: false, val: factors.pop().unwrap() },
+					SubExpression { inverse: false, val: Expression::Literal(2.) },
+					]) });
+			}
+			else {
+				values.extend( factors.into_iter().map(|f| SubExpression { inverse: false, val: f }) );
+			}
+			return Some(fold_constants(make_node(Op::MulDiv, values)));
+		}
+		else
+		{
+			// Irrational roots, `-b/(2a) ± (disc/(4a^2))^(1/2)`
+			let centre = (-rb).checked_div(Rational::from_int(2) * ra)?;
+			let offset = disc.checked_div(Rational::from_int(4) * ra * ra)?;
+			let offset = make_node(Op::ExpRoot, vec![
+				SubExpression { inverse: false, val: offset.to_expr() },
+				SubExpression { inverse: false, val: Expression::Literal(0.5) },
+				]);
+			let root = |inverse: bool| fold_constants(make_node(Op::AddSub, vec![
+				SubExpression { inverse: false, val: centre.to_expr() },
+				SubExpression { inverse: inverse, val: offset.clone() },
+				]));
+			return Some(fold_constants(make_node(Op::MulDiv, vec![
+				SubExpression { inverse: false, val: ra.to_expr() },
+				SubExpression { inverse: false, val: sub_root(var, root(false)) },
+				SubExpression { inverse: false, val: sub_root(var, root(true)) },
+				])));
+		}
+	}
+
+	// General case, use the quadratic formula
+	let disc = fold_constants(make_node(Op::AddSub, vec![
+		SubExpression { inverse: false, val: square(&b) },
+		SubExpression { inverse: true, val: mul(&Expression::Literal(4.), &mul(&a, &c)) },
+		]));
+	let sqrt_disc = make_node(Op::ExpRoot, vec![
+		SubExpression { inverse: false, val: disc },
+		SubExpression { inverse: false, val: make_node(Op::MulDiv, vec![
+			SubExpression { inverse: false, val: Expression::Literal(1.) },
+			SubExpression { inverse: true, val: Expression::Literal(2.) },
+			]) },
+		]);
+	let root = |inverse: bool| div(&make_node(Op::AddSub, vec![
+		SubExpression { inverse: false, val: neg(&b) },
+		SubExpression { inverse: inverse, val: sqrt_disc.clone() },
+		]), &mul(&Expression::Literal(2.), &a));
+	Some(fold_constants(make_node(Op::MulDiv, vec![
+		SubExpression { inverse: false, val: a.clone() },
+		SubExpression { inverse: false, val: sub_root(var, root(false)) },
+		SubExpression { inverse: false, val: sub_root(var, root(true)) },
+		])))
+}
+fn square(e: &Expression) -> Expression
+{
+	fold_constants(make_node(Op::ExpRoot, vec![
+		SubExpression { inverse: false, val: e.clone() },
+		SubExpression { inverse: false, val: Expression::Literal(2.) },
+		]))
+}
+fn sub_root_expr(x: Expression, root: Expression) -> Expression
+{
+	fold_constants(make_node(Op::AddSub, vec![
+		SubExpression { inverse: false, val: x },
+		SubExpression { inverse: true, val: root },
+		]))
+}
+
+/// Rewrite a quadratic as `a*(x + b/(2*a))^2 + (c - b^2/(4*a))`
+pub fn complete_square(e: &Expression, var: &str) -> Option<Expression>
+{
+	let (a, b, c) = quadratic_coeffs(e, var)?;
+	let h = div(&b, &mul(&Expression::Literal(2.), &a));
+	let k = add(&c, &neg(&div(&square(&b), &mul(&Expression::Literal(4.), &a))));
+	let square = make_node(Op::ExpRoot, vec![
+		SubExpression { inverse: false, val: fold_constants(make_node(Op::AddSub, vec![
+			SubExpression { inverse: false, val: Expression::Variable(var.to_owned()) },
+			SubExpression { inverse: false, val: h },
+			])) },
+		SubExpression { inverse: false, val: Expression::Literal(2.) },
+		]);
+	// NOTE: Not using `mul`, as that would expand the square again
+	Some(fold_constants(make_node(Op::AddSub, vec![
+		SubExpression { inverse: false, val: fold_constants(make_node(Op::MulDiv, vec![
+			SubExpression { inverse: false, val: a },
+			SubExpression { inverse: false, val: square },
+			])) },
+		SubExpression { inverse: false, val: k },
+		])))
+}
+
+/// Polynomial with exact rational coefficients (indexed by power), used when decomposing rational functions
+///
+/// The coefficients come from user input, so the arithmetic helpers return `None` on overflow.
+type RatPoly = Vec<Rational>;
+
+fn rp_trim(mut p: RatPoly) -> RatPoly
+{
+	while p.last().map(|c| c.is_zero()).unwrap_or(false) {
+		p.pop();
+	}
+	p
+}
+fn rp_add(a: &RatPoly, b: &RatPoly) -> Option<RatPoly>
+{
+	let n = std::cmp::max(a.len(), b.len());
+	Some(rp_trim( (0 .. n).map(|i| a.get(i).unwrap_or(&Rational::zero()).checked_add(*b.get(i).unwrap_or(&Rational::zero()))).collect::<Option<_>>()? ))
+}
+fn rp_scale(a: &RatPoly, c: Rational) -> Option<RatPoly>
+{
+	Some(rp_trim( a.iter().map(|v| v.checked_mul(c)).collect::<Option<_>>()? ))
+}
+fn rp_mul(a: &RatPoly, b: &RatPoly) -> Option<RatPoly>
+{
+	if a.is_empty() || b.is_empty() {
+		return Some(vec![]);
+	}
+	let mut rv = vec![Rational::zero(); a.len() + b.len() - 1];
+	for (i,x) in a.iter().enumerate() {
+		for (j,y) in b.iter().enumerate() {
+			rv[i+j] = rv[i+j].checked_add(x.checked_mul(*y)?)?;
+		}
+	}
+	Some(rp_trim(rv))
+}
+/// Long division (the divisor must be non-zero)
+fn rp_divrem(a: &RatPoly, d: &RatPoly) -> Option<(RatPoly, RatPoly)>
+{
+	let d_deg = d.len() - 1;
+	let mut rem = a.clone();
+	let mut quot = vec![Rational::zero(); rem.len().saturating_sub(d_deg)];
+	while rem.len() > d_deg
+	{
+		let top = rem.len() - 1;
+		let q = rem[top].checked_div(d[d_deg])?;
+		for i in 0 ..= d_deg {
+			rem[top - d_deg + i] = rem[top - d_deg + i].checked_sub(q.checked_mul(d[i])?)?;
+		}
+		quot[top - d_deg] = q;
+		rem = rp_trim(rem);
+	}
+	Some( (rp_trim(quot), rem) )
+}
+fn rp_deriv(a: &RatPoly) -> Option<RatPoly>
+{
+	Some(rp_trim( a.iter().enumerate().skip(1).map(|(i,c)| c.checked_mul(Rational::from_int(i as i128))).collect::<Option<_>>()? ))
+}
+/// Monic greatest common divisor
+fn rp_gcd(a: &RatPoly, b: &RatPoly) -> Option<RatPoly>
+{
+	let (mut a, mut b) = (a.clone(), b.clone());
+	while !b.is_empty()
+	{
+		let r = rp_divrem(&a, &b)?.1;
+		a = b;
+		b = r;
+	}
+	match a.last()
+	{
+	Some(&lead) => rp_scale(&a, lead.recip()?),
+	None => Some(a),
+	}
+}
+fn rp_eval(a: &RatPoly, x: Rational) -> Option<Rational>
+{
+	let mut rv = Rational::zero();
+	for c in a.iter().rev() {
+		rv = rv.checked_mul(x)?.checked_add(*c)?;
+	}
+	Some(rv)
+}
+/// Scale to coprime integer coefficients with a positive leading coefficient
+fn rp_primitive(a: &RatPoly) -> Option<RatPoly>
+{
+	let (mut num_gcd, mut den_lcm) = (0, 1i128);
+	for c in a.iter()
+	{
+		num_gcd = crate::rational::gcd(num_gcd, c.num());
+		den_lcm = (den_lcm / crate::rational::gcd(den_lcm, c.den())).checked_mul(c.den())?;
+	}
+	match a.last()
+	{
+	Some(lead) if num_gcd != 0 => rp_scale(a, Rational::new(if lead.is_negative() { -den_lcm } else { den_lcm }, num_gcd)),
+	_ => Some(a.clone()),
+	}
+}
+fn rp_to_expr(a: &RatPoly, var: &str) -> Expression
+{
+	Polynomial { var: var.to_owned(), coeffs: a.iter().map(|c| c.to_expr()).collect() }.to_expr()
+}
+
+/// Interpret an expression as a ratio of polynomials in `var` with rational coefficients (`None` if not, or on overflow)
+fn rational_function(e: &Expression, var: &str) -> Option<(RatPoly, RatPoly)>
+{
+	let one = || vec![Rational::one()];
+	if let Some(c) = Rational::from_expr(e) {
+		return Some( (rp_trim(vec![c]), one()) );
+	}
+	match e
+	{
+	Expression::Literal(_) => None,
+	Expression::Variable(ref n) => if n == var { Some( (vec![Rational::zero(), Rational::one()], one()) ) } else { None },
+	Expression::Negative(ref v) => {
+		let (n, d) = rational_function(v, var)?;
+		Some( (rp_scale(&n, -Rational::one())?, d) )
+		},
+	Expression::SubNode(ref sn) =>
+		match sn.operation
+		{
+		Op::Equality => None,
+		Op::AddSub => {
+			let (mut num, mut den) = (vec![], one());
+			for v in sn.values.iter()
+			{
+				let (n, d) = rational_function(&v.val, var)?;
+				let n = if v.inverse { rp_scale(&n, -Rational::one())? } else { n };
+				num = rp_add(&rp_mul(&num, &d)?, &rp_mul(&n, &den)?)?;
+				den = rp_mul(&den, &d)?;
+			}
+			Some( (num, den) )
+			},
+		Op::MulDiv => {
+			let (mut num, mut den) = (one(), one());
+			for v in sn.values.iter()
+			{
+				let (n, d) = rational_function(&v.val, var)?;
+				if v.inverse {
+					if n.is_empty() {
+						return None;
+					}
+					num = rp_mul(&num, &d)?;
+					den = rp_mul(&den, &n)?;
+				}
+				else {
+					num = rp_mul(&num, &n)?;
+					den = rp_mul(&den, &d)?;
+				}
+			}
+			Some( (num, den) )
+			},
+		Op::ExpRoot => {
+			let (base, exp) = crate::manip::split_power(sn);
+			let exp = Rational::from_expr(&exp)?;
+			if !exp.is_integer() || exp.num().abs() > 64 {
+				return None;
+			}
+			let (n, d) = rational_function(&base, var)?;
+			let (mut num, mut den) = (one(), one());
+			for _ in 0 .. exp.num().abs() {
+				num = rp_mul(&num, &n)?;
+				den = rp_mul(&den, &d)?;
+			}
+			if exp.is_negative() {
+				if num.is_empty() {
+					return None;
+				}
+				Some( (den, num) )
+			}
+			else {
+				Some( (num, den) )
+			}
+			},
+		},
+	}
+}
+
+/// Find all rational roots (using the rational root theorem), `None` on overflow
+fn rational_roots(p: &RatPoly) -> Option<Vec<Rational>>
+{
+	fn divisors(v: i128) -> Vec<i128> {
+		let v = v.abs();
+		let mut rv = vec![];
+		let mut i = 1;
+		// Give up on huge coefficients rather than taking forever
+		while i * i <= v && i < 1_000_000
+		{
+			if v % i == 0 {
+				rv.push(i);
+				rv.push(v / i);
+			}
+			i += 1;
+		}
+		rv
+	}
+	let p = rp_primitive(p)?;
+	let mut rv = vec![];
+	// Zero roots first, then candidates `±a/b` where `a` divides the lowest non-zero coefficient and `b` the leading one
+	let low = match p.iter().position(|c| !c.is_zero()) { Some(v) => v, None => return Some(rv) };
+	if low > 0 {
+		rv.push(Rational::zero());
+	}
+	for a in divisors(p[low].num())
+	{
+		for b in divisors(p.last().unwrap().num())
+		{
+			for &s in [1, -1].iter()
+			{
+				let r = Rational::new(s * a, b);
+				if !rv.contains(&r) && rp_eval(&p, r)?.is_zero() {
+					rv.push(r);
+				}
+			}
+		}
+	}
+	Some(rv)
+}
+
+/// Split a square-free polynomial with no rational roots into rational quadratics
+///
+/// Pairs of (numerically found) roots are tried until their quadratic divides exactly. Returns `None` if that fails (or on
+/// overflow).
+fn quadratic_factors(p: &RatPoly) -> Option<Vec<RatPoly>>
+{
+	fn approx_rational(v: f64) -> Option<Rational> {
+		if v.abs() < 1e-9 { Some(Rational::zero()) } else { Rational::from_f32(v as f32) }
+	}
+	let mut rest = p.clone();
+	let mut roots = complex_roots(p);
+	let mut rv = vec![];
+	while rest.len() > 1
+	{
+		let z = roots.remove(0);
+		let mut found = None;
+		for (i,w) in roots.iter().enumerate()
+		{
+			let sum = approx_rational(z.0 + w.0);
+			let prod = approx_rational(z.0 * w.0 - z.1 * w.1);
+			if let (Some(sum), Some(prod)) = (sum, prod)
+			{
+				let q = rp_primitive(&vec![prod, sum.checked_neg()?, Rational::one()])?;
+				let (d, r) = rp_divrem(&rest, &q)?;
+				if r.is_empty() {
+					rest = d;
+					found = Some( (i, q) );
+					break;
+				}
+			}
+		}
+		let (i, q) = found?;
+		roots.remove(i);
+		rv.push(q);
+	}
+	Some(rv)
+}
+/// Approximate all complex roots (as `(re, im)` pairs) using the Durand-Kerner method
+fn complex_roots(p: &RatPoly) -> Vec<(f64, f64)>
+{
+	type C = (f64, f64);
+	fn mul(a: C, b: C) -> C { (a.0*b.0 - a.1*b.1, a.0*b.1 + a.1*b.0) }
+	fn sub(a: C, b: C) -> C { (a.0 - b.0, a.1 - b.1) }
+	fn div(a: C, b: C) -> C { let m = b.0*b.0 + b.1*b.1; ((a.0*b.0 + a.1*b.1) / m, (a.1*b.0 - a.0*b.1) / m) }
+
+	let lead = p.last().unwrap().to_f64();
+	let coeffs: Vec<f64> = p.iter().map(|c| c.to_f64() / lead).collect();
+	let n = coeffs.len() - 1;
+	let eval = |z: C| coeffs.iter().rev().fold((0.0, 0.0), |acc, &c| { let v = mul(acc, z); (v.0 + c, v.1) });
+	let mut roots: Vec<C> = vec![];
+	let mut z = (1.0, 0.0);
+	for _ in 0 .. n {
+		roots.push(z);
+		z = mul(z, (0.4, 0.9));
+	}
+	for _ in 0 .. 1000
+	{
+		for i in 0 .. n
+		{
+			let mut d = (1.0, 0.0);
+			for j in 0 .. n {
+				if i != j {
+					d = mul(d, sub(roots[i], roots[j]));
+				}
+			}
+			roots[i] = sub(roots[i], div(eval(roots[i]), d));
+		}
+	}
+	roots
+}
+
+/// Solve a square linear system over the rationals (`None` if singular, or on overflow)
+fn solve_linear(mut m: Vec<Vec<Rational>>, mut rhs: Vec<Rational>) -> Option<Vec<Rational>>
+{
+	let n = rhs.len();
+	for col in 0 .. n
+	{
+		let pivot = (col .. n).find(|&r| !m[r][col].is_zero())?;
+		m.swap(col, pivot);
+		rhs.swap(col, pivot);
+		for r in 0 .. n
+		{
+			if r != col && !m[r][col].is_zero()
+			{
+				let f = m[r][col].checked_div(m[col][col])?;
+				for c in col .. n {
+					m[r][c] = m[r][c].checked_sub(f.checked_mul(m[col][c])?)?;
+				}
+				rhs[r] = rhs[r].checked_sub(f.checked_mul(rhs[col])?)?;
+			}
+		}
+	}
+	(0 .. n).map(|i| rhs[i].checked_div(m[i][i])).collect()
+}
+
+/// Decompose a rational function of `var` into partial fractions
+///
+/// The denominator is factored over the rationals into linear factors and irreducible quadratics (possibly repeated),
+/// and the result is a flat sum of the polynomial part and one fraction per power of each factor. Returns `None` if the
+/// expression can't be decomposed, or if the coefficients become too large to handle exactly.
+pub fn partial_fractions(e: &Expression, var: &str) -> Option<Expression>
+{
+	let (num, den) = rational_function(e, var)?;
+	if den.is_empty() {
+		return None;
+	}
+	// Cancel any common factors first
+	let g = rp_gcd(&num, &den)?;
+	let (num, den) = (rp_divrem(&num, &g)?.0, rp_divrem(&den, &g)?.0);
+	if den.len() < 2 {
+		return None;
+	}
+	let (quot, rem) = rp_divrem(&num, &den)?;
+
+	// Factor the denominator into (primitive factor, multiplicity) pairs
+	let mut factors: Vec<(RatPoly, usize)> = vec![];
+	let mut rest = den.clone();
+	for r in rational_roots(&den)?
+	{
+		let f = rp_primitive(&vec![r.checked_neg()?, Rational::one()])?;
+		let mut k = 0;
+		while rest.len() > 1 && rp_eval(&rest, r)?.is_zero() {
+			rest = rp_divrem(&rest, &f)?.0;
+			k += 1;
+		}
+		factors.push( (f, k) );
+	}
+	if rest.len() > 1
+	{
+		let square_free = rp_divrem(&rest, &rp_gcd(&rest, &rp_deriv(&rest)?)?)?.0;
+		for q in quadratic_factors(&square_free)?
+		{
+			let mut k = 0;
+			loop
+			{
+				let (d, r) = rp_divrem(&rest, &q)?;
+				if !r.is_empty() {
+					break;
+				}
+				rest = d;
+				k += 1;
+			}
+			factors.push( (q, k) );
+		}
+		if rest.len() > 1 {
+			return None;
+		}
+	}
+
+	// Each power of each factor gets a numerator with unknown coefficients (`A` for linear, `B*x + C` for quadratic),
+	// which are found by equating coefficients of `rem = sum(numerator * den/factor^power)`
+	let mut basis = vec![];
+	let mut shape = vec![];
+	for (i,(f,k)) in factors.iter().enumerate()
+	{
+		let mut f_pow = vec![Rational::one()];
+		for j in 1 ..= *k
+		{
+			f_pow = rp_mul(&f_pow, f)?;
+			let b = rp_divrem(&den, &f_pow)?.0;
+			if f.len() == 3 {
+				basis.push(rp_mul(&b, &vec![Rational::zero(), Rational::one()])?);
+				shape.push( (i, j, 1) );
+			}
+			basis.push(b);
+			shape.push( (i, j, 0) );
+		}
+	}
+	let n = den.len() - 1;
+	if basis.len() != n {
+		return None;
+	}
+	let get = |p: &RatPoly, i: usize| *p.get(i).unwrap_or(&Rational::zero());
+	let matrix = (0 .. n).map(|row| basis.iter().map(|b| get(b, row)).collect()).collect();
+	let values = solve_linear(matrix, (0 .. n).map(|row| get(&rem, row)).collect())?;
+
+	let mut terms = vec![];
+	match rp_to_expr(&quot, var)
+	{
+	Expression::SubNode(sn) if sn.operation == Op::AddSub => terms.extend(sn.values),
+	Expression::Negative(v) => terms.push(SubExpression { inverse: true, val: *v }),
+	v => if !quot.is_empty() { terms.push(SubExpression { inverse: false, val: v }) },
+	}
+	for (i,(f,k)) in factors.iter().enumerate()
+	{
+		for j in 1 ..= *k
+		{
+			let mut numerator = vec![Rational::zero(); 2];
+			for (s,v) in shape.iter().zip(values.iter()) {
+				if s.0 == i && s.1 == j {
+					numerator[s.2] = *v;
+				}
+			}
+			let numerator = rp_trim(numerator);
+			let inverse = match numerator.last()
+				{
+				Some(lead) => lead.is_negative(),
+				None => continue,
+				};
+			let numerator = if inverse { rp_scale(&numerator, -Rational::one())? } else { numerator };
+			let f = rp_to_expr(f, var);
+			let f = if j == 1 { f } else { make_node(Op::ExpRoot, vec![
+				SubExpression { inverse: false, val: f },
+				SubExpression { inverse: false, val: Expression::Literal(j as f32) },
+				]) };
+			terms.push(SubExpression { inverse: inverse, val: make_node(Op::MulDiv, vec![
+				SubExpression { inverse: false, val: rp_to_expr(&numerator, var) },
+				SubExpression { inverse: true, val: f },
+				]) });
+		}
+	}
+	Some(make_node(Op::AddSub, terms))
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn poly(s: &str) -> Polynomial {
+		Polynomial::from_expr(&s.parse().unwrap(), "x").unwrap()
+	}
+
+	#[test]
+	fn coefficients() {
+		let p = poly("3*x^2 - (x - 1)*2 + 5");
+		assert_eq!(p.degree(), Some(2));
+		assert_eq!(p.coeffs.iter().map(|c| c.to_string()).collect::<Vec<_>>(), vec!["7", "-2", "3"]);
+		assert_eq!(poly("0*x^3").degree(), None);
+		assert!(Polynomial::from_expr(&"1/x".parse().unwrap(), "x").is_none());
+		assert!(Polynomial::from_expr(&"x^(1/2)".parse().unwrap(), "x").is_none());
+	}
+	#[test]
+	fn symbolic_coefficients_cancel() {
+		let p = poly("x^2*y - y*x^2 + x");
+		assert_eq!(p.degree(), Some(1));
+		assert_eq!(p.to_expr().to_string(), "x");
+		let p = poly("(x+a)*(x-a)");
+		assert_eq!(p.degree(), Some(2));
+		assert!(is_zero(&p.coeff(1)), "{}", p.coeff(1));
+		assert_eq!(p.coeff(0).to_string(), "-(a^2)");
+	}
+	#[test]
+	fn quadratics() {
+		let e = |s: &str| -> Expression { s.parse().unwrap() };
+		assert_eq!(factor_quadratic(&e("x^2 - 5*x + 6"), "x").map(|v| v.to_string()), Some("(x-3)*(x-2)".to_owned()));
+		assert_eq!(complete_square(&e("2*x^2 + 4*x + 1"), "x").map(|v| v.to_string()), Some("2*(x+1)^2-1".to_owned()));
+		assert_eq!(complete_square(&e("x^2 + 2*b*x + c"), "x").map(|v| v.to_string()), Some("(x+b)^2+c-b^2".to_owned()));
+		assert_eq!(partial_fractions(&e("(3*x+1)/((x-1)*(x+2))"), "x").map(|v| v.to_string()), Some("(4/3)/(x-1)+(5/3)/(x+2)".to_owned()));
+	}
+	#[test]
+	fn quadratic_factoring() {
+		let factor = |s: &str| factor_quadratic(&s.parse().unwrap(), "x").map(|v| v.to_string());
+		// Leading coefficient and rational roots
+		assert_eq!(factor("2*x^2 - x - 1"), Some("(x-1)*(2*x+1)".to_owned()));
+		assert_eq!(factor("3*x^2 - 6*x + 3"), Some("3*(x-1)^2".to_owned()));
+		// Irrational roots
+		assert_eq!(factor("x^2 - 2"), Some("(x-2^0.5)*(x+2^0.5)".to_owned()));
+		// Symbolic coefficients use the quadratic formula
+		assert!(factor("x^2 + b*x + c").is_some());
+		// No real roots, or not a quadratic
+		assert_eq!(factor("x^2 + 1"), None);
+		assert_eq!(factor("x^3 + x"), None);
+		assert_eq!(factor("x + 1"), None);
+	}
+	#[test]
+	fn square_completion() {
+		let complete = |s: &str| complete_square(&s.parse().unwrap(), "x").map(|v| v.to_string());
+		assert_eq!(complete("x^2 + 4*x + 1"), Some("(x+2)^2-3".to_owned()));
+		assert_eq!(complete("2*x^2 - 4*x"), Some("2*(x-1)^2-2".to_owned()));
+		assert_eq!(complete("x^2"), Some("x^2".to_owned()));
+		assert_eq!(complete("y^2 + x"), None);
+	}
+	#[test]
+	fn partial_fraction_forms() {
+		let pf = |s: &str| partial_fractions(&s.parse().unwrap(), "x");
+		let text = |s: &str| pf(s).map(|v| v.to_string());
+		// Repeated linear factor
+		assert_eq!(text("1/(x^2*(x+1))"), Some("-(1/x)+1/x^2+1/(x+1)".to_owned()));
+		// Irreducible quadratic
+		assert_eq!(text("1/(x^3 + x)"), Some("1/x-x/(x^2+1)".to_owned()));
+		// Polynomial part
+		assert_eq!(text("x^2/(x-1)"), Some("x+1+1/(x-1)".to_owned()));
+		// The result is a flat sum
+		match pf("(3*x+1)/((x-1)*(x+2))")
+		{
+		Some(Expression::SubNode(crate::expression::ExprNode { operation: Op::AddSub, ref values })) => assert_eq!(values.len(), 2),
+		e => panic!("{:?}", e),
+		}
+		// Nothing to decompose
+		assert_eq!(text("(x+1)/(x+1)"), None);
+		assert_eq!(text("x^2 + 1"), None);
+		assert_eq!(text("1/(x + y)"), None);
+		// Too large to handle exactly (instead of panicking)
+		assert_eq!(text("1/(999*x+1)^20"), None);
+		assert_eq!(text("1/((999999999*x+1)^3*(x-1))"), None);
+	}
+	#[test]
+	fn large_coefficients() {
+		// Too large for exact coefficients, but still handled (without panicking)
+		let p = poly("1000000000^5*x + 1");
+		assert_eq!(p.degree(), Some(1));
+	}
+}
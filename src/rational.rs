@@ -0,0 +1,310 @@
+//!
+//! Exact rational numbers (used when folding and manipulating literals)
+//!
+
+use crate::expression::{Expression, SubExpression, ExprNode, Op};
+
+/// Always stored in lowest terms with a positive denominator
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Hash)]
+pub struct Rational
+{
+	num: i128,
+	den: i128,
+}
+
+pub fn gcd(a: i128, b: i128) -> i128
+{
+	let (mut a, mut b) = (a.abs(), b.abs());
+	while b != 0
+	{
+		let t = a % b;
+		a = b;
+		b = t;
+	}
+	a
+}
+
+impl Rational
+{
+	pub fn new(num: i128, den: i128) -> Rational
+	{
+		assert!(den != 0, "Rational with zero denominator");
+		let g = gcd(num, den);
+		let g = if g == 0 { 1 } else { g };
+		let s = if den < 0 { -1 } else { 1 };
+		Rational { num: s * num / g, den: s * den / g }
+	}
+	pub fn from_int(v: i128) -> Rational {
+		Rational { num: v, den: 1 }
+	}
+	pub fn zero() -> Rational {
+		Rational::from_int(0)
+	}
+	pub fn one() -> Rational {
+		Rational::from_int(1)
+	}
+
+	/// Convert a literal into an exact rational (only if it's close to a fraction with a small denominator)
+	pub fn from_f32(v: f32) -> Option<Rational>
+	{
+		const MAX_DEN: i128 = 1000;
+		if !v.is_finite() || v.abs() > 1e9 {
+			return None;
+		}
+		let v = v as f64;
+		for den in 1 .. MAX_DEN+1
+		{
+			let n = (v * den as f64).round();
+			// Accept anything within the precision of the original literal
+			if (n / den as f64 - v).abs() <= f32::EPSILON as f64 * v.abs() {
+				return Some(Rational::new(n as i128, den));
+			}
+		}
+		None
+	}
+	/// Closest fraction to a value with the numerator and denominator no larger than `max` (using continued fractions)
+	pub fn approximate(v: f64, max: i128) -> Option<Rational>
+	{
+		if !v.is_finite() || v.abs() > max as f64 {
+			return None;
+		}
+		let mut x = v.abs();
+		// Previous two convergents
+		let (mut h0, mut h1) = (0i128, 1i128);
+		let (mut k0, mut k1) = (1i128, 0i128);
+		for _ in 0 .. 64
+		{
+			let a = x.floor();
+			let (h2, k2) = (a as i128 * h1 + h0, a as i128 * k1 + k0);
+			if h2 > max || k2 > max {
+				break;
+			}
+			h0 = h1; h1 = h2;
+			k0 = k1; k1 = k2;
+			let frac = x - a;
+			if frac == 0.0 || h1 as f64 / k1 as f64 == v.abs() {
+				break;
+			}
+			x = 1.0 / frac;
+		}
+		if k1 == 0 {
+			return None;
+		}
+		Some(Rational::new(if v < 0.0 { -h1 } else { h1 }, k1))
+	}
+	/// Get the rational value of a literal expression (including negated literals and literal fractions)
+	pub fn from_expr(e: &Expression) -> Option<Rational>
+	{
+		match e
+		{
+		Expression::Literal(v) => Rational::from_f32(*v),
+		Expression::Negative(ref v) => Rational::from_expr(v).map(|v| -v),
+		Expression::SubNode(ref sn) if sn.operation == Op::MulDiv || sn.operation == Op::AddSub => {
+			let mut rv = if sn.operation == Op::MulDiv { Rational::one() } else { Rational::zero() };
+			for v in sn.values.iter()
+			{
+				let x = Rational::from_expr(&v.val)?;
+				rv = match (sn.operation, v.inverse)
+					{
+					(Op::MulDiv, false) => rv.checked_mul(x)?,
+					(Op::MulDiv, true) => rv.checked_div(x)?,
+					(_, false) => rv.checked_add(x)?,
+					(_, true) => rv.checked_sub(x)?,
+					};
+			}
+			Some(rv)
+			},
+		Expression::SubNode(ref sn) if sn.operation == Op::ExpRoot && sn.values.len() == 2 => {
+			let base = Rational::from_expr(&sn.values[0].val)?;
+			let exp = Rational::from_expr(&sn.values[1].val)?;
+			if exp.is_integer() && exp.num.abs() <= 64 { base.checked_pow(exp.num as i32) } else { None }
+			},
+		_ => None,
+		}
+	}
+
+	pub fn num(&self) -> i128 {
+		self.num
+	}
+	pub fn den(&self) -> i128 {
+		self.den
+	}
+	pub fn is_zero(&self) -> bool {
+		self.num == 0
+	}
+	pub fn is_one(&self) -> bool {
+		self.num == 1 && self.den == 1
+	}
+	pub fn is_integer(&self) -> bool {
+		self.den == 1
+	}
+	pub fn is_negative(&self) -> bool {
+		self.num < 0
+	}
+	pub fn abs(&self) -> Rational {
+		Rational { num: self.num.abs(), den: self.den }
+	}
+	pub fn recip(&self) -> Option<Rational> {
+		if self.num == 0 { None } else { Some(Rational::new(self.den, self.num)) }
+	}
+	pub fn checked_add(self, o: Rational) -> Option<Rational> {
+		let num = self.num.checked_mul(o.den)?.checked_add(o.num.checked_mul(self.den)?)?;
+		Some(Rational::new(num, self.den.checked_mul(o.den)?))
+	}
+	pub fn checked_sub(self, o: Rational) -> Option<Rational> {
+		self.checked_add(o.checked_neg()?)
+	}
+	pub fn checked_mul(self, o: Rational) -> Option<Rational> {
+		// Cancel common factors first, so only results that really don't fit fail
+		let g1 = gcd(self.num, o.den).max(1);
+		let g2 = gcd(o.num, self.den).max(1);
+		let num = (self.num / g1).checked_mul(o.num / g2)?;
+		let den = (self.den / g2).checked_mul(o.den / g1)?;
+		Some(Rational::new(num, den))
+	}
+	pub fn checked_div(self, o: Rational) -> Option<Rational> {
+		self.checked_mul(o.recip()?)
+	}
+	pub fn checked_neg(self) -> Option<Rational> {
+		Some(Rational { num: self.num.checked_neg()?, den: self.den })
+	}
+	pub fn checked_pow(self, e: i32) -> Option<Rational> {
+		let b = if e < 0 { self.recip()? } else { self };
+		let (mut num, mut den) = (1i128, 1i128);
+		for _ in 0 .. e.abs() {
+			num = num.checked_mul(b.num)?;
+			den = den.checked_mul(b.den)?;
+		}
+		Some(Rational::new(num, den))
+	}
+	/// Exact square root (if one exists)
+	pub fn sqrt(&self) -> Option<Rational> {
+		fn isqrt(v: i128) -> Option<i128> {
+			if v < 0 {
+				return None;
+			}
+			let r = (v as f64).sqrt().round() as i128;
+			(r-1 ..= r+1).find(|&x| x >= 0 && x*x == v)
+		}
+		Some(Rational::new(isqrt(self.num)?, isqrt(self.den)?))
+	}
+	pub fn to_f64(&self) -> f64 {
+		self.num as f64 / self.den as f64
+	}
+
+	/// Convert to an expression (a literal where exactly representable as a short decimal, otherwise a fraction)
+	pub fn to_expr(&self) -> Expression
+	{
+		let abs = self.abs();
+		// Denominators with only factors of 2 and 5 are exact decimals
+		let mut d = abs.den;
+		while d % 2 == 0 { d /= 2; }
+		while d % 5 == 0 { d /= 5; }
+		let v = if d == 1 && abs.den <= 1000 {
+				Expression::Literal(abs.to_f64() as f32)
+			}
+			else {
+				Expression::SubNode(ExprNode {
+					operation: Op::MulDiv,
+					values: vec![
+						SubExpression { inverse: false, val: Expression::Literal(abs.num as f32) },
+						SubExpression { inverse: true, val: Expression::Literal(abs.den as f32) },
+						],
+					})
+			};
+		if self.num < 0 {
+			Expression::Negative(Box::new(v))
+		}
+		else {
+			v
+		}
+	}
+}
+
+impl std::fmt::Display for Rational
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		if self.den == 1 {
+			write!(f, "{}", self.num)
+		}
+		else {
+			write!(f, "{}/{}", self.num, self.den)
+		}
+	}
+}
+
+// NOTE: The operators panic on overflow, use the `checked_*` methods for values from user input
+impl std::ops::Add for Rational
+{
+	type Output = Rational;
+	fn add(self, o: Rational) -> Rational {
+		self.checked_add(o).expect("Rational overflow")
+	}
+}
+impl std::ops::Sub for Rational
+{
+	type Output = Rational;
+	fn sub(self, o: Rational) -> Rational {
+		self.checked_sub(o).expect("Rational overflow")
+	}
+}
+impl std::ops::Mul for Rational
+{
+	type Output = Rational;
+	fn mul(self, o: Rational) -> Rational {
+		self.checked_mul(o).expect("Rational overflow")
+	}
+}
+impl std::ops::Neg for Rational
+{
+	type Output = Rational;
+	fn neg(self) -> Rational {
+		Rational { num: -self.num, den: self.den }
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn arithmetic() {
+		let (a, b) = (Rational::new(1, 3), Rational::new(-1, 6));
+		assert_eq!(a + b, Rational::new(1, 6));
+		assert_eq!(a - b, Rational::new(1, 2));
+		assert_eq!(a * b, Rational::new(-1, 18));
+		assert_eq!(a.checked_div(b), Some(Rational::from_int(-2)));
+		assert_eq!(a.checked_div(Rational::zero()), None);
+		assert_eq!(Rational::new(2, -4), Rational::new(-1, 2));
+		assert_eq!(Rational::new(2, 3).checked_pow(-2), Some(Rational::new(9, 4)));
+		assert_eq!(Rational::new(9, 4).sqrt(), Some(Rational::new(3, 2)));
+		assert_eq!(Rational::new(2, 1).sqrt(), None);
+	}
+	#[test]
+	fn overflow() {
+		let big = Rational::from_int(i128::max_value() / 2 + 1);
+		assert_eq!(big.checked_add(big), None);
+		assert_eq!(big.checked_mul(Rational::from_int(2)), None);
+		assert_eq!(Rational::from_int(i128::min_value()).checked_neg(), None);
+		// Common factors cancel before multiplying
+		assert_eq!(big.checked_mul(Rational::new(1, 2)), Some(Rational::from_int(i128::max_value() / 4 + 1)));
+		assert_eq!(Rational::from_int(10).checked_pow(40), None);
+	}
+	#[test]
+	fn literals() {
+		assert_eq!(Rational::from_f32(0.25), Some(Rational::new(1, 4)));
+		assert_eq!(Rational::from_f32(1.0 / 3.0), Some(Rational::new(1, 3)));
+		assert_eq!(Rational::from_f32(0.00001), None);
+		assert_eq!(Rational::from_expr(&"2^(-3)".parse().unwrap()), Some(Rational::new(1, 8)));
+		assert_eq!(Rational::from_expr(&"1/3 - 1/2".parse().unwrap()), Some(Rational::new(-1, 6)));
+		assert_eq!(Rational::from_expr(&"2^100".parse().unwrap()), None);
+		assert_eq!(Rational::new(1, 3).to_expr().to_string(), "1/3");
+		assert_eq!(Rational::approximate(-0.75, 100), Some(Rational::new(-3, 4)));
+		assert_eq!(Rational::approximate(std::f64::consts::PI, 1000), Some(Rational::new(355, 113)));
+		let r = Rational::approximate(0.7390851332151607, 1 << 24).unwrap();
+		assert!((r.to_f64() - 0.7390851332151607).abs() < 1e-13);
+		assert_eq!(Rational::approximate(1e10, 1000), None);
+		assert_eq!(Rational::new(-5, 2).to_expr().to_string(), "-2.5");
+	}
+}
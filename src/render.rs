@@ -0,0 +1,409 @@
+//!
+//! Expression tree walker shared by the text, LaTeX, MathML and curses renderers
+//!
+
+use crate::expression::{Expression, ExprNode, Op};
+use crate::ui_helpers::Selection;
+
+/// Kind of node being entered or exited
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Node
+{
+	Negative,
+	Op(Op),
+}
+
+/// Structured forms supported by a renderer (everything else is written with plain operators)
+#[derive(Debug,Copy,Clone,Default)]
+pub struct Features
+{
+	/// Divisions are written as a single fraction (see `Renderer::fraction_start`)
+	pub fractions: bool,
+	/// Exponents are visibly grouped (e.g. `^{...}`), so never need brackets
+	pub grouped_exponents: bool,
+}
+
+/// Receives the pieces of an expression from `walk`
+pub trait Renderer
+{
+	fn features(&self) -> Features {
+		Features::default()
+	}
+
+	/// Start of a node (inside any brackets around it)
+	fn node_enter(&mut self, _node: Node) {}
+	fn node_exit(&mut self, _node: Node) {}
+	/// Operator between two values of a node (`inverse` for subtraction and division), `next` is the following value
+	fn operator(&mut self, op: Op, inverse: bool, next: &Expression);
+	/// Unary minus
+	fn negate(&mut self);
+	fn literal(&mut self, v: f32);
+	fn variable(&mut self, name: &str);
+	fn open_paren(&mut self);
+	fn close_paren(&mut self);
+	/// Innermost brackets enclosing the selection (plain brackets by default)
+	fn open_parent_paren(&mut self) {
+		self.open_paren()
+	}
+	fn close_parent_paren(&mut self) {
+		self.close_paren()
+	}
+	/// Bounds of the selection (called around each selected value when the values are re-ordered into a fraction)
+	fn selection_start(&mut self) {}
+	fn selection_end(&mut self) {}
+
+	/// Around a power, with `power_exponent` between the base and the exponent
+	fn power_start(&mut self) {}
+	fn power_exponent(&mut self, exp: &Expression) {
+		self.operator(Op::ExpRoot, false, exp)
+	}
+	fn power_end(&mut self) {}
+	/// Check if an exponent can be written in a compact form by `superscript` (e.g. `²`)
+	fn can_superscript(&self, _exp: &Expression) -> bool {
+		false
+	}
+	fn superscript(&mut self, _exp: &Expression) {}
+	/// Root to use in place of an exponent (e.g. 2 for `1/2`), if any
+	fn root_degree(&self, _exp: &Expression) -> Option<u32> {
+		None
+	}
+	/// Check if the radicand of a root must be bracketed
+	fn root_parens(&self, _radicand: &Expression) -> bool {
+		false
+	}
+	/// Around the radicand of a root (including any brackets from `root_parens`)
+	fn root_start(&mut self, _degree: u32, _radicand: &Expression) {}
+	fn root_end(&mut self, _degree: u32, _radicand: &Expression) {}
+	/// Parts of a fraction (only with `Features::fractions`)
+	fn fraction_start(&mut self) {}
+	fn fraction_middle(&mut self) {}
+	fn fraction_end(&mut self) {}
+}
+
+/// Pass each part of an expression to a renderer, with selection callbacks if `sel` is given
+pub fn walk<R: Renderer>(r: &mut R, e: &Expression, sel: Option<&Selection>)
+{
+	let empty = Selection::new();
+	// Find the innermost brackets around the selection first (as they're written before any inner ones)
+	let emphasis = match sel
+		{
+		Some(sel) => {
+			let mut p = Probe(&*r);
+			let mut w = Walker { features: r.features(), r: &mut p, sel: sel, emphasis: None, deepest_parens: None };
+			w.expr(e, 0);
+			w.deepest_parens
+			},
+		None => None,
+		};
+	let mut w = Walker { features: r.features(), r: r, sel: sel.unwrap_or(&empty), emphasis: emphasis, deepest_parens: None };
+	w.expr(e, if sel.is_some() { 0 } else { !0 });
+}
+/// Render a single node (with no selection)
+pub fn walk_node<R: Renderer>(r: &mut R, sn: &ExprNode)
+{
+	let empty = Selection::new();
+	let mut w = Walker { features: r.features(), r: r, sel: &empty, emphasis: None, deepest_parens: None };
+	w.node(sn, !0);
+}
+
+/// Renderer that only answers layout queries (for a first pass over the expression)
+struct Probe<'a, R: Renderer>(&'a R);
+impl<R: Renderer> Renderer for Probe<'_, R>
+{
+	fn features(&self) -> Features {
+		self.0.features()
+	}
+	fn operator(&mut self, _op: Op, _inverse: bool, _next: &Expression) {}
+	fn negate(&mut self) {}
+	fn literal(&mut self, _v: f32) {}
+	fn variable(&mut self, _name: &str) {}
+	fn open_paren(&mut self) {}
+	fn close_paren(&mut self) {}
+	fn power_exponent(&mut self, _exp: &Expression) {}
+	fn can_superscript(&self, exp: &Expression) -> bool {
+		self.0.can_superscript(exp)
+	}
+	fn root_degree(&self, exp: &Expression) -> Option<u32> {
+		self.0.root_degree(exp)
+	}
+	fn root_parens(&self, radicand: &Expression) -> bool {
+		self.0.root_parens(radicand)
+	}
+}
+
+struct Walker<'a, R: Renderer>
+{
+	r: &'a mut R,
+	sel: &'a Selection,
+	features: Features,
+	/// Path position of the brackets to draw with `open_parent_paren`
+	emphasis: Option<usize>,
+	/// Deepest path position of a bracketed value (i.e. the innermost brackets enclosing the selection)
+	deepest_parens: Option<usize>,
+}
+impl<R: Renderer> Walker<'_, R>
+{
+	// NOTE: `path_pos` is the depth within the selection path, or `!0` when off the path
+
+	/// Selection path position for the `i`th child
+	fn child_pos(&self, path_pos: usize, i: usize) -> usize {
+		if path_pos < self.sel.path.len() && self.sel.path[path_pos] == i { path_pos + 1 } else { !0 }
+	}
+	/// Is the selection a range of this node's values?
+	fn at_sel(&self, path_pos: usize) -> bool {
+		path_pos == self.sel.path.len()
+	}
+
+	fn expr(&mut self, e: &Expression, path_pos: usize)
+	{
+		match e
+		{
+		Expression::Negative(ref v) => {
+			self.r.node_enter(Node::Negative);
+			self.r.negate();
+			let at_sel = self.at_sel(path_pos);
+			if at_sel {
+				self.r.selection_start();
+			}
+			let parens = match **v
+				{
+				Expression::Literal(_) | Expression::Variable(_) => false,
+				_ => true,
+				};
+			self.value(v, parens, self.child_pos(path_pos, 0));
+			if at_sel {
+				self.r.selection_end();
+			}
+			self.r.node_exit(Node::Negative);
+			},
+		Expression::Literal(v) => self.r.literal(*v),
+		Expression::Variable(ref n) => self.r.variable(n),
+		Expression::SubNode(ref sn) => self.node(sn, path_pos),
+		}
+	}
+	fn value(&mut self, e: &Expression, parens: bool, path_pos: usize)
+	{
+		if parens && path_pos != !0 {
+			self.deepest_parens = Some(path_pos);
+		}
+		let parent = self.emphasis == Some(path_pos);
+		if parens {
+			if parent { self.r.open_parent_paren() } else { self.r.open_paren() }
+		}
+		self.expr(e, path_pos);
+		if parens {
+			if parent { self.r.close_parent_paren() } else { self.r.close_paren() }
+		}
+	}
+	/// The `i`th value of a node, with selection bounds
+	fn child(&mut self, sn: &ExprNode, i: usize, parens: bool, path_pos: usize)
+	{
+		let at_sel = self.at_sel(path_pos);
+		if at_sel && i == self.sel.first {
+			self.r.selection_start();
+		}
+		self.value(&sn.values[i].val, parens, self.child_pos(path_pos, i));
+		if at_sel && i == self.sel.last {
+			self.r.selection_end();
+		}
+	}
+
+	fn node(&mut self, sn: &ExprNode, path_pos: usize)
+	{
+		self.r.node_enter(Node::Op(sn.operation));
+		match sn.operation
+		{
+		Op::MulDiv if self.features.fractions && sn.values.iter().any(|v| v.inverse) => self.fraction(sn, path_pos),
+		Op::ExpRoot => self.power(sn, 0, path_pos),
+		_ => {
+			for (i,v) in sn.values.iter().enumerate()
+			{
+				if i > 0 {
+					self.r.operator(sn.operation, v.inverse, &v.val);
+				}
+				self.child(sn, i, v.val.needs_parens(sn.operation), path_pos);
+			}
+			},
+		}
+		self.r.node_exit(Node::Op(sn.operation));
+	}
+
+	/// Multiplied values over the divided values
+	fn fraction(&mut self, sn: &ExprNode, path_pos: usize)
+	{
+		let mut num: Vec<usize> = (0 .. sn.values.len()).filter(|&i| !sn.values[i].inverse).collect();
+		// `1/6*j` is `j` over `6`
+		if num.len() > 1 && sn.values[num[0]].val == Expression::Literal(1.) {
+			num.remove(0);
+		}
+		let den: Vec<usize> = (0 .. sn.values.len()).filter(|&i| sn.values[i].inverse).collect();
+
+		self.r.fraction_start();
+		if num.is_empty() {
+			self.r.literal(1.);
+		}
+		else {
+			self.factors(sn, &num, path_pos);
+		}
+		self.r.fraction_middle();
+		self.factors(sn, &den, path_pos);
+		self.r.fraction_end();
+	}
+	fn factors(&mut self, sn: &ExprNode, idxs: &[usize], path_pos: usize)
+	{
+		let at_sel = self.at_sel(path_pos);
+		for (j,&i) in idxs.iter().enumerate()
+		{
+			let v = &sn.values[i].val;
+			if j > 0 {
+				self.r.operator(Op::MulDiv, false, v);
+			}
+			let selected = at_sel && self.sel.first <= i && i <= self.sel.last;
+			if selected {
+				self.r.selection_start();
+			}
+			// A lone factor is already grouped by the fraction
+			self.value(v, idxs.len() > 1 && v.needs_parens(Op::MulDiv), self.child_pos(path_pos, i));
+			if selected {
+				self.r.selection_end();
+			}
+		}
+	}
+
+	/// Values `i..` of a power chain (right-associative, so `a^b^c` is `a^(b^c)`)
+	fn power(&mut self, sn: &ExprNode, i: usize, path_pos: usize)
+	{
+		let last = sn.values.len() - 1;
+		let base = &sn.values[i].val;
+		let exp = &sn.values[last].val;
+		// Compact forms are only used if the selection doesn't involve the exponent
+		let exp_selected = self.child_pos(path_pos, last) != !0 || (self.at_sel(path_pos) && self.sel.last == last);
+
+		if i == 0 && last == 1 && !exp_selected {
+			if let Some(n) = self.r.root_degree(exp) {
+				self.r.root_start(n, base);
+				let parens = self.r.root_parens(base);
+				self.child(sn, 0, parens, path_pos);
+				self.r.root_end(n, base);
+				return ;
+			}
+		}
+
+		self.r.power_start();
+		self.child(sn, i, base.needs_parens_as_base(), path_pos);
+		if i + 1 < last {
+			self.r.power_exponent(&sn.values[i+1].val);
+			self.power(sn, i + 1, path_pos);
+		}
+		else if self.child_pos(path_pos, last) == !0 && self.r.can_superscript(exp) {
+			let at_sel = self.at_sel(path_pos);
+			if at_sel && self.sel.first == last {
+				self.r.selection_start();
+			}
+			self.r.superscript(exp);
+			if at_sel && self.sel.last == last {
+				self.r.selection_end();
+			}
+		}
+		else {
+			self.r.power_exponent(exp);
+			self.child(sn, last, !self.features.grouped_exponents && exp.needs_parens(Op::ExpRoot), path_pos);
+		}
+		self.r.power_end();
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use crate::expression::Expression;
+	use crate::ui_helpers::{render_tokens, Selection, Style, TokenClass};
+
+	#[test]
+	fn selection_split() {
+		let split = |s: &str, sel: &str| {
+			let (a, b, c) = crate::ui_helpers::split_expression(&s.parse().unwrap(), &sel.parse().unwrap());
+			format!("{}[{}]{}", a, b, c)
+			};
+		assert_eq!(split("a = b + c*d", "[] 1-1"), "a=[b+c*d]");
+		assert_eq!(split("a = b + c + d", "[1] 0-1"), "a=[b+c]+d");
+		assert_eq!(split("a = b + c*d", "[1, 1] 1-1"), "a=b+c*[d]");
+		// Selections within a fraction stay in one piece
+		assert_eq!(split("a = b/c", "[1] 0-1"), "a=[b/c]");
+	}
+	/// Text with the emphasised brackets marked by `[]`
+	fn emphasised(e: &str, sel: Selection) -> String {
+		render_tokens(&e.parse().unwrap(), Some(&sel), Style::Ascii).iter()
+			.map(|t| match t.class
+				{
+				TokenClass::ParentParen => if t.text == "(" { "[" } else { "]" },
+				_ => &t.text[..],
+				})
+			.collect()
+	}
+
+	#[test]
+	fn enclosing_brackets() {
+		// `(d+e)` itself selected, so the brackets around it are the enclosing ones
+		assert_eq!(emphasised("a*(b+c*(d+e))", Selection { path: vec![1, 1], first: 1, last: 1 }), "a*[b+c*(d+e)]");
+		// `d` selected
+		assert_eq!(emphasised("a*(b+c*(d+e))", Selection { path: vec![1, 1, 1], first: 0, last: 0 }), "a*(b+c*[d+e])");
+		// Directly in the bracketed node
+		assert_eq!(emphasised("a*(b+c)", Selection { path: vec![1], first: 1, last: 1 }), "a*[b+c]");
+		// No brackets enclose the selection
+		assert_eq!(emphasised("a*(b+c)", Selection { path: vec![], first: 0, last: 0 }), "a*(b+c)");
+	}
+	#[test]
+	fn token_classes() {
+		let e: Expression = "a*(2 + b)".parse().unwrap();
+		let classes: Vec<_> = render_tokens(&e, None, Style::Ascii).iter().map(|t| t.class).collect();
+		assert_eq!(classes, vec![
+			TokenClass::Variable, TokenClass::Operator, TokenClass::Paren,
+			TokenClass::Literal, TokenClass::Operator, TokenClass::Variable,
+			TokenClass::Paren,
+			]);
+	}
+
+	/// Records the callbacks from `walk`
+	struct Recorder(Vec<String>);
+	impl super::Renderer for Recorder
+	{
+		fn node_enter(&mut self, node: super::Node) {
+			self.0.push(format!("<{:?}", node));
+		}
+		fn node_exit(&mut self, node: super::Node) {
+			self.0.push(format!("{:?}>", node));
+		}
+		fn operator(&mut self, _op: crate::expression::Op, inverse: bool, _next: &Expression) {
+			self.0.push(if inverse { "inv".to_owned() } else { "op".to_owned() });
+		}
+		fn negate(&mut self) {
+			self.0.push("neg".to_owned());
+		}
+		fn literal(&mut self, v: f32) {
+			self.0.push(v.to_string());
+		}
+		fn variable(&mut self, name: &str) {
+			self.0.push(name.to_owned());
+		}
+		fn open_paren(&mut self) {
+			self.0.push("(".to_owned());
+		}
+		fn close_paren(&mut self) {
+			self.0.push(")".to_owned());
+		}
+		fn selection_start(&mut self) {
+			self.0.push("[".to_owned());
+		}
+		fn selection_end(&mut self) {
+			self.0.push("]".to_owned());
+		}
+	}
+	#[test]
+	fn walker_callbacks() {
+		let e: Expression = "a - -(b*2)".parse().unwrap();
+		let mut r = Recorder(vec![]);
+		super::walk(&mut r, &e, Some(&"[] 1-1".parse().unwrap()));
+		assert_eq!(r.0.join(" "), "<Op(AddSub) a inv [ <Negative neg ( <Op(MulDiv) b op 2 Op(MulDiv)> ) Negative> ] Op(AddSub)>");
+	}
+}
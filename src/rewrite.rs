@@ -0,0 +1,233 @@
+//!
+//! Pattern-based rewrite rules
+//!
+//! A rule is written as `pattern -> replacement # Name`, where variables starting with `?` are metavariables that
+//! match (and bind) any sub-expression.
+//!
+
+use crate::expression::{Expression, SubExpression, ExprNode, ParseError};
+
+pub struct Rule
+{
+	pub name: String,
+	pub pattern: Expression,
+	pub replacement: Expression,
+}
+
+type Bindings = Vec<(String, Expression)>;
+
+impl Rule
+{
+	/// Parse a rule from a single line of text
+	pub fn from_str(s: &str) -> Result<Rule, ParseError>
+	{
+		let split_pos = match s.find("->")
+			{
+			Some(v) => v,
+			None => return Err(ParseError::Unexpected("Missing `->` in rule".to_owned())),
+			};
+		let (pattern, _) = Expression::parse_pattern_with_comment(&s[..split_pos])?;
+		let (replacement, comment) = Expression::parse_pattern_with_comment(&s[split_pos+2..])?;
+		let comment = comment.trim_start_matches('#').trim();
+		// Unbound metavariables would be left in the result, which can't be parsed again
+		let mut pattern_vars = Vec::new();
+		collect_metavars(&pattern, &mut pattern_vars);
+		let mut replacement_vars = Vec::new();
+		collect_metavars(&replacement, &mut replacement_vars);
+		if let Some(v) = replacement_vars.iter().find(|v| !pattern_vars.contains(v)) {
+			return Err(ParseError::Unexpected(format!("`{}` isn't bound by the pattern", v)));
+		}
+		Ok(Rule {
+			name: if comment != "" { comment.to_owned() } else { format!("{} -> {}", pattern, replacement) },
+			pattern: pattern,
+			replacement: replacement,
+			})
+	}
+
+	/// Attempt to apply this rule to the root of the passed expression
+	pub fn apply(&self, e: &Expression) -> Option<Expression>
+	{
+		let mut bindings = Vec::new();
+		if match_expr(&self.pattern, e, &mut bindings)
+		{
+			let mut rv = expand(&self.replacement, &bindings);
+			crate::manip::simplify(&mut rv);
+			Some(rv)
+		}
+		else
+		{
+			None
+		}
+	}
+}
+
+/// Load a set of rules from a file (one per line, blank lines and `#` comments ignored)
+pub fn load_rules(p: &std::path::Path) -> std::io::Result<Vec<Rule>>
+{
+	use std::io::BufRead;
+	let f = std::io::BufReader::new( std::fs::File::open(p)? );
+
+	let mut rv = Vec::new();
+	for (i,line) in f.lines().enumerate()
+	{
+		let line = line?;
+		let l = line.trim();
+		if l == "" || l.starts_with("#") {
+			continue ;
+		}
+		match Rule::from_str(l)
+		{
+		Ok(r) => rv.push(r),
+		Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}:{}: Bad rule - {}", p.display(), i+1, e))),
+		}
+	}
+	Ok( rv )
+}
+
+fn is_metavar(name: &str) -> bool
+{
+	name.starts_with("?")
+}
+
+fn collect_metavars(e: &Expression, out: &mut Vec<String>)
+{
+	match e
+	{
+	Expression::Variable(ref n) if is_metavar(n) => out.push(n.clone()),
+	Expression::Negative(ref v) => collect_metavars(v, out),
+	Expression::SubNode(ref sn) =>
+		for v in sn.values.iter() {
+			collect_metavars(&v.val, out);
+		},
+	Expression::Literal(_) | Expression::Variable(_) => {},
+	}
+}
+
+fn bind(name: &str, e: Expression, bindings: &mut Bindings) -> bool
+{
+	if let Some(b) = bindings.iter().find(|b| b.0 == name)
+	{
+		return b.1 == e;
+	}
+	bindings.push( (name.to_owned(), e) );
+	true
+}
+
+fn match_expr(pat: &Expression, e: &Expression, bindings: &mut Bindings) -> bool
+{
+	match pat
+	{
+	Expression::Variable(ref n) if is_metavar(n) => bind(n, e.clone(), bindings),
+	Expression::Negative(ref p) =>
+		match e
+		{
+		Expression::Negative(ref v) => match_expr(p, v, bindings),
+		_ => false,
+		},
+	Expression::SubNode(ref psn) =>
+		match e
+		{
+		Expression::SubNode(ref sn) if sn.operation == psn.operation => match_node(psn, sn, bindings),
+		_ => false,
+		},
+	Expression::Literal(_) | Expression::Variable(_) => pat == e,
+	}
+}
+fn match_node(pat: &ExprNode, e: &ExprNode, bindings: &mut Bindings) -> bool
+{
+	if e.values.len() < pat.values.len() {
+		return false;
+	}
+	let n = pat.values.len();
+	for (p,v) in Iterator::zip(pat.values[..n-1].iter(), e.values.iter())
+	{
+		if p.inverse != v.inverse || !match_expr(&p.val, &v.val, bindings) {
+			return false;
+		}
+	}
+
+	let last = &pat.values[n-1];
+	if e.values.len() == n
+	{
+		last.inverse == e.values[n-1].inverse && match_expr(&last.val, &e.values[n-1].val, bindings)
+	}
+	else
+	{
+		// If the final pattern entry is a metavariable, it absorbs the rest of the node (e.g. `?a+?b` matches `x+y-z`)
+		match last.val
+		{
+		Expression::Variable(ref name) if is_metavar(name) => {
+			let rest = &e.values[n-1..];
+			if rest[0].inverse != last.inverse {
+				return false;
+			}
+			let values = rest.iter().map(|v| SubExpression { inverse: v.inverse ^ last.inverse, val: v.val.clone() }).collect();
+			bind(name, crate::manip::make_node(e.operation, values), bindings)
+			},
+		_ => false,
+		}
+	}
+}
+
+/// Replace all metavariables in the expression with their bound values
+fn expand(e: &Expression, bindings: &Bindings) -> Expression
+{
+	match e
+	{
+	Expression::Variable(ref n) if is_metavar(n) =>
+		match bindings.iter().find(|b| b.0 == *n)
+		{
+		Some(b) => b.1.clone(),
+		// Unbound metavariables are left as-is
+		None => e.clone(),
+		},
+	Expression::Negative(ref v) => Expression::Negative(Box::new(expand(v, bindings))),
+	Expression::SubNode(ref sn) => Expression::SubNode(ExprNode {
+		operation: sn.operation,
+		values: sn.values.iter().map(|v| SubExpression { inverse: v.inverse, val: expand(&v.val, bindings) }).collect(),
+		}),
+	Expression::Literal(_) | Expression::Variable(_) => e.clone(),
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn apply(rule: &str, e: &str) -> Option<String> {
+		let r = Rule::from_str(rule).unwrap();
+		r.apply(&e.parse().unwrap()).map(|v| v.to_string())
+	}
+
+	#[test]
+	fn parse_rule() {
+		let r = Rule::from_str("?a*(?b+?c) -> ?a*?b + ?a*?c # Distribute").unwrap();
+		assert_eq!(r.name, "Distribute");
+		assert_eq!(r.pattern.to_string(), "?a*(?b+?c)");
+		// Unnamed rules are named after themselves
+		assert_eq!(Rule::from_str("?a -> ?a*1").unwrap().name, "?a -> ?a*1");
+	}
+	#[test]
+	fn bad_rules() {
+		assert!(Rule::from_str("?a + ?b").is_err());
+		assert!(Rule::from_str("?a -> ?b").is_err());
+		assert!(Rule::from_str("-> x").is_err());
+	}
+	#[test]
+	fn metavars_only_in_rules() {
+		assert!("?a + 1".parse::<Expression>().is_err());
+		assert!(Expression::opt_parse_from_str_with_comment("x = ?y").is_err());
+	}
+
+	#[test]
+	fn apply_rules() {
+		assert_eq!(apply("?a*(?b+?c) -> ?a*?b + ?a*?c", "x*(y+2)"), Some("x*y+x*2".to_owned()));
+		// Repeated metavariables must bind the same value
+		assert_eq!(apply("?a - ?a -> 0", "x*y - x*y"), Some("0".to_owned()));
+		assert_eq!(apply("?a - ?a -> 0", "x - y"), None);
+		// The last metavariable absorbs the rest of the node
+		assert_eq!(apply("?a + ?b -> ?b + ?a", "x + y - z"), Some("y-z+x".to_owned()));
+		assert_eq!(apply("-?a -> ?a*(-1)", "x"), None);
+	}
+}
@@ -1,7 +1,7 @@
 use crate::expression::Expression;
 use crate::expression::ExprNode;
 
-#[derive(Clone)]
+#[derive(Clone,PartialEq)]
 pub struct Selection {
 	pub path: Vec<usize>,
 	pub first: usize,
@@ -13,8 +13,54 @@ impl std::fmt::Debug for Selection {
 	}
 }
 
+/// Parses the format produced by `Debug` (e.g. `[1, 0] 0-2`)
+impl std::str::FromStr for Selection {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Selection, String> {
+		let s = s.trim();
+		let close = match s.find(']')
+			{
+			Some(p) if s.starts_with("[") => p,
+			_ => return Err(format!("Bad selection `{}`", s)),
+			};
+		let mut path = Vec::new();
+		for v in s[1..close].split(',').map(|v| v.trim()).filter(|v| *v != "")
+		{
+			path.push( v.parse().map_err(|_| format!("Bad selection path `{}`", v))? );
+		}
+		let mut it = s[close+1..].trim().splitn(2, '-');
+		let first = it.next().and_then(|v| v.parse().ok()).ok_or_else(|| format!("Bad selection range `{}`", s))?;
+		let last = it.next().and_then(|v| v.parse().ok()).ok_or_else(|| format!("Bad selection range `{}`", s))?;
+		Ok(Selection { path: path, first: first, last: last })
+	}
+}
+
 impl Selection
 {
+	pub fn new() -> Selection {
+		Selection { path: vec![], first: 0, last: 0 }
+	}
+
+	/// Check that this selection refers to a valid location within the expression
+	pub fn is_valid(&self, e: &Expression) -> bool {
+		let mut e = e;
+		for &idx in self.path.iter()
+		{
+			e = match e
+				{
+				Expression::Negative(ref v) if idx == 0 => v,
+				Expression::SubNode(ref sn) if idx < sn.values.len() => &sn.values[idx].val,
+				_ => return false,
+				};
+		}
+		match e
+		{
+		Expression::Negative(_) => self.first == 0 && self.last == 0,
+		Expression::SubNode(ref sn) => self.first <= self.last && self.last < sn.values.len(),
+		_ => false,
+		}
+	}
+
 	fn get_cur_size(&self, e: &Expression) -> usize {
 		if let Some( (&last, s) ) = self.path.split_last()
 		{
@@ -132,23 +178,47 @@ impl Selection
 	}
 }
 
+/// Category of a rendered token (used for colouring)
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum TokenClass
+{
+	Variable,
+	Literal,
+	Operator,
+	Paren,
+	/// Brackets around the node containing the selection
+	ParentParen,
+	Comment,
+}
+/// Piece of rendered text
+#[derive(Debug,Clone)]
+pub struct Token
+{
+	pub class: TokenClass,
+	pub text: String,
+	/// Before (0), within (1) or after (2) the selection
+	pub part: usize,
+}
+
+/// Renders into a list of classified tokens, split into before, within and after the selection
 pub struct RenderSink
 {
+	style: Style,
 	cur_buf: usize,
-	buffers: [String; 3],
+	tokens: Vec<Token>,
 }
 impl RenderSink
 {
-	pub fn new() -> RenderSink {
+	pub fn new(style: Style) -> RenderSink {
 		RenderSink {
+			style: style,
 			cur_buf: 0,
-			buffers: [String::new(), String::new(), String::new()],
+			tokens: Vec::new(),
 			}
 	}
-	pub fn put(&mut self, v: impl std::fmt::Display)
+	pub fn put(&mut self, class: TokenClass, v: impl std::fmt::Display)
 	{
-		use std::fmt::Write;
-		write!(&mut self.buffers[self.cur_buf], "{}", v);
+		self.tokens.push(Token { class: class, text: v.to_string(), part: self.cur_buf });
 	}
 	pub fn hilight_active(&self) -> bool {
 		self.cur_buf == 1
@@ -161,6 +231,74 @@ impl RenderSink
 		assert!(self.cur_buf == 1);
 		self.cur_buf = 2;
 	}
+	fn unicode(&self) -> bool {
+		self.style == Style::Unicode
+	}
+}
+impl crate::render::Renderer for RenderSink
+{
+	fn operator(&mut self, op: crate::expression::Op, inverse: bool, _next: &Expression) {
+		use crate::expression::Op;
+		self.put(TokenClass::Operator, match op
+			{
+			Op::AddSub if inverse => if self.unicode() { "\u{2212}" } else { "-" },
+			Op::AddSub => "+",
+			Op::MulDiv if inverse => "/",
+			Op::MulDiv => if self.unicode() { "\u{b7}" } else { "*" },
+			Op::ExpRoot => "^",
+			Op::Equality => "=",
+			});
+	}
+	fn negate(&mut self) {
+		self.put(TokenClass::Operator, if self.unicode() { "\u{2212}" } else { "-" });
+	}
+	fn literal(&mut self, v: f32) {
+		self.put(TokenClass::Literal, v);
+	}
+	fn variable(&mut self, name: &str) {
+		if self.unicode() {
+			self.put(TokenClass::Variable, crate::unicode::ident(name));
+		}
+		else {
+			self.put(TokenClass::Variable, name);
+		}
+	}
+	fn open_paren(&mut self) {
+		self.put(TokenClass::Paren, "(");
+	}
+	fn close_paren(&mut self) {
+		self.put(TokenClass::Paren, ")");
+	}
+	fn open_parent_paren(&mut self) {
+		self.put(TokenClass::ParentParen, "(");
+	}
+	fn close_parent_paren(&mut self) {
+		self.put(TokenClass::ParentParen, ")");
+	}
+	fn selection_start(&mut self) {
+		self.start_hilight();
+	}
+	fn selection_end(&mut self) {
+		self.end_hilight();
+	}
+
+	fn can_superscript(&self, exp: &Expression) -> bool {
+		self.unicode() && crate::unicode::superscript(exp).is_some()
+	}
+	fn superscript(&mut self, exp: &Expression) {
+		if let Some(s) = crate::unicode::superscript(exp) {
+			self.put(TokenClass::Literal, s);
+		}
+	}
+	fn root_degree(&self, exp: &Expression) -> Option<u32> {
+		if self.unicode() { crate::unicode::root_degree(exp) } else { None }
+	}
+	fn root_parens(&self, radicand: &Expression) -> bool {
+		crate::unicode::root_needs_parens(radicand)
+	}
+	fn root_start(&mut self, degree: u32, _radicand: &Expression) {
+		self.put(TokenClass::Operator, crate::unicode::root_sign(degree));
+	}
 }
 
 fn get_level_size(e: &Expression, path: &[usize], last_idx: usize) -> Option<usize>
@@ -318,91 +456,31 @@ pub fn replace_subexpression(e: &mut Expression, sel: &mut Selection, new_e: Exp
 	h_expr(e, sel, 0, new_e, /*simplify=*/false)
 }
 
-pub fn split_expression(e: &Expression, sel: &Selection) -> (String, String, String)
+/// Character set used when rendering expressions as text
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Style
 {
-	let mut sink = RenderSink::new();
-	
-	fn h_expr(sink: &mut RenderSink, e: &Expression, sel: &Selection, path_pos: usize)
-	{
-		match e
-		{
-		Expression::Negative(e) => {
-			sink.put("-");
-			let needs_parens = match **e
-				{
-				Expression::Literal(_) | Expression::Variable(_) => false,
-				_ => true,
-				};
-			if path_pos < sel.path.len() {
-				assert!(sel.path[path_pos] == 0);
-			}
-			if path_pos == sel.path.len() {
-				assert!(sel.first == 0);
-				assert!(sel.last == 0);
-			}
-			if path_pos == sel.path.len() {
-				sink.start_hilight();
-			}
-			if needs_parens {
-				sink.put("(");
-			}
-			h_expr(sink, e, sel, if path_pos < sel.path.len() { path_pos+1 } else { !0 });
-			if needs_parens {
-				sink.put(")");
-			}
-			if path_pos == sel.path.len() {
-				sink.end_hilight();
-			}
-			},
-		Expression::SubNode(sn) => h_node(sink, sn, sel, path_pos),
-		Expression::Literal(v) => sink.put(&v),
-		Expression::Variable(v) => sink.put(&v),
-		}
-	}
-	fn h_node(sink: &mut RenderSink, e: &ExprNode, sel: &Selection, path_pos: usize)
-	{
-		for (i,v) in Iterator::enumerate(e.values.iter())
-		{
-			if i == 0
-			{
-			}
-			else
-			{
-				match e.operation
-				{
-				crate::expression::Op::AddSub => sink.put(if v.inverse { "-" } else { "+" }),
-				crate::expression::Op::MulDiv => sink.put(if v.inverse { "/" } else { "*" }),
-				crate::expression::Op::ExpRoot => sink.put("^"),
-				crate::expression::Op::Equality => sink.put("="),
-				}
-			}
-
-			if path_pos == sel.path.len() && i == sel.first {
-				sink.start_hilight();
-			}
-
-			let needs_parens = v.val.needs_parens(e.operation);
-			if needs_parens {
-				sink.put("(");
-			}
-			h_expr(sink, &v.val, sel, if path_pos < sel.path.len() && sel.path[path_pos] == i { path_pos + 1 } else { !0 });
-			if needs_parens {
-				sink.put(")");
-			}
+	Ascii,
+	/// `·`, `−`, superscript exponents, root signs and subscripts (see `crate::unicode`)
+	Unicode,
+}
 
-			if path_pos == sel.path.len() && i == sel.last {
-				sink.end_hilight();
-			}
-		}
+/// Render an expression into classified tokens
+pub fn render_tokens(e: &Expression, sel: Option<&Selection>, style: Style) -> Vec<Token>
+{
+	let mut sink = RenderSink::new(style);
+	crate::render::walk(&mut sink, e, sel);
+	assert!(!sink.hilight_active(), "Path was invalid, didn't terminate hilight");
+	sink.tokens
+}
 
-		assert!(!(path_pos == sel.path.len() && sink.hilight_active()), "Path was invalid, didn't terminate hilight");
+pub fn split_expression(e: &Expression, sel: &Selection) -> (String, String, String)
+{
+	let mut buffers = [String::new(), String::new(), String::new()];
+	for t in render_tokens(e, Some(sel), Style::Ascii)
+	{
+		buffers[t.part].push_str(&t.text);
 	}
-
-	h_expr(&mut sink, e, sel, 0);
-	assert!(sink.cur_buf != 1);
-	(
-		::std::mem::replace(&mut sink.buffers[0], String::new()),
-		::std::mem::replace(&mut sink.buffers[1], String::new()),
-		::std::mem::replace(&mut sink.buffers[2], String::new()),
-		)
+	let [before, hilight, after] = buffers;
+	(before, hilight, after)
 }
@@ -0,0 +1,186 @@
+//!
+//! Unicode glyphs for rendering expressions (`·`, `−`, superscript exponents, `√`, subscripted identifiers)
+//!
+
+use crate::expression::{Expression, Op, greek_letter, split_ident, GREEK};
+
+const SUPERSCRIPT_DIGITS: [char; 10] = ['⁰', '¹', '²', '³', '⁴', '⁵', '⁶', '⁷', '⁸', '⁹'];
+const SUBSCRIPT_DIGITS: [char; 10] = ['₀', '₁', '₂', '₃', '₄', '₅', '₆', '₇', '₈', '₉'];
+
+/// Superscript form of a small integer exponent (e.g. `²` or `⁻¹`)
+pub fn superscript(e: &Expression) -> Option<String>
+{
+	let (neg, v) = match e
+		{
+		Expression::Literal(v) => (false, *v),
+		Expression::Negative(ref v) => match **v { Expression::Literal(v) => (true, v), _ => return None },
+		_ => return None,
+		};
+	if v < 0. || v >= 1000. || v.fract() != 0. {
+		return None;
+	}
+	let mut rv = String::new();
+	if neg {
+		rv.push('⁻');
+	}
+	rv.extend( format!("{}", v as u32).bytes().map(|b| SUPERSCRIPT_DIGITS[(b - b'0') as usize]) );
+	Some(rv)
+}
+/// Parse a superscript exponent back into an expression
+pub fn parse_superscript(s: &str) -> Expression
+{
+	let mut v = 0.;
+	for c in s.chars()
+	{
+		if let Some(d) = SUPERSCRIPT_DIGITS.iter().position(|&g| g == c) {
+			v = v * 10. + d as f32;
+		}
+	}
+	if s.starts_with('⁻') { Expression::Negative(Box::new(Expression::Literal(v))) } else { Expression::Literal(v) }
+}
+
+/// Degree of root for an exponent of `1/2`, `1/3` or `1/4`
+pub fn root_degree(exp: &Expression) -> Option<u32>
+{
+	match exp
+	{
+	Expression::SubNode(ref sn) if sn.operation == Op::MulDiv && sn.values.len() == 2 && !sn.values[0].inverse && sn.values[1].inverse => {
+		if sn.values[0].val != Expression::Literal(1.) {
+			return None;
+		}
+		match sn.values[1].val
+		{
+		Expression::Literal(v) if v == 2. => Some(2),
+		Expression::Literal(v) if v == 3. => Some(3),
+		Expression::Literal(v) if v == 4. => Some(4),
+		_ => None,
+		}
+		},
+	_ => None,
+	}
+}
+pub fn root_sign(degree: u32) -> char
+{
+	match degree
+	{
+	3 => '∛',
+	4 => '∜',
+	_ => '√',
+	}
+}
+/// Degree of a root sign (reverse of `root_sign`)
+pub fn sign_degree(c: char) -> f32
+{
+	match c
+	{
+	'∛' => 3.,
+	'∜' => 4.,
+	_ => 2.,
+	}
+}
+/// A root sign applies to a single value, so anything else is bracketed
+pub fn root_needs_parens(radicand: &Expression) -> bool
+{
+	match radicand
+	{
+	Expression::Literal(_) | Expression::Variable(_) => false,
+	_ => true,
+	}
+}
+
+/// Render an identifier with Greek letter names as glyphs and numeric subscripts lowered (e.g. `theta_0` is `θ₀`)
+pub fn ident(name: &str) -> String
+{
+	let (stem, primes, sub) = split_ident(name);
+	let mut rv = match greek_letter(stem)
+		{
+		Some(c) => c.to_string(),
+		None => stem.to_owned(),
+		};
+	rv.push_str(&"'".repeat(primes));
+	if let Some(sub) = sub
+	{
+		let digits = sub.trim_end_matches('\'');
+		if digits != "" && digits.bytes().all(|b| b.is_ascii_digit()) {
+			rv.extend( digits.bytes().map(|b| SUBSCRIPT_DIGITS[(b - b'0') as usize]) );
+			rv.push_str(&sub[digits.len()..]);
+		}
+		else {
+			rv.push('_');
+			rv.push_str(sub);
+		}
+	}
+	rv
+}
+/// Convert an identifier using glyphs back into its plain name (reverse of `ident`)
+pub fn parse_ident(s: &str) -> String
+{
+	let mut rv = String::new();
+	let mut in_sub = false;
+	for c in s.chars()
+	{
+		if let Some(d) = SUBSCRIPT_DIGITS.iter().position(|&g| g == c) {
+			if !in_sub {
+				rv.push('_');
+				in_sub = true;
+			}
+			rv.push((b'0' + d as u8) as char);
+			continue ;
+		}
+		in_sub = false;
+		match GREEK.iter().find(|g| g.1 == c)
+		{
+		Some(g) => rv.push_str(g.0),
+		None => rv.push(c),
+		}
+	}
+	rv
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use crate::ui_helpers::{render_tokens, Style};
+
+	fn render(e: &Expression) -> String {
+		render_tokens(e, None, Style::Unicode).iter().map(|t| &t.text[..]).collect()
+	}
+
+	#[test]
+	fn glyphs() {
+		assert_eq!(superscript(&Expression::Literal(12.)), Some("¹²".to_owned()));
+		assert_eq!(superscript(&"-1".parse().unwrap()), Some("⁻¹".to_owned()));
+		assert_eq!(superscript(&Expression::Literal(2.5)), None);
+		assert_eq!(superscript(&Expression::Literal(1000.)), None);
+		assert_eq!(parse_superscript("⁻¹²"), "-12".parse().unwrap());
+		assert_eq!(ident("theta_0"), "θ₀");
+		assert_eq!(ident("v_12'"), "v₁₂'");
+		assert_eq!(ident("x_a"), "x_a");
+		for name in &["theta_0", "v_12'", "x_a", "alpha'", "_0"] {
+			assert_eq!(parse_ident(&ident(name)), *name);
+		}
+	}
+	#[test]
+	fn render_roundtrip() {
+		for (s, u) in &[
+				("a*b - c", "a·b−c"),
+				("x^2 + y^-1", "x²+y⁻¹"),
+				("x^(1/2) + (a+b)^(1/3)", "√x+∛(a+b)"),
+				("theta_0 + v_12'", "θ₀+v₁₂'"),
+				("2*-x", "2·−x"),
+				("x^n + x^2.5", "x^n+x^2.5"),
+				] {
+			let e: Expression = s.parse().unwrap();
+			assert_eq!(render(&e), *u);
+			assert_eq!(u.parse::<Expression>().unwrap(), e, "{}", u);
+		}
+	}
+	#[test]
+	fn parse_glyphs() {
+		let p = |s: &str| s.parse::<Expression>().unwrap().to_string();
+		assert_eq!(p("x²·y − √z"), "x^2*y-z^(1/2)");
+		assert_eq!(p("∜16 = 2"), "16^(1/4)=2");
+		assert_eq!(p("α₁ − β"), "alpha_1-beta");
+	}
+}
@@ -0,0 +1,321 @@
+//!
+//! Dimensional analysis using units declared per variable
+//!
+//! Units are declared with header lines such as `#! unit a_0: m/s^2`
+//!
+
+use crate::expression::{Expression, ExprNode, Op};
+use crate::rational::Rational;
+use crate::ui_helpers::Selection;
+use std::collections::{BTreeMap, HashMap};
+
+/// Powers of each base unit (empty for dimensionless)
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Dimension
+{
+	powers: BTreeMap<String, Rational>,
+}
+impl Dimension
+{
+	pub fn dimensionless() -> Dimension {
+		Default::default()
+	}
+	pub fn is_dimensionless(&self) -> bool {
+		self.powers.is_empty()
+	}
+	fn base(name: &str) -> Dimension {
+		let mut rv = Dimension::dimensionless();
+		rv.powers.insert(name.to_owned(), Rational::one());
+		rv
+	}
+	fn mul(&self, o: &Dimension, scale: Rational) -> Dimension {
+		let mut rv = self.clone();
+		for (n,p) in o.powers.iter()
+		{
+			let v = *rv.powers.get(n).unwrap_or(&Rational::zero()) + *p * scale;
+			if v.is_zero() {
+				rv.powers.remove(n);
+			}
+			else {
+				rv.powers.insert(n.clone(), v);
+			}
+		}
+		rv
+	}
+	fn pow(&self, e: Rational) -> Dimension {
+		Dimension::dimensionless().mul(self, e)
+	}
+
+	/// Convert a unit expression (e.g. `m/s^2`) into a dimension
+	pub fn from_expr(e: &Expression) -> Result<Dimension, String>
+	{
+		match e
+		{
+		Expression::Literal(v) if *v == 1.0 => Ok(Dimension::dimensionless()),
+		Expression::Variable(ref n) => Ok(Dimension::base(n)),
+		Expression::SubNode(ref sn) if sn.operation == Op::MulDiv => {
+			let mut rv = Dimension::dimensionless();
+			for v in sn.values.iter()
+			{
+				let d = Dimension::from_expr(&v.val)?;
+				rv = rv.mul(&d, if v.inverse { -Rational::one() } else { Rational::one() });
+			}
+			Ok(rv)
+			},
+		Expression::SubNode(ref sn) if sn.operation == Op::ExpRoot => {
+			let (base, exp) = crate::manip::split_power(sn);
+			match Rational::from_expr(&exp)
+			{
+			Some(exp) => Ok(Dimension::from_expr(&base)?.pow(exp)),
+			None => Err(format!("Unit exponent `{}` isn't a number", exp)),
+			}
+			},
+		_ => Err(format!("`{}` isn't a unit", e)),
+		}
+	}
+}
+impl std::fmt::Display for Dimension
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		if self.powers.is_empty() {
+			return f.write_str("1");
+		}
+		// Positive powers first (e.g. `m/s^2`)
+		let mut first = true;
+		for (n,p) in self.powers.iter().filter(|(_,p)| !p.is_negative())
+		{
+			if !first {
+				f.write_str("*")?;
+			}
+			first = false;
+			f.write_str(n)?;
+			if !p.is_one() {
+				write!(f, "^{}", p)?;
+			}
+		}
+		if first {
+			f.write_str("1")?;
+		}
+		for (n,p) in self.powers.iter().filter(|(_,p)| p.is_negative())
+		{
+			write!(f, "/{}", n)?;
+			if !p.abs().is_one() {
+				write!(f, "^{}", p.abs())?;
+			}
+		}
+		Ok( () )
+	}
+}
+
+/// Check if an expression is a plain number (no variables)
+fn is_constant(e: &Expression) -> bool
+{
+	match e
+	{
+	Expression::Literal(_) => true,
+	Expression::Variable(_) => false,
+	Expression::Negative(ref v) => is_constant(v),
+	Expression::SubNode(ref sn) => sn.values.iter().all(|v| is_constant(&v.val)),
+	}
+}
+
+/// Declared units for variables
+#[derive(Debug,Clone,Default)]
+pub struct Units
+{
+	vars: HashMap<String, Dimension>,
+	/// Declarations in the order they were given (for saving)
+	order: Vec<(String, String)>,
+}
+
+/// A unit mismatch within an expression
+#[derive(Debug)]
+pub struct UnitError
+{
+	/// The offending term(s)
+	pub sel: Selection,
+	pub message: String,
+}
+
+impl Units
+{
+	pub fn new() -> Units {
+		Default::default()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.vars.is_empty()
+	}
+
+	/// Parse a `unit <var>: <unit>` declaration (the part after the `#!`)
+	pub fn parse_declaration(&mut self, s: &str) -> Result<(), String>
+	{
+		let s = s.trim();
+		if !s.starts_with("unit ") {
+			return Err(format!("Unknown header `{}`", s));
+		}
+		let s = &s["unit ".len()..];
+		let colon = s.find(':').ok_or_else(|| format!("Expected `:` in unit declaration"))?;
+		let (var, unit) = (s[..colon].trim(), s[colon+1..].trim());
+		let e: Expression = unit.parse().map_err(|e| format!("Bad unit `{}` - {}", unit, e))?;
+		let dim = Dimension::from_expr(&e)?;
+		self.vars.insert(var.to_owned(), dim);
+		self.order.push( (var.to_owned(), unit.to_owned()) );
+		Ok( () )
+	}
+	/// Header lines to save
+	pub fn declarations(&self) -> impl Iterator<Item=String> + '_ {
+		self.order.iter().map(|(v,u)| format!("unit {}: {}", v, u))
+	}
+
+	/// Infer the dimension of an expression, returning the first mismatch
+	///
+	/// Variables without a declared unit are treated as unknown, and don't cause errors.
+	pub fn check(&self, e: &Expression) -> Result<Option<Dimension>, UnitError>
+	{
+		let mut path = Vec::new();
+		self.check_int(e, &mut path)
+	}
+	fn check_int(&self, e: &Expression, path: &mut Vec<usize>) -> Result<Option<Dimension>, UnitError>
+	{
+		match e
+		{
+		Expression::Literal(_) => Ok(Some(Dimension::dimensionless())),
+		Expression::Variable(ref n) => Ok(self.vars.get(n).cloned()),
+		Expression::Negative(ref v) => {
+			path.push(0);
+			let rv = self.check_int(v, path);
+			path.pop();
+			rv
+			},
+		Expression::SubNode(ref sn) => {
+			let mut dims = Vec::new();
+			for (i,v) in sn.values.iter().enumerate()
+			{
+				path.push(i);
+				let d = self.check_int(&v.val, path);
+				path.pop();
+				dims.push(d?);
+			}
+			self.check_node(sn, dims, path)
+			},
+		}
+	}
+	fn check_node(&self, sn: &ExprNode, dims: Vec<Option<Dimension>>, path: &[usize]) -> Result<Option<Dimension>, UnitError>
+	{
+		let err = |first: usize, last: usize, message: String| UnitError {
+			sel: Selection { path: path.to_owned(), first: first, last: last },
+			message: message,
+			};
+		match sn.operation
+		{
+		// All terms (or sides) must have the same dimension
+		// - Except for plain numbers, which take the dimension of the other terms (e.g. `t = 2` or `v - 0`)
+		Op::Equality | Op::AddSub => {
+			let mut rv: Option<(usize, Dimension)> = None;
+			let mut all_constant = true;
+			for (i,d) in dims.into_iter().enumerate()
+			{
+				if is_constant(&sn.values[i].val) {
+					continue ;
+				}
+				all_constant = false;
+				match (d, &rv)
+				{
+				(None, _) => {},
+				(Some(d), None) => rv = Some( (i, d) ),
+				(Some(d), Some( (j, d2) )) => if d != *d2 {
+					return Err(err(i, i, format!("`{}` has units {}, but `{}` has units {}", sn.values[i].val, d, sn.values[*j].val, d2)));
+					},
+				}
+			}
+			if all_constant {
+				return Ok(Some(Dimension::dimensionless()));
+			}
+			Ok(rv.map(|v| v.1))
+			},
+		Op::MulDiv => {
+			let mut rv = Dimension::dimensionless();
+			for (v,d) in sn.values.iter().zip(dims.into_iter())
+			{
+				match d
+				{
+				Some(d) => rv = rv.mul(&d, if v.inverse { -Rational::one() } else { Rational::one() }),
+				None => return Ok(None),
+				}
+			}
+			Ok(Some(rv))
+			},
+		// Exponents must be dimensionless, and a base with units needs a numeric exponent
+		Op::ExpRoot => {
+			for (i,d) in dims.iter().enumerate().skip(1)
+			{
+				if let Some(ref d) = d {
+					if !d.is_dimensionless() {
+						return Err(err(i, i, format!("Exponent `{}` has units {}", sn.values[i].val, d)));
+					}
+				}
+			}
+			match dims[0]
+			{
+			Some(ref d) if d.is_dimensionless() => Ok(Some(d.clone())),
+			Some(ref d) => {
+				let (_, exp) = crate::manip::split_power(sn);
+				match Rational::from_expr(&exp)
+				{
+				Some(exp) => Ok(Some(d.pow(exp))),
+				None => Err(err(0, sn.values.len() - 1, format!("`{}` has units {} but is raised to a non-numeric power", sn.values[0].val, d))),
+				}
+				},
+			None => Ok(None),
+			}
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn units() -> Units {
+		let mut u = Units::new();
+		for d in &["unit t: s", "unit v: m/s", "unit a_0: m/s^2", "unit s: m"] {
+			u.parse_declaration(d).unwrap();
+		}
+		u
+	}
+	fn check(u: &Units, s: &str) -> Result<Option<String>, String> {
+		u.check(&s.parse().unwrap()).map(|d| d.map(|d| d.to_string())).map_err(|e| e.message)
+	}
+
+	#[test]
+	fn declarations() {
+		let u = units();
+		assert_eq!(u.declarations().collect::<Vec<_>>()[2], "unit a_0: m/s^2");
+		assert!(Units::new().parse_declaration("unit x m").is_err());
+		assert!(Units::new().parse_declaration("colour x: red").is_err());
+		assert_eq!(Dimension::from_expr(&"kg*m^2/s^2".parse().unwrap()).unwrap().to_string(), "kg*m^2/s^2");
+	}
+	#[test]
+	fn inference() {
+		let u = units();
+		assert_eq!(check(&u, "v = a_0*t"), Ok(Some("m/s".to_owned())));
+		assert_eq!(check(&u, "s = 0.5*a_0*t^2 + v*t"), Ok(Some("m".to_owned())));
+		// Unknown variables don't cause errors
+		assert_eq!(check(&u, "x = v*t"), Ok(Some("m".to_owned())));
+		assert!(check(&u, "s = v + t").is_err());
+		assert!(check(&u, "x = 2^t").is_err());
+	}
+	#[test]
+	fn numbers_take_any_unit() {
+		let u = units();
+		// Binding lines (used by evaluate and find roots)
+		assert_eq!(check(&u, "t = 2"), Ok(Some("s".to_owned())));
+		assert_eq!(check(&u, "v = 0"), Ok(Some("m/s".to_owned())));
+		assert_eq!(check(&u, "v = -1/2"), Ok(Some("m/s".to_owned())));
+		assert_eq!(check(&u, "1 + 2"), Ok(Some("1".to_owned())));
+		// But are dimensionless in products
+		assert!(check(&u, "s = 2*t").is_err());
+	}
+}